@@ -0,0 +1,110 @@
+use crate::Parser;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+
+/// A mock server serving fixture pages, so scrapes are tested without the network.
+///
+/// Definitions handed to [`Fixtures::parser`] are written to a directory of their own, which is
+/// removed again when the fixtures are dropped.
+pub(crate) struct Fixtures {
+    server: MockServer,
+    dir: PathBuf,
+}
+
+impl Fixtures {
+    pub async fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "pdml-fixtures-{}-{}",
+            std::process::id(),
+            FIXTURES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Fixtures {
+            server: MockServer::start().await,
+            dir,
+        }
+    }
+
+    /// Serves `html` with a 200 on GET requests for `route`.
+    pub async fn serve(&self, route: &str, html: &str) -> &Self {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/html")
+                    .set_body_string(html),
+            )
+            .mount(&self.server)
+            .await;
+        self
+    }
+
+    /// The routes, query included, of the requests the server received so far, in order.
+    pub async fn requested_routes(&self) -> Vec<String> {
+        let requests = self.server.received_requests().await.unwrap_or_default();
+        let route = |url: &url::Url| url[url::Position::BeforePath..].to_string();
+        requests.iter().map(|request| route(&request.url)).collect()
+    }
+
+    /// The absolute url of `route` on the server.
+    pub fn url(&self, route: &str) -> String {
+        format!("{}{}", self.server.uri(), route)
+    }
+
+    /// A parser for `definition`, resolving relative page urls against the server.
+    pub fn parser(&self, definition: &str) -> Parser {
+        let file = self.dir.join(format!("{}.pdml", FIXTURES.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&file, definition).unwrap();
+        Parser::for_file(file.to_string_lossy().into_owned()).with_base_url(self.server.uri())
+    }
+}
+
+impl Drop for Fixtures {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "json")]
+    use crate::scrape::ParserExt;
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn scrapes_served_fixtures() {
+        let fixtures = Fixtures::start().await;
+        fixtures
+            .serve("/a", "<h1>First</h1>")
+            .await
+            .serve("/b", "<h1>Second</h1>")
+            .await;
+        let json = fixtures
+            .parser("page </a> { $title = h1; } page </b> { $title = h1; }")
+            .scrape_json()
+            .await
+            .unwrap();
+        assert_eq!(
+            json,
+            [
+                serde_json::json!({ "title": "First" }),
+                serde_json::json!({ "title": "Second" }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn removes_its_definitions_when_dropped() {
+        let fixtures = Fixtures::start().await;
+        let dir = fixtures.dir.clone();
+        fixtures.parser(&format!("page <{}> {{ $title = h1; }}", fixtures.url("/a")));
+        assert!(dir.exists());
+        drop(fixtures);
+        assert!(!dir.exists());
+    }
+}
@@ -1,50 +1,46 @@
 use crate::reader::{CharReader, ReaderError};
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use thiserror::Error;
 
-const VALID_IDEN_CHARS: &'static str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_";
+/// Whether selector text ends in a combinator or a `,`, so the selector goes on past the line.
+fn continues_on_next_line(chars: &[char]) -> bool {
+    chars
+        .iter()
+        .rev()
+        .find(|c| !c.is_whitespace())
+        .is_some_and(|c| matches!(c, ',' | '>' | '+' | '~'))
+}
+
+/// The text of a doc comment without its `/**` and `*/`, its lines trimmed, without the `*` they
+/// may start with, and joined with spaces.
+fn doc_text(comment: &str) -> String {
+    let lines = comment.lines().map(|line| {
+        let line = line.trim();
+        line.strip_prefix('*').unwrap_or(line).trim()
+    });
+    lines
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Whether `name` can name a selector or a pipeline: an uppercase letter followed by uppercase
+/// letters, digits or `_`.
+pub fn is_selector_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_uppercase())
+        && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
 
 #[derive(Debug, Clone)]
 pub struct Token {
     token_type: TokenType,
+    text: Option<String>,
 }
 
 impl PartialEq<TokenType> for Token {
     fn eq(&self, other: &TokenType) -> bool {
-        match self.get_type() {
-            TokenType::Literal(t, _) => match other {
-                TokenType::Literal(ot, _) => &t == ot,
-                _ => false,
-            },
-            TokenType::Assignment => match other {
-                TokenType::Assignment => true,
-                _ => false,
-            },
-            TokenType::Paren(p) => match other {
-                TokenType::Paren(op) => &p == op,
-                _ => false,
-            },
-            TokenType::EOF => match other {
-                TokenType::EOF => true,
-                _ => false,
-            },
-            TokenType::Whitespace => match other {
-                TokenType::Whitespace => true,
-                _ => false,
-            },
-            TokenType::Page => match other {
-                TokenType::Page => true,
-                _ => false,
-            },
-            TokenType::Unknown(_) => match other {
-                TokenType::Unknown(_) => true,
-                _ => false,
-            },
-            TokenType::Selector(_, _) => match other {
-                TokenType::Selector(_, _) => true,
-                _ => false,
-            },
-        }
+        self.token_type.kind() == other.kind()
     }
 }
 
@@ -52,15 +48,24 @@ type Result<T> = std::result::Result<T, LexerError>;
 
 impl Token {
     pub fn of_type(token_type: TokenType) -> Self {
-        Self { token_type }
+        Self {
+            token_type,
+            text: None,
+        }
     }
 
-    pub fn get_type(&self) -> TokenType {
-        self.token_type.clone()
+    pub fn with_text(mut self, text: String) -> Self {
+        self.text = Some(text);
+        self
+    }
+
+    /// The exact source text of the token, if it was lexed with it. See `Parser::lex_full`.
+    pub fn text(&self) -> Option<&String> {
+        self.text.as_ref()
     }
 
-    pub fn to_inner(self) -> TokenType {
-        self.token_type
+    pub fn get_type(&self) -> TokenType {
+        self.token_type.clone()
     }
 }
 
@@ -69,29 +74,248 @@ pub enum TokenType {
     Literal(LiteralType, String),
     Assignment,
     Paren(ParenType),
-    EOF,
+    Eof,
     Whitespace,
+    /// A block comment, `/* ... */`.
+    Comment,
     Page,
+    /// `.disabled` right after `page` or an identifier.
+    Disabled,
+    /// `.optional` right after an identifier.
+    Optional,
+    /// `[key=value, ...]` right after `page`, values being bare words or quoted strings.
+    PageOptions(Vec<(String, String)>),
     Unknown(char),
-    Selector(String, Quantifier),
+    Selector(String, Quantifier, Vec<Transform>),
+    SelectorDefinition(String, String),
+    /// `pipeline NAME = trim | number`, naming transforms for reuse after selectors.
+    PipelineDefinition(String, Vec<Transform>),
+    /// `assert $price matches /\d+/`, with what follows `assert` as written.
+    Assertion(String),
+    /// A char the lexer could not make sense of, with its byte offset. Only produced by
+    /// [`Lexer::recovering`].
+    Error(usize, char),
+}
+
+impl TokenType {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            TokenType::Literal(literal_type, _) => TokenKind::Literal(*literal_type),
+            TokenType::Assignment => TokenKind::Assignment,
+            TokenType::Paren(paren_type) => TokenKind::Paren(*paren_type),
+            TokenType::Eof => TokenKind::Eof,
+            TokenType::Whitespace => TokenKind::Whitespace,
+            TokenType::Comment => TokenKind::Comment,
+            TokenType::Page => TokenKind::Page,
+            TokenType::Disabled => TokenKind::Disabled,
+            TokenType::Optional => TokenKind::Optional,
+            TokenType::PageOptions(_) => TokenKind::PageOptions,
+            TokenType::Unknown(_) => TokenKind::Unknown,
+            TokenType::Selector(_, _, _) => TokenKind::Selector,
+            TokenType::SelectorDefinition(_, _) => TokenKind::SelectorDefinition,
+            TokenType::PipelineDefinition(_, _) => TokenKind::PipelineDefinition,
+            TokenType::Assertion(_) => TokenKind::Assertion,
+            TokenType::Error(_, _) => TokenKind::Error,
+        }
+    }
+}
+
+impl Display for TokenType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenType::Unknown(c) => write!(f, "unknown char '{}'", c),
+            TokenType::Error(position, c) => write!(f, "invalid char '{}' at {}", c, position),
+            token_type => write!(f, "{}", token_type.kind()),
+        }
+    }
+}
+
+/// The kind of a [`TokenType`] without what it holds, used to describe the tokens a parser
+/// expects.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+    Literal(LiteralType),
+    Assignment,
+    Paren(ParenType),
+    Eof,
+    Whitespace,
+    Comment,
+    Page,
+    Disabled,
+    Optional,
+    PageOptions,
+    Unknown,
+    Selector,
+    SelectorDefinition,
+    PipelineDefinition,
+    Assertion,
+    Error,
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::Literal(LiteralType::String) => write!(f, "string literal"),
+            TokenKind::Literal(LiteralType::Url) => write!(f, "URL literal"),
+            TokenKind::Literal(LiteralType::Identifier) => write!(f, "identifier"),
+            TokenKind::Assignment => write!(f, "'='"),
+            TokenKind::Paren(ParenType::BlockOpen) => write!(f, "'{{'"),
+            TokenKind::Paren(ParenType::BlockClose) => write!(f, "'}}'"),
+            TokenKind::Eof => write!(f, "end of file"),
+            TokenKind::Whitespace => write!(f, "whitespace"),
+            TokenKind::Comment => write!(f, "comment"),
+            TokenKind::Page => write!(f, "'page'"),
+            TokenKind::Disabled => write!(f, "'.disabled'"),
+            TokenKind::Optional => write!(f, "'.optional'"),
+            TokenKind::PageOptions => write!(f, "page options"),
+            TokenKind::Unknown => write!(f, "unknown char"),
+            TokenKind::Selector => write!(f, "selector"),
+            TokenKind::SelectorDefinition => write!(f, "selector definition"),
+            TokenKind::PipelineDefinition => write!(f, "pipeline definition"),
+            TokenKind::Assertion => write!(f, "assertion"),
+            TokenKind::Error => write!(f, "invalid char"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Quantifier {
+    /// No quantifier, `*first` or a trailing `first`: only the first match.
     Single,
-    Many,
+    /// `*`, `*all` or a trailing `all`: every match.
+    All,
+    /// `*last` or a trailing `last`: only the final match.
+    Last,
+    /// `*N`: the first `N` matches.
     Fixed(usize),
-    Any,
+    /// `*N..M`: the matches from index `N` up to, but excluding, index `M`.
+    Range(usize, usize),
+    /// `*N..` or a trailing `skip N`: every match but the first `N`.
+    Skip(usize),
+    /// `*Q when GUARD else R`: what `Q` takes if the `GUARD` selector matches in the scope of the
+    /// element, what `R` takes otherwise. So `.item*all when .show-all else 10` takes every item
+    /// on pages with a `.show-all` and the first ten on others.
+    When {
+        guard: String,
+        then: Box<Quantifier>,
+        otherwise: Box<Quantifier>,
+    },
 }
 
+impl Quantifier {
+    /// The first `amt` matches. Taking none is rejected, leave the element out instead.
+    pub fn fixed(amt: usize) -> Result<Self> {
+        if amt == 0 {
+            return Err(LexerError::InvalidQuantifier(
+                "a count must be at least 1".to_string(),
+            ));
+        }
+        Ok(Quantifier::Fixed(amt))
+    }
+
+    /// The matches from index `start` up to, but excluding, index `end`. Empty ranges are rejected.
+    pub fn range(start: usize, end: usize) -> Result<Self> {
+        if start >= end {
+            return Err(LexerError::InvalidQuantifier(format!(
+                "empty range {}..{}",
+                start, end
+            )));
+        }
+        Ok(Quantifier::Range(start, end))
+    }
+
+    /// Keeps the matches the quantifier takes out of all `matches`, in their order. A `when`
+    /// quantifier takes what its `else` branch does, the scraper picks the branch its guard
+    /// matches before applying it.
+    pub fn apply<T>(&self, mut matches: Vec<T>) -> Vec<T> {
+        match *self {
+            Quantifier::Single => matches.into_iter().take(1).collect(),
+            Quantifier::All => matches,
+            Quantifier::Last => matches.pop().into_iter().collect(),
+            Quantifier::Fixed(amt) => matches.into_iter().take(amt).collect(),
+            Quantifier::Range(start, end) => {
+                matches.into_iter().skip(start).take(end - start).collect()
+            }
+            Quantifier::Skip(amt) => matches.into_iter().skip(amt).collect(),
+            Quantifier::When { ref otherwise, .. } => otherwise.apply(matches),
+        }
+    }
+}
+
+/// A built-in function applied to each extracted value, written after the selector as in
+/// `.price | trim | strip("$") | number`. The transforms of an element apply in order.
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Transform {
+    /// `trim`: removes leading and trailing whitespace.
+    Trim,
+    /// `lowercase`
+    Lowercase,
+    /// `uppercase`
+    Uppercase,
+    /// `strip("text")`: removes `text` from the start and from the end of the value, once each.
+    Strip(String),
+    /// `number`: reads the value as a number and writes it in plain form, so ` 1.50 ` is `1.5`.
+    Number,
+    /// `unique`: leaves out matches whose value came before, then the quantifier takes its share,
+    /// so `*5 | unique` takes five different values. Leaves each value as is.
+    Unique,
+    /// `unique_taken`: like `unique`, but only among the matches the quantifier took, so `*5 |
+    /// unique_taken` takes the first five and may end up with fewer different values.
+    UniqueTaken,
+    /// `decode`: decodes the HTML entities in the value, such as `&amp;` or `&#39;`, that are left
+    /// after the document was parsed, for pages that encode them twice. Attribute values are
+    /// decoded like this unless they are `raw`, text only with `decode`. Entities that aren't
+    /// complete, as in `AT&T`, stay as they are.
+    Decode,
+    /// `raw`: keeps attribute values as the parsed document has them, without decoding entities.
+    Raw,
+    /// `data_uri`: decodes the payload of values that are `data:` uris, as the `src` of an inline
+    /// image may be, into bytes next to the value. Other values are left without a payload.
+    DataUri,
+    /// `NAME`: the transforms of the pipeline defined as `NAME`, which the parser puts in its
+    /// place.
+    Pipeline(String),
+}
+
+impl Transform {
+    /// Applies the transform to `value`. Only `number` fails, for values that aren't numbers. An
+    /// unresolved pipeline leaves the value as is, and so do the transforms the scraper applies
+    /// while extracting, `unique`, `unique_taken`, `decode`, `raw` and `data_uri`.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        match self {
+            Transform::Trim => Some(value.trim().to_string()),
+            Transform::Lowercase => Some(value.to_lowercase()),
+            Transform::Uppercase => Some(value.to_uppercase()),
+            Transform::Strip(text) => {
+                let value = value.strip_prefix(text.as_str()).unwrap_or(value);
+                Some(
+                    value
+                        .strip_suffix(text.as_str())
+                        .unwrap_or(value)
+                        .to_string(),
+                )
+            }
+            Transform::Number => value.trim().parse::<f64>().ok().map(|n| n.to_string()),
+            Transform::Unique
+            | Transform::UniqueTaken
+            | Transform::Decode
+            | Transform::Raw
+            | Transform::DataUri
+            | Transform::Pipeline(_) => Some(value.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LiteralType {
     String,
     Url,
     Identifier,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ParenType {
     BlockOpen,
     BlockClose,
@@ -99,21 +323,63 @@ pub enum ParenType {
 
 pub struct Lexer {
     reader: CharReader,
+    token_start: usize,
+    flaggable: bool,
+    after_page: bool,
+    doc: Option<String>,
 }
 
 impl Lexer {
     pub fn new(reader: CharReader) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            token_start: 0,
+            flaggable: false,
+            after_page: false,
+            doc: None,
+        }
+    }
+
+    pub fn for_file(file: &str) -> Result<Self> {
+        Ok(Self::new(CharReader::from_file(file)?))
     }
 
-    fn parse_literal_raw(&mut self, end_delimiter: char) -> Result<String> {
+    pub fn for_source(source: &str) -> Self {
+        Self::new(CharReader::from_source(source))
+    }
+
+    /// Reads selector text up to a `;`, the end of the line or the end of the source, so the `;`
+    /// may be left out. A line ending in `,`, `>`, `+` or `~` continues on the next one. A `{` or
+    /// `}` also ends the selector but is left for the next token. None of these count inside
+    /// quotes.
+    fn parse_selector_raw(&mut self) -> Result<String> {
         let mut chars: Vec<char> = vec![];
-        let mut next = self.reader.next_char()?;
-        while next != end_delimiter {
+        let mut quote: Option<char> = None;
+        loop {
+            let next = match self.reader.peek() {
+                Ok(next) => next,
+                Err(ReaderError::Eof) => break,
+                Err(err) => return Err(err.into()),
+            };
+            match (quote, next) {
+                (None, '\n') if continues_on_next_line(&chars) => {
+                    chars.push(' ');
+                    self.reader.advance(1);
+                    continue;
+                }
+                (None, ';' | '\n') => {
+                    self.reader.advance(1);
+                    break;
+                }
+                (None, '{' | '}') => break,
+                (None, '"' | '\'') => quote = Some(next),
+                (Some(q), c) if q == c => quote = None,
+                _ => {}
+            }
             chars.push(next);
-            next = self.reader.next_char()?;
+            self.reader.advance(1);
         }
-        return Ok(String::from_iter(chars));
+        Ok(String::from_iter(chars).trim().to_string())
     }
 
     fn parse_literal(
@@ -123,8 +389,8 @@ impl Lexer {
     ) -> Result<Token> {
         let start_char = self.reader.next_char()?;
         if start_char != start_delimiter {
-            return Err(LexerError::UnmatchedTokenError(TokenType::Unknown(
-                start_char,
+            return Err(LexerError::UnmatchedTokenError(TokenKind::Literal(
+                literal_type,
             )));
         }
 
@@ -143,17 +409,20 @@ impl Lexer {
     fn parse_identifier(&mut self) -> Result<Token> {
         let start_char = self.reader.next_char()?;
         if start_char != '$' {
-            return Err(LexerError::UnmatchedTokenError(TokenType::Literal(
+            return Err(LexerError::UnmatchedTokenError(TokenKind::Literal(
                 LiteralType::Identifier,
-                "".to_string(),
             )));
         }
 
+        // Only peek at the char ending the identifier, it belongs to the next token. Identifiers
+        // are made of Unicode XID_Continue chars, so `$título` and `$名前` work.
         let mut chars: Vec<char> = vec![];
-        let mut next = self.reader.next_char()?;
-        while VALID_IDEN_CHARS.chars().any(|c| c == next) {
+        while let Ok(next) = self.reader.peek() {
+            if !unicode_ident::is_xid_continue(next) {
+                break;
+            }
             chars.push(next);
-            next = self.reader.next_char()?;
+            self.reader.advance(1);
         }
         Ok(Token::of_type(TokenType::Literal(
             LiteralType::Identifier,
@@ -162,70 +431,557 @@ impl Lexer {
     }
 
     fn parse_page(&mut self) -> Result<Token> {
-        let buf = self.reader.peek_many(4).unwrap();
+        let buf = self.reader.peek_many(4)?;
         if buf == ['p', 'a', 'g', 'e'] {
             self.reader.advance(4);
             Ok(Token::of_type(TokenType::Page))
         } else {
-            Err(LexerError::ReaderError("".to_string()))
+            Err(LexerError::UnmatchedTokenError(TokenKind::Page))
+        }
+    }
+
+    fn parse_selector_definition(&mut self) -> Result<Token> {
+        let buf = self.reader.peek_many(9)?;
+        if buf.len() < 9
+            || buf[..8] != ['s', 'e', 'l', 'e', 'c', 't', 'o', 'r']
+            || !buf[8].is_whitespace()
+        {
+            return Err(LexerError::UnmatchedTokenError(
+                TokenKind::SelectorDefinition,
+            ));
+        }
+        self.reader.advance(8);
+
+        let definition = self.parse_selector_raw()?;
+        match definition.split_once('=') {
+            Some((name, selector)) if is_selector_name(name.trim()) => Ok(Token::of_type(
+                TokenType::SelectorDefinition(name.trim().to_string(), selector.trim().to_string()),
+            )),
+            Some((name, _)) => Err(LexerError::InvalidSelectorName(name.trim().to_string())),
+            None => Err(LexerError::InvalidSelectorName(
+                definition.trim().to_string(),
+            )),
+        }
+    }
+
+    fn parse_pipeline_definition(&mut self) -> Result<Token> {
+        let buf = self.reader.peek_many(9)?;
+        if buf.len() < 9
+            || buf[..8] != ['p', 'i', 'p', 'e', 'l', 'i', 'n', 'e']
+            || !buf[8].is_whitespace()
+        {
+            return Err(LexerError::UnmatchedTokenError(
+                TokenKind::PipelineDefinition,
+            ));
+        }
+        self.reader.advance(8);
+
+        let definition = self.parse_selector_raw()?;
+        match definition.split_once('=') {
+            Some((name, stages)) if is_selector_name(name.trim()) => {
+                Ok(Token::of_type(TokenType::PipelineDefinition(
+                    name.trim().to_string(),
+                    Self::parse_transforms(stages)?,
+                )))
+            }
+            Some((name, _)) => Err(LexerError::InvalidSelectorName(name.trim().to_string())),
+            None => Err(LexerError::InvalidSelectorName(
+                definition.trim().to_string(),
+            )),
+        }
+    }
+
+    /// Reads an `assert ...` up to the `;` or the end of the line. Quotes and the slashes of a
+    /// `/regex/` may hold any of these, with `\` escaping the next char.
+    fn parse_assertion(&mut self) -> Result<Token> {
+        let buf = self.reader.peek_many(7)?;
+        if buf.len() < 7 || buf[..6] != ['a', 's', 's', 'e', 'r', 't'] || !buf[6].is_whitespace() {
+            return Err(LexerError::UnmatchedTokenError(TokenKind::Assertion));
+        }
+        self.reader.advance(6);
+        let mut chars: Vec<char> = vec![];
+        let mut quote: Option<char> = None;
+        loop {
+            let next = match self.reader.peek() {
+                Ok(next) => next,
+                Err(ReaderError::Eof) => break,
+                Err(err) => return Err(err.into()),
+            };
+            match (quote, next) {
+                (None, ';' | '\n') => {
+                    self.reader.advance(1);
+                    break;
+                }
+                (None, '}') => break,
+                (None, '"' | '\'' | '/') => quote = Some(next),
+                (Some(_), '\\') => {
+                    chars.push(next);
+                    self.reader.advance(1);
+                }
+                (Some(q), c) if q == c => quote = None,
+                _ => {}
+            }
+            chars.extend(self.reader.peek().ok());
+            self.reader.advance(1);
         }
+        Ok(Token::of_type(TokenType::Assertion(
+            String::from_iter(chars).trim().to_string(),
+        )))
     }
 
+    /// Skips a `/* ... */` comment, which may contain further comments. The text of a doc comment,
+    /// `/** ... */`, is kept for [`Lexer::doc`].
+    fn parse_block_comment(&mut self) -> Result<Token> {
+        if self.reader.peek_many(2)? != ['/', '*'] {
+            return Err(LexerError::UnmatchedTokenError(TokenKind::Comment));
+        }
+        self.reader.advance(2);
+        let is_doc = self.reader.peek_many(2)?.first() == Some(&'*')
+            && self.reader.peek_many(2)? != ['*', '/'];
+
+        let mut depth = 1;
+        let mut chars: Vec<char> = vec![];
+        while depth > 0 {
+            match self.reader.peek_many(2)?.as_slice() {
+                ['/', '*'] => {
+                    depth += 1;
+                    chars.extend(['/', '*']);
+                    self.reader.advance(2);
+                }
+                ['*', '/'] => {
+                    depth -= 1;
+                    if depth > 0 {
+                        chars.extend(['*', '/']);
+                    }
+                    self.reader.advance(2);
+                }
+                [] => return Err(LexerError::UnterminatedComment),
+                [c, ..] => {
+                    chars.push(*c);
+                    self.reader.advance(1);
+                }
+            }
+        }
+        let text = doc_text(&String::from_iter(chars.iter().skip(1)));
+        if is_doc && !text.is_empty() {
+            self.doc = Some(text);
+        }
+        Ok(Token::of_type(TokenType::Comment))
+    }
+
+    fn parse_count(str: &str) -> Result<usize> {
+        str.parse::<usize>()
+            .map_err(|err| LexerError::InvalidQuantifier(err.to_string()))
+    }
+
+    /// Parses the quantifier after the `*`, naming it in the error if it is invalid.
     fn parse_quantifier(str: &str) -> Result<Quantifier> {
+        Self::parse_quantifier_kind(str).map_err(|err| match err {
+            LexerError::InvalidQuantifier(reason) => {
+                LexerError::InvalidQuantifier(format!("{} ({})", str, reason))
+            }
+            err => err,
+        })
+    }
+
+    fn parse_quantifier_kind(str: &str) -> Result<Quantifier> {
         match str {
-            "" => Ok(Quantifier::Many),
-            q => match q.parse::<u32>() {
-                Ok(amt) => Ok(Quantifier::Fixed(amt as usize)),
-                Err(err) => Err(LexerError::InvalidQuantifier(err.to_string())),
+            "" | "all" => Ok(Quantifier::All),
+            "first" => Ok(Quantifier::Single),
+            "last" => Ok(Quantifier::Last),
+            q => match q.split_once("..") {
+                Some((start, "")) => Ok(Quantifier::Skip(Self::parse_count(start)?)),
+                Some((start, end)) => {
+                    Quantifier::range(Self::parse_count(start)?, Self::parse_count(end)?)
+                }
+                None => Quantifier::fixed(Self::parse_count(q)?),
             },
         }
     }
 
-    fn parse_selector(&mut self) -> Result<Token> {
-        let selector = self.parse_literal_raw(';')?;
-        let selector_string;
-        let quantifier;
-
-        if selector.contains('*') {
-            let spl: Vec<&str> = selector.split('*').collect();
-            selector_string = spl[0];
-            let mut quantifier_str = "";
-            if spl.len() > 1 {
-                quantifier_str = spl[1];
+    /// Splits a selector and its quantifier, a `*` one or a trailing word, see
+    /// [`Lexer::split_star_quantifier`] and [`Lexer::split_word_quantifier`]. A `when ... else`
+    /// quantifier has one of these for its first branch, and a quantifier without the `*` for its
+    /// `else` branch.
+    fn split_quantifier(selector: &str) -> Result<(&str, Quantifier)> {
+        if let Some((rest, guard, otherwise)) = Self::split_conditional_quantifier(selector) {
+            let (rest, then) = Self::split_quantifier(rest)?;
+            let otherwise = otherwise
+                .strip_prefix('*')
+                .unwrap_or(otherwise)
+                .trim_start();
+            if guard.is_empty() || otherwise.is_empty() {
+                return Err(LexerError::InvalidQuantifier(format!(
+                    "when {} else {} (a conditional quantifier needs a guard and an else branch)",
+                    guard, otherwise
+                )));
             }
-            match Self::parse_quantifier(quantifier_str) {
-                Ok(q) => quantifier = q,
-                Err(err) => {
-                    return Err(LexerError::InvalidQuantifier(format!(
-                        "{} ({})",
-                        quantifier_str.to_string(),
-                        err.to_string()
-                    )))
+            let quantifier = Quantifier::When {
+                guard: guard.to_string(),
+                then: Box::new(then),
+                otherwise: Box::new(Self::parse_quantifier(otherwise)?),
+            };
+            return Ok((rest, quantifier));
+        }
+        if let Some((rest, quantifier)) = Self::split_star_quantifier(selector) {
+            // Whitespace around the `*` is insignificant, so `div * 3` is `div*3`.
+            return Ok((rest, Self::parse_quantifier(quantifier)?));
+        }
+        match Self::split_word_quantifier(selector) {
+            Some((rest, quantifier)) => Ok((rest, Self::parse_quantifier(&quantifier)?)),
+            None => Ok((selector, Quantifier::Single)),
+        }
+    }
+
+    /// Splits `Q when GUARD else R` into the selector with `Q`, `GUARD` and `R`, if `selector` ends
+    /// in a conditional quantifier. A `when <selector> { ... }` guard has no selector before the
+    /// `when`.
+    fn split_conditional_quantifier(selector: &str) -> Option<(&str, &str, &str)> {
+        let otherwise = Self::find_keyword(selector, "else")?;
+        let when = Self::find_keyword(&selector[..otherwise], "when")?;
+        let rest = selector[..when].trim_end();
+        if rest.is_empty() {
+            return None;
+        }
+        Some((
+            rest,
+            selector[when + "when".len()..otherwise].trim(),
+            selector[otherwise + "else".len()..].trim(),
+        ))
+    }
+
+    /// The index of the last `word` in `selector` that stands on its own, outside of quotes,
+    /// brackets and parentheses.
+    fn find_keyword(selector: &str, word: &str) -> Option<usize> {
+        let mut quote: Option<char> = None;
+        let mut depth = 0usize;
+        let mut previous = ' ';
+        let mut found: Option<usize> = None;
+        for (i, c) in selector.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '"' | '\'' => quote = Some(c),
+                    '[' | '(' => depth += 1,
+                    ']' | ')' => depth = depth.saturating_sub(1),
+                    _ if depth == 0
+                        && previous.is_whitespace()
+                        && selector[i..].starts_with(word)
+                        && selector[i + word.len()..]
+                            .chars()
+                            .next()
+                            .is_none_or(char::is_whitespace) =>
+                    {
+                        found = Some(i)
+                    }
+                    _ => {}
+                },
+            }
+            previous = c;
+        }
+        found
+    }
+
+    /// Splits a trailing quantifier word off a selector and translates it to its `*` form:
+    /// `li all` is `li*all` and `tr skip 1` is `tr*1..`.
+    fn split_word_quantifier(selector: &str) -> Option<(&str, String)> {
+        let (rest, last) = selector.rsplit_once(char::is_whitespace)?;
+        let rest = rest.trim_end();
+        match last {
+            "first" | "all" | "last" => Some((rest, last.to_string())),
+            count => match rest.rsplit_once(char::is_whitespace) {
+                Some((rest, "skip")) => Some((rest.trim_end(), format!("{}..", count))),
+                _ => None,
+            },
+        }
+    }
+
+    /// Splits a selector at its quantifier `*`: the last `*` outside of quotes, brackets and
+    /// parentheses that follows selector text other than a combinator or `@` and is followed by
+    /// nothing but a quantifier. Any other `*`, as in `*`, `div > *` or `[href*=x]`, is the
+    /// universal selector or part of an attribute selector, and the one of `a@*` takes every
+    /// attribute.
+    fn split_star_quantifier(selector: &str) -> Option<(&str, &str)> {
+        let mut quote: Option<char> = None;
+        let mut depth = 0usize;
+        let mut star: Option<usize> = None;
+        for (i, c) in selector.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '"' | '\'' => quote = Some(c),
+                    '[' | '(' => depth += 1,
+                    ']' | ')' => depth = depth.saturating_sub(1),
+                    '*' if depth == 0 => star = Some(i),
+                    _ => {}
+                },
+            }
+        }
+        let star = star?;
+        let (rest, quantifier) = (selector[..star].trim_end(), selector[star + 1..].trim());
+        if rest.is_empty()
+            || rest.ends_with(['>', '+', '~', ',', '@'])
+            || !quantifier
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.')
+        {
+            return None;
+        }
+        Some((rest, quantifier))
+    }
+
+    /// Splits the transforms off a selector. Each starts at a `|` with whitespace before it outside
+    /// of quotes, brackets and parentheses, so `[lang|=en]` and `svg|rect` are left alone.
+    fn split_transforms(selector: &str) -> Result<(&str, Vec<Transform>)> {
+        match Self::find_pipes(selector).first() {
+            Some(&first) => Ok((
+                selector[..first].trim_end(),
+                Self::parse_transforms(&selector[first + 1..])?,
+            )),
+            None => Ok((selector, vec![])),
+        }
+    }
+
+    /// Parses `|` separated transforms, as they follow the first `|` after a selector.
+    fn parse_transforms(stages: &str) -> Result<Vec<Transform>> {
+        let mut start = 0;
+        let mut transforms: Vec<Transform> = vec![];
+        for end in Self::find_pipes(stages).into_iter().chain([stages.len()]) {
+            transforms.push(Self::parse_transform(&stages[start..end])?);
+            start = end + 1;
+        }
+        Ok(transforms)
+    }
+
+    fn find_pipes(text: &str) -> Vec<usize> {
+        let mut quote: Option<char> = None;
+        let mut depth = 0usize;
+        let mut previous = ' ';
+        let mut pipes: Vec<usize> = vec![];
+        for (i, c) in text.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '"' | '\'' => quote = Some(c),
+                    '[' | '(' => depth += 1,
+                    ']' | ')' => depth = depth.saturating_sub(1),
+                    '|' if depth == 0 && previous.is_whitespace() => pipes.push(i),
+                    _ => {}
+                },
+            }
+            previous = c;
+        }
+        pipes
+    }
+
+    fn parse_transform(stage: &str) -> Result<Transform> {
+        let stage = stage.trim();
+        let invalid = || LexerError::InvalidTransform(stage.to_string());
+        let (name, argument) = match stage.split_once('(') {
+            Some((name, rest)) => {
+                let argument = rest.strip_suffix(')').ok_or_else(invalid)?.trim();
+                let mut chars = argument.chars();
+                match (chars.next(), chars.next_back()) {
+                    (Some(open @ ('"' | '\'')), Some(close)) if open == close => {
+                        (name.trim(), Some(chars.as_str().to_string()))
+                    }
+                    _ => return Err(invalid()),
                 }
             }
-        } else {
-            selector_string = selector.as_str();
-            quantifier = Quantifier::Single
+            None => (stage, None),
+        };
+        match (name, argument) {
+            ("trim", None) => Ok(Transform::Trim),
+            ("lowercase", None) => Ok(Transform::Lowercase),
+            ("uppercase", None) => Ok(Transform::Uppercase),
+            ("strip", Some(text)) => Ok(Transform::Strip(text)),
+            ("number", None) => Ok(Transform::Number),
+            ("unique", None) => Ok(Transform::Unique),
+            ("unique_taken", None) => Ok(Transform::UniqueTaken),
+            ("decode", None) => Ok(Transform::Decode),
+            ("raw", None) => Ok(Transform::Raw),
+            ("data_uri", None) => Ok(Transform::DataUri),
+            (name, None) if is_selector_name(name) => Ok(Transform::Pipeline(name.to_string())),
+            _ => Err(invalid()),
         }
+    }
+
+    fn parse_selector(&mut self) -> Result<Token> {
+        let raw = self.parse_selector_raw()?;
+        let (selector, transforms) = Self::split_transforms(&raw)?;
+        let (selector_string, quantifier) = Self::split_quantifier(selector)?;
 
         Ok(Token::of_type(TokenType::Selector(
             selector_string.to_string(),
             quantifier,
+            transforms,
         )))
     }
 
+    /// Starts lexing the source over from the beginning, so it can be tokenized again.
+    pub fn reset(&mut self) -> Result<()> {
+        self.reader.rewind()?;
+        self.token_start = 0;
+        self.flaggable = false;
+        self.after_page = false;
+        Ok(())
+    }
+
+    /// Byte offset of the next char in the source.
+    pub fn position(&self) -> usize {
+        self.reader.position()
+    }
+
+    /// Number of bytes left in the source, if its length is known.
+    pub fn remaining(&self) -> Option<u64> {
+        self.reader.remaining()
+    }
+
+    /// Byte offset at which the last token returned started.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// The doc comment right before the last token [`Lexer::next_non_whitespace`] returned, with
+    /// only whitespace and other comments in between.
+    pub fn doc(&self) -> Option<&String> {
+        self.doc.as_ref()
+    }
+
     pub fn next_non_whitespace(&mut self) -> Result<Token> {
+        self.doc = None;
         let mut token = self.next_token()?;
-        while token.token_type == TokenType::Whitespace {
+        while matches!(token.token_type, TokenType::Whitespace | TokenType::Comment) {
             token = self.next_token()?;
         }
         Ok(token)
     }
 
     pub fn next_token(&mut self) -> Result<Token> {
+        self.token_start = self.reader.position();
+        let flaggable = std::mem::take(&mut self.flaggable);
+        let after_page = std::mem::take(&mut self.after_page);
+        let token = match self.parse_page_options(after_page)? {
+            Some(token) => token,
+            None => match self.parse_flag(flaggable)? {
+                Some(token) => token,
+                None => self.lex_token()?,
+            },
+        };
+        self.flaggable = matches!(
+            token.token_type,
+            TokenType::Page
+                | TokenType::PageOptions(_)
+                | TokenType::Literal(LiteralType::Identifier, _)
+                | TokenType::Disabled
+                | TokenType::Optional
+        );
+        self.after_page = token.token_type == TokenType::Page;
+        Ok(token)
+    }
+
+    /// Reads the `[key=value, ...]` options right after `page`. Keys are identifier chars, values
+    /// bare words or quoted strings such as `".content > p"`.
+    fn parse_page_options(&mut self, after_page: bool) -> Result<Option<Token>> {
+        if !after_page || self.reader.peek().ok() != Some('[') {
+            return Ok(None);
+        }
+        self.reader.advance(1);
+        let mut options: Vec<(String, String)> = vec![];
+        loop {
+            self.skip_inline_whitespace();
+            let key = self.take_while(unicode_ident::is_xid_continue);
+            self.skip_inline_whitespace();
+            if key.is_empty() || self.reader.next_char()? != '=' {
+                return Err(LexerError::InvalidPageOption(format!(
+                    "expected key=value, found '{}'",
+                    key
+                )));
+            }
+            self.skip_inline_whitespace();
+            let value = match self.reader.peek()? {
+                quote @ ('"' | '\'') => {
+                    self.reader.advance(1);
+                    let mut value = String::new();
+                    loop {
+                        match self.reader.next_char()? {
+                            c if c == quote => break,
+                            '\\' => value.push(self.reader.next_char()?),
+                            c => value.push(c),
+                        }
+                    }
+                    value
+                }
+                _ => self.take_while(|c| !matches!(c, ',' | ']') && !c.is_whitespace()),
+            };
+            if value.is_empty() {
+                return Err(LexerError::InvalidPageOption(format!(
+                    "{} has no value",
+                    key
+                )));
+            }
+            options.push((key, value));
+            self.skip_inline_whitespace();
+            match self.reader.next_char()? {
+                ',' => {}
+                ']' => break,
+                c => {
+                    return Err(LexerError::InvalidPageOption(format!(
+                        "expected ',' or ']', found '{}'",
+                        c
+                    )))
+                }
+            }
+        }
+        Ok(Some(Token::of_type(TokenType::PageOptions(options))))
+    }
+
+    fn take_while(&mut self, mut predicate: impl FnMut(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Ok(c) = self.reader.peek() {
+            if !predicate(c) {
+                break;
+            }
+            taken.push(c);
+            self.reader.advance(1);
+        }
+        taken
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        self.take_while(|c| c == ' ' || c == '\t');
+    }
+
+    /// Reads a `.disabled` or `.optional` flag right after `page`, an identifier or another flag.
+    /// Anywhere else, or with whitespace in between, it is the start of a class selector.
+    fn parse_flag(&mut self, flaggable: bool) -> Result<Option<Token>> {
+        if !flaggable {
+            return Ok(None);
+        }
+        let buf = self.reader.peek_many(10)?;
+        let flag = match buf.get(..9) {
+            Some(['.', 'd', 'i', 's', 'a', 'b', 'l', 'e', 'd']) => TokenType::Disabled,
+            Some(['.', 'o', 'p', 't', 'i', 'o', 'n', 'a', 'l']) => TokenType::Optional,
+            _ => return Ok(None),
+        };
+        if buf
+            .get(9)
+            .is_some_and(|c| unicode_ident::is_xid_continue(*c) || *c == '-')
+        {
+            return Ok(None);
+        }
+        self.reader.advance(9);
+        Ok(Some(Token::of_type(flag)))
+    }
+
+    fn lex_token(&mut self) -> Result<Token> {
         match self.reader.peek() {
             Ok(next) => match next {
                 '"' => self.parse_literal(LiteralType::String, ('"', '"')),
+                '\'' => self.parse_literal(LiteralType::String, ('\'', '\'')),
                 ' ' | '\r' | '\n' | '\t' => {
                     self.reader.advance(1);
                     Ok(Token::of_type(TokenType::Whitespace))
@@ -236,7 +992,9 @@ impl Lexer {
                     Ok(Token::of_type(TokenType::Assignment))
                 }
                 'p' => {
-                    let page_parse_result = self.parse_page();
+                    let page_parse_result = self
+                        .parse_page()
+                        .or_else(|_| self.parse_pipeline_definition());
                     match page_parse_result {
                         Ok(res) => Ok(res),
                         Err(error) => match error {
@@ -245,6 +1003,21 @@ impl Lexer {
                         },
                     }
                 }
+                '/' => match self.parse_block_comment() {
+                    Ok(res) => Ok(res),
+                    Err(LexerError::UnmatchedTokenError(_)) => self.parse_selector(),
+                    Err(err) => Err(err),
+                },
+                's' => match self.parse_selector_definition() {
+                    Ok(res) => Ok(res),
+                    Err(LexerError::UnmatchedTokenError(_)) => self.parse_selector(),
+                    Err(err) => Err(err),
+                },
+                'a' => match self.parse_assertion() {
+                    Ok(res) => Ok(res),
+                    Err(LexerError::UnmatchedTokenError(_)) => self.parse_selector(),
+                    Err(err) => Err(err),
+                },
                 '$' => self.parse_identifier(),
                 '{' => {
                     self.reader.advance(1);
@@ -266,7 +1039,7 @@ impl Lexer {
                 },
             },
             Err(error) => match error {
-                ReaderError::EOF => Ok(Token::of_type(TokenType::EOF)),
+                ReaderError::Eof => Ok(Token::of_type(TokenType::Eof)),
                 _ => Err(LexerError::from(error)),
             },
         }
@@ -275,13 +1048,44 @@ impl Lexer {
     pub fn tokenize(&mut self) -> Result<Vec<Token>> {
         let mut tokens: Vec<Token> = vec![];
         let mut next_token = self.next_token()?;
-        while next_token.get_type() != TokenType::EOF {
+        while next_token.get_type() != TokenType::Eof {
             tokens.push(next_token);
             next_token = self.next_token()?;
         }
 
         Ok(tokens)
     }
+
+    /// Turns the lexer into an iterator that never fails. Where [`Lexer::tokenize`] would return an
+    /// error, it yields a [`TokenType::Error`] for the char the failing token started at and
+    /// carries on with the char after it.
+    pub fn recovering(self) -> RecoveringTokens {
+        RecoveringTokens { lexer: self }
+    }
+}
+
+pub struct RecoveringTokens {
+    lexer: Lexer,
+}
+
+impl Iterator for RecoveringTokens {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = &mut self.lexer.reader;
+        let position = reader.position();
+        reader.mark();
+        match self.lexer.next_token() {
+            Ok(token) if token == TokenType::Eof => None,
+            Ok(token) => Some(token),
+            Err(_) => {
+                let reader = &mut self.lexer.reader;
+                reader.reset();
+                let bad = reader.next_char().ok()?;
+                Some(Token::of_type(TokenType::Error(position, bad)))
+            }
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -290,17 +1094,229 @@ pub enum LexerError {
     ReaderError(String),
 
     #[error("Unmatched token type: {:?}", .0)]
-    UnmatchedTokenError(TokenType),
+    UnmatchedTokenError(TokenKind),
 
     #[error("An error occurred while parsing. Unexpected char: {}", .0)]
     UnexpectedChar(char),
 
     #[error("Invalid quantifier encountered: {}", .0)]
     InvalidQuantifier(String),
+
+    #[error("Block comment is not closed before the end of the source")]
+    UnterminatedComment,
+
+    #[error("Invalid selector name: {} (expected SCREAMING_CASE)", .0)]
+    InvalidSelectorName(String),
+
+    #[error("Unknown transform: {}", .0)]
+    InvalidTransform(String),
+
+    #[error("Invalid page options: {}", .0)]
+    InvalidPageOption(String),
 }
 
 impl From<ReaderError> for LexerError {
     fn from(value: ReaderError) -> Self {
-        return LexerError::ReaderError(value.to_string());
+        LexerError::ReaderError(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(source: &str) -> Result<(String, Quantifier)> {
+        let mut lexer = Lexer::for_source(&format!("{};", source));
+        match lexer.next_non_whitespace()?.get_type() {
+            TokenType::Selector(selector, quantifier, _) => Ok((selector, quantifier)),
+            other => panic!("expected a selector, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lexes_every_quantifier_form() {
+        let cases = [
+            ("li", Quantifier::Single),
+            ("li*", Quantifier::All),
+            ("li*all", Quantifier::All),
+            ("li*first", Quantifier::Single),
+            ("li*last", Quantifier::Last),
+            ("li*1", Quantifier::Fixed(1)),
+            ("li*12", Quantifier::Fixed(12)),
+            ("li*2..5", Quantifier::Range(2, 5)),
+            ("li*0..1", Quantifier::Range(0, 1)),
+            ("li*3..", Quantifier::Skip(3)),
+            ("li all", Quantifier::All),
+            ("li first", Quantifier::Single),
+            ("li last", Quantifier::Last),
+            ("li skip 2", Quantifier::Skip(2)),
+        ];
+        for (source, quantifier) in cases {
+            assert_eq!(
+                selector(source).unwrap(),
+                ("li".to_string(), quantifier),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_quantifiers() {
+        for source in ["li*x", "li*0", "li*3..3", "li*5..2", "li*1..x", "li*..3"] {
+            assert!(
+                matches!(selector(source), Err(LexerError::InvalidQuantifier(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn validates_counted_quantifiers() {
+        assert_eq!(Quantifier::fixed(1).unwrap(), Quantifier::Fixed(1));
+        assert!(Quantifier::fixed(0).is_err());
+        assert_eq!(Quantifier::range(0, 1).unwrap(), Quantifier::Range(0, 1));
+        assert!(Quantifier::range(2, 2).is_err());
+        assert!(Quantifier::range(3, 2).is_err());
+        for (source, message) in [
+            ("li*0", "0 (a count must be at least 1)"),
+            ("li*3..3", "3..3 (empty range 3..3)"),
+            ("li skip x", "x.. (invalid digit found in string)"),
+        ] {
+            match selector(source) {
+                Err(LexerError::InvalidQuantifier(reason)) => assert_eq!(reason, message),
+                other => panic!(
+                    "expected an invalid quantifier for {}, got {:?}",
+                    source, other
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn lexes_conditional_quantifiers() {
+        let when = |guard: &str, then, otherwise| Quantifier::When {
+            guard: guard.to_string(),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        };
+        let cases = [
+            (
+                ".item*all when .show-all else 10",
+                when(".show-all", Quantifier::All, Quantifier::Fixed(10)),
+            ),
+            (
+                ".item when a[href='x else y'] else *2..4",
+                when(
+                    "a[href='x else y']",
+                    Quantifier::Single,
+                    Quantifier::Range(2, 4),
+                ),
+            ),
+            (
+                ".item last when div > .more else first",
+                when("div > .more", Quantifier::Last, Quantifier::Single),
+            ),
+        ];
+        for (source, quantifier) in cases {
+            assert_eq!(
+                selector(source).unwrap(),
+                (".item".to_string(), quantifier),
+                "{}",
+                source
+            );
+        }
+        for source in [
+            ".item*all when else 10",
+            ".item*all when .x else",
+            ".item when .x else 0",
+        ] {
+            assert!(
+                matches!(selector(source), Err(LexerError::InvalidQuantifier(_))),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            selector("when .x else").unwrap(),
+            ("when .x else".to_string(), Quantifier::Single)
+        );
+    }
+
+    #[test]
+    fn allows_whitespace_around_the_star() {
+        for source in ["div*3", "div *3", "div * 3", "div\t*\t3", "  div  *  3  "] {
+            assert_eq!(
+                selector(source).unwrap(),
+                ("div".to_string(), Quantifier::Fixed(3)),
+                "{:?}",
+                source
+            );
+        }
+        assert_eq!(
+            selector("div * last").unwrap(),
+            ("div".to_string(), Quantifier::Last)
+        );
+    }
+
+    #[test]
+    fn tells_the_universal_selector_from_the_quantifier() {
+        let cases = [
+            ("*", "*", Quantifier::Single),
+            ("**all", "*", Quantifier::All),
+            ("div > *", "div > *", Quantifier::Single),
+            ("div > * * 2", "div > *", Quantifier::Fixed(2)),
+            ("a[href*=\"x\"]", "a[href*=\"x\"]", Quantifier::Single),
+            ("[data-x=1]", "[data-x=1]", Quantifier::Single),
+            ("[data-x=1]*last", "[data-x=1]", Quantifier::Last),
+            ("a@*", "a@*", Quantifier::Single),
+            ("a@* * 3", "a@*", Quantifier::Fixed(3)),
+        ];
+        for (source, expected, quantifier) in cases {
+            assert_eq!(
+                selector(source).unwrap(),
+                (expected.to_string(), quantifier),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn lexes_a_truncated_page_keyword_as_something_else() {
+        for source in ["p", "pa", "pag"] {
+            let token = Lexer::for_source(source).next_token();
+            assert!(
+                !matches!(token, Ok(ref t) if *t == TokenType::Page),
+                "{}",
+                source
+            );
+        }
+        assert!(Lexer::for_source("page").next_token().unwrap() == TokenType::Page);
+    }
+
+    #[test]
+    fn ends_selectors_at_newlines_unless_they_continue() {
+        let selectors = |source: &str| -> Vec<String> {
+            let mut lexer = Lexer::for_source(source);
+            let mut selectors: Vec<String> = vec![];
+            loop {
+                match lexer.next_non_whitespace().unwrap().get_type() {
+                    TokenType::Selector(selector, _, _) => selectors.push(selector),
+                    TokenType::Eof => return selectors,
+                    _ => {}
+                }
+            }
+        };
+        assert_eq!(selectors("h1\np;"), ["h1", "p"]);
+        assert_eq!(selectors("h1 }"), ["h1"]);
+        assert_eq!(selectors("h1"), ["h1"]);
+        assert_eq!(selectors("div,\n  span;\np"), ["div,   span", "p"]);
+        assert_eq!(
+            selectors("ul >\n  li\nh2 +\r\n  p ~\n  b"),
+            ["ul >   li", "h2 +\r   p ~   b"]
+        );
+        assert_eq!(selectors("a[title=\"x,\"]\nb"), ["a[title=\"x,\"]", "b"]);
     }
 }
@@ -1,11 +1,17 @@
-mod lexer;
+pub mod lexer;
 pub mod parser;
 mod reader;
 
 #[cfg(feature = "scrape")]
 pub mod scrape;
 
+#[cfg(all(test, feature = "scrape"))]
+mod fixtures;
+
 #[macro_use]
 extern crate pdml_macros;
 
+// The derives of pdml-macros name this crate, also when they are used inside of it.
+extern crate self as pdml_lib;
+
 pub use parser::{Error, Parser};
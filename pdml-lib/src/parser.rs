@@ -1,178 +1,1245 @@
 use crate::lexer;
-use crate::lexer::{Lexer, LexerError, LiteralType, ParenType, Token, TokenType};
+use crate::lexer::{
+    is_selector_name, Lexer, LexerError, LiteralType, ParenType, Token, TokenKind, TokenType,
+};
 use crate::parser::Error::{UnexpectedTokenError, UnexpectedTokenValidManyError};
 use crate::reader::{CharReader, ReaderError};
 #[cfg(feature = "scrape")]
 use crate::Error::ScraperError;
+use encoding_rs::Encoding;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use thiserror::Error;
-
-const ANY: &'static str = "any";
-
-macro_rules! any_string {
-    () => {
-        ANY.to_string()
-    };
-}
+use url::Url;
 
 pub struct Parser {
-    file: String,
+    file: Option<String>,
+    base_url: Option<String>,
+    encoding: Option<String>,
+    strict: bool,
+    max_input_bytes: Option<usize>,
+    profile: Option<String>,
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 impl Parser {
+    /// Creates a parser that isn't tied to a file. Sources are given to
+    /// [`Parser::parse_path`] and [`Parser::parse_source`] instead.
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            base_url: None,
+            encoding: None,
+            strict: false,
+            max_input_bytes: None,
+            profile: None,
+        }
+    }
+
     pub fn for_file(file: String) -> Self {
-        Self { file }
+        Self {
+            file: Some(file),
+            base_url: None,
+            encoding: None,
+            strict: false,
+            max_input_bytes: None,
+            profile: None,
+        }
+    }
+
+    /// Sets the url relative page urls are resolved against. Without one, relative urls are
+    /// rejected.
+    pub fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = Some(base_url);
+        self
+    }
+
+    /// Rejects selectors without an `$identifier`, which would parse but never be bound.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Sets the encoding source files are read in by its label, e.g. `windows-1252`. Defaults to
+    /// UTF-8. Sources passed as strings are already decoded and not affected.
+    pub fn with_encoding(mut self, encoding: String) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Rejects sources longer than `max` bytes. Files are checked by their size before they are
+    /// read, everything else as it is read. The limit is on the bytes as given, so a file in
+    /// another encoding counts its bytes before decoding, see [`Parser::with_encoding`].
+    pub fn with_max_input_bytes(mut self, max: usize) -> Self {
+        self.max_input_bytes = Some(max);
+        self
     }
 
+    /// Only keeps the pages of `profile`, and those without a `page[profile=...]`. Without a
+    /// profile every page is kept, whatever its profile.
+    pub fn with_profile(mut self, profile: String) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Parses the file given to [`Parser::for_file`].
     pub fn parse(&mut self) -> Result<Vec<Page>> {
-        let reader = CharReader::from_file(&self.file)?;
-        let lexer = Lexer::new(reader);
-        PageParser { lexer }.parse_pages()
+        match &self.file {
+            Some(file) => self.parse_path(file),
+            None => Err(Error::NoSourceError),
+        }
+    }
+
+    /// Parses the file given to [`Parser::for_file`] and renders it as S-expressions, one page per
+    /// line group. See [`Page::to_sexpr`].
+    pub fn parse_sexpr(&mut self) -> Result<String> {
+        let pages = self.parse()?;
+        Ok(pages
+            .iter()
+            .map(Page::to_sexpr)
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    /// Describes what `pages` scrape in Markdown: a section per page with its url, and a nested
+    /// list of its elements with their selectors, how many matches they take and their doc
+    /// comments.
+    pub fn to_markdown(pages: &[Page]) -> String {
+        pages
+            .iter()
+            .map(Page::to_markdown)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Renders `pages` as YAML, through the serde derives of the `serde` feature. Options that
+    /// aren't set, such as the `children` of an element without a block, are `null`.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(pages: &[Page]) -> Result<String> {
+        serde_yaml::to_string(pages)
+            .map_err(|err| Error::SerializationError("YAML", err.to_string()))
+    }
+
+    /// Reads pages rendered by [`Parser::to_yaml`]. Options left out read as not set, like `null`.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<Vec<Page>> {
+        serde_yaml::from_str(yaml).map_err(|err| Error::SerializationError("YAML", err.to_string()))
+    }
+
+    /// Renders `pages` as TOML, an array of tables `[[pages]]`. TOML has no `null`, so options
+    /// that aren't set are left out, as are the `children` of an element without a block.
+    #[cfg(feature = "toml")]
+    pub fn to_toml(pages: &[Page]) -> Result<String> {
+        toml::to_string(&TomlPages { pages })
+            .map_err(|err| Error::SerializationError("TOML", err.to_string()))
+    }
+
+    /// Reads pages rendered by [`Parser::to_toml`].
+    #[cfg(feature = "toml")]
+    pub fn from_toml(source: &str) -> Result<Vec<Page>> {
+        let document: TomlPages<Vec<Page>> = toml::from_str(source)
+            .map_err(|err| Error::SerializationError("TOML", err.to_string()))?;
+        Ok(document.pages)
+    }
+
+    /// Like [`Parser::parse`], but hands every page to `on_page` as soon as it is parsed instead of
+    /// collecting them, so the pages of a large file are never all held at once. An error
+    /// returned by `on_page` stops parsing and is returned as is.
+    pub fn parse_streaming(&mut self, mut on_page: impl FnMut(Page) -> Result<()>) -> Result<()> {
+        for page in self.pages()? {
+            on_page(page?)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Parser::parse`], but returns an iterator that parses the next page only when it is
+    /// asked for one. It ends after the last page or after yielding the first error.
+    pub fn pages(&mut self) -> Result<Pages> {
+        let path = match &self.file {
+            Some(file) => file.clone(),
+            None => return Err(Error::NoSourceError),
+        };
+        let reader = self.open(&path)?;
+        Ok(Pages {
+            parser: Some(self.page_parser(reader)?),
+            pending: VecDeque::new(),
+            source: path,
+        })
+    }
+
+    pub fn parse_path(&self, path: &str) -> Result<Vec<Page>> {
+        let mut pages = self.parse_reader(self.open(path)?)?;
+        for page in pages.iter_mut() {
+            page.source = Some(path.to_string());
+        }
+        Ok(pages)
+    }
+
+    pub fn parse_source(&self, source: &str) -> Result<Vec<Page>> {
+        self.parse_reader(CharReader::from_source(source))
+    }
+
+    /// Lexes `source` into every token, whitespace and comments included, each with its exact
+    /// source text. Concatenating the texts gives back `source`.
+    pub fn lex_full(source: &str) -> Result<Vec<Token>> {
+        let mut lexer = Lexer::for_source(source);
+        let mut tokens: Vec<Token> = vec![];
+        loop {
+            let token = lexer.next_token()?;
+            if token == TokenType::Eof {
+                return Ok(tokens);
+            }
+            let text = source[lexer.token_start()..lexer.position()].to_string();
+            tokens.push(token.with_text(text));
+        }
+    }
+
+    /// Parses `source` again after the bytes `edited` of the source `previous` was parsed from were
+    /// replaced with `inserted` bytes. If the edit lies strictly inside one `page` block, only that
+    /// block is parsed again and spliced between the untouched pages. Edits outside of any block or
+    /// across its `page` keyword or closing `}`, previous pages without spans and blocks that no
+    /// longer parse fall back to parsing all of `source`. The block is parsed with the named
+    /// selectors and pipelines defined before it, which are lexed again for that. Either way the
+    /// pages keep the [`Page::source`] of `previous`.
+    pub fn reparse(
+        &self,
+        source: &str,
+        previous: Vec<Page>,
+        edited: Range<usize>,
+        inserted: usize,
+    ) -> Result<Vec<Page>> {
+        let page_source = previous.first().and_then(|page| page.source.clone());
+        let parse_all = || -> Result<Vec<Page>> {
+            let mut pages = self.parse_source(source)?;
+            for page in pages.iter_mut() {
+                page.source = page_source.clone();
+            }
+            Ok(pages)
+        };
+        let span = previous.iter().find_map(|page| {
+            page.span
+                .clone()
+                .filter(|span| span.start < edited.start && edited.end < span.end)
+        });
+        let (span, end) = match span {
+            Some(span) if previous.iter().all(|page| page.span.is_some()) => {
+                let end = (span.end + inserted).checked_sub(edited.len());
+                match end.filter(|end| source.get(span.start..*end).is_some()) {
+                    Some(end) => (span, end),
+                    None => return parse_all(),
+                }
+            }
+            _ => return parse_all(),
+        };
+        let block = CharReader::from_source(&source[span.start..end]);
+        let reparsed = self.page_parser(block).and_then(|mut parser| {
+            parser.define_all(&source[..span.start])?;
+            parser.parse_pages()
+        });
+        let mut reparsed = match reparsed {
+            Ok(pages) if !pages.is_empty() => pages,
+            _ => return parse_all(),
+        };
+        // The merge group of the page is outside of its block.
+        let merge = previous
+            .iter()
+            .find(|page| page.span == Some(span.clone()))
+            .and_then(|page| page.merge.clone());
+        let mut pages: Vec<Page> = vec![];
+        let mut previous = previous.into_iter().peekable();
+        while let Some(page) = previous.next_if(|page| page.span != Some(span.clone())) {
+            pages.push(page);
+        }
+        for page in reparsed.iter_mut() {
+            page.source = page_source.clone();
+            page.merge = merge.clone();
+            page.move_spans(0, span.start);
+        }
+        pages.extend(reparsed);
+        for mut page in previous.skip_while(|page| page.span == Some(span.clone())) {
+            page.move_spans(span.end, end);
+            pages.push(page);
+        }
+        Ok(pages)
+    }
+
+    fn open(&self, path: &str) -> Result<CharReader> {
+        if let Some(max) = self.max_input_bytes {
+            let size = fs::metadata(path).map_err(ReaderError::from)?.len();
+            if size > max as u64 {
+                return Err(ReaderError::TooLarge(max).into());
+            }
+        }
+        match &self.encoding {
+            Some(label) => match Encoding::for_label(label.as_bytes()) {
+                Some(encoding) => Ok(CharReader::from_file_with_encoding(path, encoding)?),
+                None => Err(Error::ReaderError(format!("unknown encoding {}", label))),
+            },
+            None => Ok(CharReader::from_file(path)?),
+        }
+    }
+
+    fn parse_reader(&self, reader: CharReader) -> Result<Vec<Page>> {
+        self.page_parser(reader)?.parse_pages()
+    }
+
+    fn page_parser(&self, reader: CharReader) -> Result<PageParser> {
+        let reader = match self.max_input_bytes {
+            Some(max) => reader.with_limit(max)?,
+            None => reader,
+        };
+        let base_url = match &self.base_url {
+            Some(base) => Some(
+                Url::parse(base.trim())
+                    .map_err(|err| Error::InvalidUrlError(base.clone(), err.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(PageParser {
+            lexer: Lexer::new(reader),
+            base_url,
+            selectors: HashMap::new(),
+            pipelines: HashMap::new(),
+            page_count: 0,
+            strict: self.strict,
+            profile: self.profile.clone(),
+            depth: 0,
+            assertions: vec![],
+            merge: None,
+            merge_count: 0,
+        })
+    }
+
+    /// Parses every `.pdml` file directly inside `dir`, in filename order, with the settings of
+    /// this parser.
+    pub fn parse_dir(&self, dir: &str) -> Result<Vec<Page>> {
+        self.parse_files(Self::collect_files(Path::new(dir), false)?)
+    }
+
+    /// Like [`Parser::parse_dir`], but also descends into subdirectories.
+    pub fn parse_dir_recursive(&self, dir: &str) -> Result<Vec<Page>> {
+        self.parse_files(Self::collect_files(Path::new(dir), true)?)
+    }
+
+    fn collect_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = vec![];
+        for entry in fs::read_dir(dir).map_err(ReaderError::from)? {
+            let path = entry.map_err(ReaderError::from)?.path();
+            if path.is_dir() {
+                if recursive {
+                    files.extend(Self::collect_files(&path, true)?);
+                }
+            } else if path.extension().is_some_and(|ext| ext == "pdml") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn parse_files(&self, files: Vec<PathBuf>) -> Result<Vec<Page>> {
+        let mut pages: Vec<Page> = vec![];
+        for file in files {
+            let file = file.to_string_lossy().to_string();
+            let parsed = self
+                .parse_path(&file)
+                .map_err(|err| Error::FileError(file, Box::new(err)))?;
+            for page in parsed {
+                if let Some(other) = pages
+                    .iter()
+                    .find(|p| p.url == page.url && p.name == page.name)
+                {
+                    return Err(Error::DuplicatePageError(
+                        page.url,
+                        other.source.clone().unwrap_or_default(),
+                        page.source.unwrap_or_default(),
+                    ));
+                }
+                pages.push(page);
+            }
+        }
+        Ok(pages)
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pages of a file, parsed one at a time. See [`Parser::pages`].
+pub struct Pages {
+    parser: Option<PageParser>,
+    pending: VecDeque<Page>,
+    source: String,
+}
+
+impl Iterator for Pages {
+    type Item = Result<Page>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(mut page) = self.pending.pop_front() {
+                page.source = Some(self.source.clone());
+                return Some(Ok(page));
+            }
+            match self.parser.as_mut()?.next_pages() {
+                Ok(Some(pages)) => self.pending.extend(pages),
+                Ok(None) => {
+                    self.parser = None;
+                    return None;
+                }
+                Err(err) => {
+                    self.parser = None;
+                    return Some(Err(err));
+                }
+            }
+        }
     }
 }
 
 struct PageParser {
     lexer: Lexer,
+    base_url: Option<Url>,
+    selectors: HashMap<String, String>,
+    pipelines: HashMap<String, Vec<Transform>>,
+    page_count: usize,
+    strict: bool,
+    profile: Option<String>,
+    depth: usize,
+    assertions: Vec<Assertion>,
+    merge: Option<MergeGroup>,
+    merge_count: usize,
+}
+
+/// Expands the `{...}` templates of a page url like a shell would. `{a,b,c}` lists values, `{1..5}`
+/// counts from 1 to 5 and `{0..100..20}` counts in steps of 20. List items may be ranges, and
+/// several templates in one url expand to every combination.
+fn expand_url_template(url: &str) -> Result<Vec<String>> {
+    let invalid =
+        |reason: &str| Error::InvalidUrlTemplateError(url.to_string(), reason.to_string());
+    let mut urls = vec![String::new()];
+    let mut rest = url;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(invalid("unclosed '{'")),
+        };
+        let limit = MAX_TEMPLATE_URLS / urls.len();
+        let values =
+            expand_template_values(&rest[start + 1..end], limit).map_err(|err| invalid(&err))?;
+        let prefix = &rest[..start];
+        urls = urls
+            .iter()
+            .flat_map(|url| {
+                values
+                    .iter()
+                    .map(move |value| format!("{}{}{}", url, prefix, value))
+            })
+            .collect();
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(invalid("unopened '}'"));
+    }
+    for url in urls.iter_mut() {
+        url.push_str(rest);
+    }
+    Ok(urls)
+}
+
+/// How many urls one url template may expand into, so that a typo like `{0..100000000}` fails
+/// instead of exhausting memory.
+pub const MAX_TEMPLATE_URLS: usize = 10_000;
+
+/// Expands the values of one `{...}` of a url template, failing if there are more than `limit`.
+fn expand_template_values(
+    template: &str,
+    limit: usize,
+) -> std::result::Result<Vec<String>, String> {
+    let too_many = || format!("expands into more than {} urls", MAX_TEMPLATE_URLS);
+    let mut values: Vec<String> = vec![];
+    for item in template.split(',').map(str::trim) {
+        let bounds: Vec<&str> = item.split("..").collect();
+        match bounds.as_slice() {
+            [""] => return Err("empty template value".to_string()),
+            [value] => values.push(value.to_string()),
+            [start, end] | [start, end, _] => {
+                let number = |s: &str| {
+                    s.parse::<i64>()
+                        .map_err(|err| format!("invalid range {}: {}", item, err))
+                };
+                let (start, end) = (number(start)?, number(end)?);
+                let step = match bounds.get(2) {
+                    Some(step) => number(step)?,
+                    None => 1,
+                };
+                if start > end || step < 1 {
+                    return Err(format!("empty range {}", item));
+                }
+                let count = (end.abs_diff(start) / step as u64).saturating_add(1);
+                if count > (limit - values.len().min(limit)) as u64 {
+                    return Err(too_many());
+                }
+                values.extend((start..=end).step_by(step as usize).map(|n| n.to_string()));
+            }
+            _ => return Err(format!("invalid range {}", item)),
+        }
+        if values.len() > limit {
+            return Err(too_many());
+        }
+    }
+    Ok(values)
+}
+
+/// Rejects urls that can't be fetched over HTTP, such as `mailto:a@b.c` or `example.com:8080/list`,
+/// which parses with `example.com` as its scheme.
+fn web_url(raw: &str, url: Url) -> Result<Url> {
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(Error::InvalidUrlError(
+            raw.to_string(),
+            format!("unsupported scheme {}, expected http or https", scheme),
+        )),
+    }
 }
 
-fn expect(token_type: TokenType, got: &Token) -> Result<()> {
-    if *got == token_type {
+fn expect(kind: TokenKind, got: &Token) -> Result<()> {
+    if got.get_type().kind() == kind {
         Ok(())
     } else {
-        Err(UnexpectedTokenError(token_type, got.get_type()))
+        Err(UnexpectedTokenError(kind, Box::new(got.get_type())))
     }
 }
 
 impl PageParser {
     pub fn parse_pages(mut self) -> Result<Vec<Page>> {
-        let mut token = self.lexer.next_non_whitespace()?;
         let mut pages: Vec<Page> = vec![];
-        while token.get_type() != TokenType::EOF {
-            let mut partial_page = PartialPage::default();
-            expect(TokenType::Page, &token)?;
+        while let Some(next) = self.next_pages()? {
+            pages.extend(next);
+        }
+        Ok(pages)
+    }
+
+    /// Parses up to the end of the next page block and returns its pages, several if its url is a
+    /// template, or `None` at the end of the source. Errors in the block are wrapped in a
+    /// [`Error::PageError`] naming the page by its url, or as `#n` for the nth page before the url.
+    fn next_pages(&mut self) -> Result<Option<Vec<Page>>> {
+        let mut token = self.lexer.next_non_whitespace()?;
+        loop {
+            match token.get_type() {
+                TokenType::SelectorDefinition(name, selector) => {
+                    self.define_selector(name, &selector)?;
+                }
+                TokenType::PipelineDefinition(name, transforms) => {
+                    self.define_pipeline(name, transforms)?;
+                }
+                TokenType::Selector(selector, quantifier, transforms)
+                    if merge_header(&selector).is_some() =>
+                {
+                    let header = merge_header(&selector).unwrap_or_default();
+                    let shown = match header {
+                        "" => "group",
+                        header => header,
+                    };
+                    let invalid = |reason: &str| {
+                        Error::InvalidBlockError("merge", shown.to_string(), reason.to_string())
+                    };
+                    if self.merge.is_some() {
+                        return Err(invalid("merge groups can't be nested"));
+                    }
+                    if quantifier != Quantifier::Single || !transforms.is_empty() {
+                        return Err(invalid("a merge group takes no quantifier or transforms"));
+                    }
+                    self.merge_count += 1;
+                    let mut group =
+                        parse_merge_header(header).map_err(|reason| invalid(&reason))?;
+                    group.id = self.merge_count;
+                    self.merge = Some(group);
+                    token = self.lexer.next_non_whitespace()?;
+                    expect(TokenKind::Paren(ParenType::BlockOpen), &token)?;
+                }
+                TokenType::Paren(ParenType::BlockClose) if self.merge.is_some() => {
+                    self.merge = None;
+                }
+                _ => break,
+            }
+            token = self.lexer.next_non_whitespace()?;
+        }
+        if token == TokenType::Eof {
+            if self.merge.is_some() {
+                return Err(UnexpectedTokenError(
+                    TokenKind::Paren(ParenType::BlockClose),
+                    Box::new(token.get_type()),
+                ));
+            }
+            return Ok(None);
+        }
+        expect(TokenKind::Page, &token)?;
+        self.page_count += 1;
+        let mut url: Option<String> = None;
+        let mut pages = self.parse_page_definition(&mut url).map_err(|err| {
+            let page = match url {
+                Some(url) => format!("<{}>", url),
+                None => format!("#{}", self.page_count),
+            };
+            Error::PageError(page, Box::new(err))
+        })?;
+        if let Some(profile) = &self.profile {
+            pages.retain(|page| page.profile.as_ref().is_none_or(|own| own == profile));
+        }
+        for page in &mut pages {
+            page.merge = self.merge.clone();
+        }
+        Ok(Some(pages))
+    }
+
+    /// Parses a page block after its `page` keyword. Sets `url` to the page url as written as soon
+    /// as it is read, so that errors can name the page.
+    fn parse_page_definition(&mut self, url: &mut Option<String>) -> Result<Vec<Page>> {
+        let mut partial_page = PartialPage::default();
+        let start = self.lexer.token_start();
+        let mut token = self.lexer.next_non_whitespace()?;
+        if let TokenType::PageOptions(options) = token.get_type() {
+            for (key, value) in options {
+                set_page_option(&mut partial_page, key, value)?;
+            }
+            token = self.lexer.next_non_whitespace()?;
+        }
+        partial_page.screenshot.get_or_insert(false);
+        partial_page.disabled = Some(token == TokenType::Disabled);
+        if token == TokenType::Disabled {
+            token = self.lexer.next_non_whitespace()?;
+        }
+        expect(TokenKind::Literal(LiteralType::Url), &token)?;
+        let urls = match token.get_type() {
+            TokenType::Literal(LiteralType::Url, str) => {
+                *url = Some(str.clone());
+                self.expand_urls(&str)?
+            }
+            _ => panic!("Unexpected behaviour"),
+        };
+        partial_page.url = Some(urls[0].clone());
+        partial_page.mirrors = Some(vec![]);
+        token = self.lexer.next_non_whitespace()?;
+        // Further urls are mirrors, expanded like the page url. Each must expand to as many
+        // urls so that every expanded page gets its own mirrors.
+        let mut mirrors: Vec<Vec<String>> = vec![];
+        while let TokenType::Literal(LiteralType::Url, str) = token.get_type() {
+            let expanded = self.expand_urls(&str)?;
+            if expanded.len() != urls.len() {
+                return Err(Error::InvalidUrlTemplateError(
+                    str,
+                    format!(
+                        "expands to {} urls but the page url to {}",
+                        expanded.len(),
+                        urls.len()
+                    ),
+                ));
+            }
+            mirrors.push(expanded);
+            token = self.lexer.next_non_whitespace()?;
+        }
+        let mut expected = vec![TokenKind::Assignment];
+        if token == TokenType::Assignment {
             token = self.lexer.next_non_whitespace()?;
-            expect(TokenType::Literal(LiteralType::Url, any_string!()), &token)?;
+            expect(TokenKind::Literal(LiteralType::String), &token)?;
             match token.get_type() {
-                TokenType::Literal(LiteralType::Url, str) => {
-                    partial_page.url = Some(str);
+                TokenType::Literal(LiteralType::String, str) => {
+                    partial_page.name = Some(str);
                 }
                 _ => panic!("Unexpected behaviour"),
             }
             token = self.lexer.next_non_whitespace()?;
-            match token.get_type() {
-                TokenType::Assignment => {
-                    token = self.lexer.next_non_whitespace()?;
-                    expect(
-                        TokenType::Literal(LiteralType::String, any_string!()),
-                        &token,
-                    )?;
-                    match token.get_type() {
-                        TokenType::Literal(LiteralType::String, str) => {
-                            partial_page.name = Some(str);
-                        }
-                        _ => panic!("Unexpected behaviour"),
-                    }
-                    token = self.lexer.next_non_whitespace()?;
-                    expect(TokenType::Paren(ParenType::BlockOpen), &token)?;
-                    pages.push(self.parse_page(partial_page)?);
-                    token = self.lexer.next_non_whitespace()?;
+            expected.clear();
+        }
+        // A page header right before another one shares the block of the next, so that variants
+        // of a page such as `page[profile=prod]` and `page[profile=staging]` are written once.
+        let (mut page, shared) = match token.get_type() {
+            TokenType::Paren(ParenType::BlockOpen) => (self.parse_page(partial_page)?, vec![]),
+            TokenType::Page => {
+                let shared = self.parse_page_definition(&mut None)?;
+                partial_page.elements = Some(shared[0].elements.clone());
+                partial_page.assertions = Some(shared[0].assertions.clone());
+                (partial_page.into(), shared)
+            }
+            t => {
+                expected.extend([TokenKind::Paren(ParenType::BlockOpen), TokenKind::Page]);
+                return Err(UnexpectedTokenValidManyError(expected, t));
+            }
+        };
+        page.span = Some(start..self.lexer.position());
+        let urls = urls
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| (url, mirrors.iter().map(|m| m[i].clone()).collect()))
+            .collect();
+        let mut pages = page.with_urls(urls);
+        for mut page in shared {
+            page.span = Some(start..self.lexer.position());
+            pages.push(page);
+        }
+        Ok(pages)
+    }
+
+    fn expand_urls(&self, template: &str) -> Result<Vec<String>> {
+        expand_url_template(template)?
+            .iter()
+            .map(|url| self.normalize_url(url))
+            .collect()
+    }
+
+    fn define_selector(&mut self, name: String, selector: &str) -> Result<()> {
+        let selector = self.resolve_selector(selector)?;
+        if self.selectors.insert(name.clone(), selector).is_some() {
+            return Err(Error::DuplicateSelectorError(name));
+        }
+        Ok(())
+    }
+
+    fn define_pipeline(&mut self, name: String, transforms: Vec<Transform>) -> Result<()> {
+        let transforms = self.resolve_transforms(transforms)?;
+        if self.pipelines.insert(name.clone(), transforms).is_some() {
+            return Err(Error::DuplicatePipelineError(name));
+        }
+        Ok(())
+    }
+
+    /// Defines the named selectors and pipelines of `source`, skipping everything else in it, so
+    /// a page block parsed on its own can use the names defined before it.
+    fn define_all(&mut self, source: &str) -> Result<()> {
+        let mut lexer = Lexer::for_source(source);
+        loop {
+            match lexer.next_token()?.get_type() {
+                TokenType::Eof => return Ok(()),
+                TokenType::SelectorDefinition(name, selector) => {
+                    self.define_selector(name, &selector)?
                 }
-                TokenType::Paren(ParenType::BlockOpen) => {
-                    pages.push(self.parse_page(partial_page)?);
+                TokenType::PipelineDefinition(name, transforms) => {
+                    self.define_pipeline(name, transforms)?
                 }
-                t => {
-                    return Err(UnexpectedTokenValidManyError(
-                        vec![
-                            TokenType::Assignment,
-                            TokenType::Paren(ParenType::BlockOpen),
-                        ],
-                        t,
-                    ));
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the named selectors referenced in `selector`. References are the words in place of
+    /// a tag name, see [`tag_words`], so `PRODUCT h2`, `PRODUCT>h2`, `PRODUCT.active` and
+    /// `PRODUCT,h3` all reference `PRODUCT`. A word shaped like a name that is neither defined
+    /// before nor an HTML tag name fails with [`Error::UndefinedSelectorError`], while uppercase
+    /// tag names such as `H1` are still plain CSS.
+    fn resolve_selector(&self, selector: &str) -> Result<String> {
+        let mut resolved = String::new();
+        let mut end = 0;
+        for word in tag_words(selector) {
+            let name = &selector[word.clone()];
+            let named = match self.selectors.get(name) {
+                Some(named) => named,
+                None if is_selector_reference(name) => {
+                    return Err(Error::UndefinedSelectorError(name.to_string()))
                 }
+                None => continue,
+            };
+            resolved.push_str(&selector[end..word.start]);
+            resolved.push_str(named);
+            end = word.end;
+        }
+        resolved.push_str(&selector[end..]);
+        Ok(resolved)
+    }
+
+    /// Replaces the named selectors referenced in the guard of a `when ... else` quantifier.
+    fn resolve_quantifier(&self, quantifier: Quantifier) -> Result<Quantifier> {
+        Ok(match quantifier {
+            Quantifier::When {
+                guard,
+                then,
+                otherwise,
+            } => Quantifier::When {
+                guard: self.resolve_selector(&guard)?,
+                then,
+                otherwise,
+            },
+            quantifier => quantifier,
+        })
+    }
+
+    /// Replaces the named pipelines among `transforms` with their transforms. Like selectors,
+    /// pipelines are defined outside of pages and only have to be defined before they are used.
+    fn resolve_transforms(&self, transforms: Vec<Transform>) -> Result<Vec<Transform>> {
+        let mut resolved: Vec<Transform> = vec![];
+        for transform in transforms {
+            match transform {
+                Transform::Pipeline(name) => match self.pipelines.get(&name) {
+                    Some(pipeline) => resolved.extend(pipeline.iter().cloned()),
+                    None => return Err(Error::UndefinedPipelineError(name)),
+                },
+                transform => resolved.push(transform),
             }
         }
-        Ok(pages)
+        Ok(resolved)
+    }
+
+    fn normalize_url(&self, raw: &str) -> Result<String> {
+        let trimmed = raw.trim();
+        let url = match (Url::parse(trimmed), &self.base_url) {
+            (Err(url::ParseError::RelativeUrlWithoutBase), Some(base)) => base.join(trimmed),
+            (result, _) => result,
+        };
+        let url = url.map_err(|err| Error::InvalidUrlError(raw.to_string(), err.to_string()))?;
+        Ok(web_url(raw, url)?.to_string())
     }
 
     fn parse_page(&mut self, mut partial_page: PartialPage) -> Result<Page> {
         let token = self.lexer.next_non_whitespace()?;
         match token.get_type() {
-            TokenType::Paren(ParenType::BlockClose) => Ok(partial_page.into()),
-            TokenType::Literal(LiteralType::Identifier, _) | TokenType::Selector(_, _) => {
-                partial_page.elements = Some(self.parse_block(token.clone())?);
+            TokenType::Paren(ParenType::BlockClose) => {
+                partial_page.elements = Some(vec![]);
+                partial_page.assertions = Some(vec![]);
+                Ok(partial_page.into())
+            }
+            TokenType::Literal(LiteralType::Identifier, _)
+            | TokenType::Selector(_, _, _)
+            | TokenType::Assertion(_) => {
+                partial_page.elements = Some(self.parse_block(token.clone(), false)?);
+                partial_page.assertions = Some(std::mem::take(&mut self.assertions));
                 Ok(partial_page.into())
             }
             t => Err(UnexpectedTokenValidManyError(
                 vec![
-                    TokenType::Paren(ParenType::BlockClose),
-                    TokenType::Literal(LiteralType::Identifier, any_string!()),
-                    TokenType::Selector(any_string!(), Quantifier::Any),
+                    TokenKind::Paren(ParenType::BlockClose),
+                    TokenKind::Literal(LiteralType::Identifier),
+                    TokenKind::Selector,
                 ],
                 t,
             )),
         }
     }
 
-    fn parse_block(&mut self, initial_token: Token) -> Result<Vec<Element>> {
+    /// Sets the selector of `elem`, how its matches are extracted, which is given by a function
+    /// such as `jsonld("Product")` or an `@attribute` after the selector, its quantifier and its
+    /// transforms.
+    fn set_selector(
+        &self,
+        elem: &mut PartialElement,
+        selector: &str,
+        quantifier: Quantifier,
+        transforms: Vec<Transform>,
+    ) -> Result<()> {
+        let (css, extraction) = parse_extraction(selector)?;
+        if let Some(reason) = extraction_conflict(&extraction, &quantifier, &transforms) {
+            return Err(Error::InvalidExtractionError(
+                selector.to_string(),
+                reason.to_string(),
+            ));
+        }
+        elem.selector = Some(self.resolve_selector(&css)?);
+        elem.extraction = Some(extraction);
+        elem.quantifier = Some(self.resolve_quantifier(quantifier)?);
+        elem.transforms = Some(self.resolve_transforms(transforms)?);
+        Ok(())
+    }
+
+    /// Parses what follows `assert`: a subject, `$name` for the values of a top level element or
+    /// `count(selector)` for the number of matches in the document, and a predicate about it.
+    fn parse_assertion(&self, source: &str) -> Result<Assertion> {
+        let invalid = |reason: &str| {
+            Error::InvalidAssertionError(format!("assert {}", source), reason.to_string())
+        };
+        let (subject, rest) = if let Some(name) = source.strip_prefix('$') {
+            let end = name
+                .find(|c: char| !unicode_ident::is_xid_continue(c))
+                .unwrap_or(name.len());
+            if end == 0 {
+                return Err(invalid("expected an identifier after '$'"));
+            }
+            (
+                AssertionSubject::Values(name[..end].to_string()),
+                &name[end..],
+            )
+        } else if let Some(args) = source.strip_prefix("count(") {
+            let mut depth = 1;
+            let end = args
+                .char_indices()
+                .find(|(_, c)| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                })
+                .map(|(i, _)| i)
+                .ok_or_else(|| invalid("count( is not closed"))?;
+            if args[..end].trim().is_empty() {
+                return Err(invalid("the selector is missing"));
+            }
+            let selector = self.resolve_selector(args[..end].trim())?;
+            (AssertionSubject::Count(selector), &args[end + 1..])
+        } else {
+            return Err(invalid("expected $name or count(selector)"));
+        };
+        let rest = rest.trim();
+        let predicate = if rest == "nonempty" {
+            Predicate::NonEmpty
+        } else if let Some(pattern) = rest.strip_prefix("matches") {
+            let pattern = pattern
+                .trim()
+                .strip_prefix('/')
+                .and_then(|pattern| pattern.strip_suffix('/'))
+                .ok_or_else(|| invalid("expected a /regex/ after matches"))?
+                .replace("\\/", "/");
+            regex::Regex::new(&pattern).map_err(|err| invalid(&err.to_string()))?;
+            Predicate::Matches(pattern)
+        } else {
+            let (comparison, number) = COMPARISONS
+                .iter()
+                .find_map(|(op, comparison)| Some((*comparison, rest.strip_prefix(op)?)))
+                .ok_or_else(|| invalid("expected matches /regex/, nonempty or a comparison"))?;
+            let number = number
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| invalid("expected a number to compare with"))?;
+            Predicate::Compare(comparison, number)
+        };
+        if matches!(subject, AssertionSubject::Count(_))
+            && !matches!(predicate, Predicate::Compare(..))
+        {
+            return Err(invalid("a count can only be compared"));
+        }
+        Ok(Assertion {
+            source: format!("assert {}", source),
+            subject,
+            predicate,
+        })
+    }
+
+    /// Parses the elements of a block. Only blocks of an element are `scoped` and may hold
+    /// selectors starting with the `>` child combinator, the elements of a page have no parent to
+    /// start at.
+    fn parse_block(&mut self, initial_token: Token, scoped: bool) -> Result<Vec<Element>> {
         let mut token = initial_token;
         let mut elements: Vec<Element> = vec![];
         while token.get_type() != TokenType::Paren(ParenType::BlockClose) {
-            let mut elem = PartialElement::default();
+            if let TokenType::Assertion(source) = token.get_type() {
+                if self.depth > 0 {
+                    return Err(Error::InvalidAssertionError(
+                        format!("assert {}", source),
+                        "assertions go in the block of the page".to_string(),
+                    ));
+                }
+                let assertion = self.parse_assertion(&source)?;
+                self.assertions.push(assertion);
+                token = self.lexer.next_non_whitespace()?;
+                continue;
+            }
+            let mut elem = PartialElement {
+                doc: self.lexer.doc().cloned(),
+                ..Default::default()
+            };
             match token.get_type() {
                 TokenType::Literal(LiteralType::Identifier, iden) => {
                     elem.identifier = Some(iden);
                     token = self.lexer.next_non_whitespace()?;
-                    expect(TokenType::Assignment, &token)?;
+                    loop {
+                        match token.get_type() {
+                            TokenType::Disabled => elem.disabled = Some(true),
+                            TokenType::Optional => elem.optional = Some(true),
+                            _ => break,
+                        }
+                        token = self.lexer.next_non_whitespace()?;
+                    }
+                    expect(TokenKind::Assignment, &token)?;
                     token = self.lexer.next_non_whitespace()?;
-                    expect(TokenType::Selector(any_string!(), Quantifier::Any), &token)?;
+                    expect(TokenKind::Selector, &token)?;
                     match token.get_type() {
-                        TokenType::Selector(sel_str, quant) => {
-                            elem.selector = Some(sel_str);
-                            elem.quantifier = Some(quant.into());
+                        TokenType::Selector(sel_str, quant, transforms) => {
+                            self.set_selector(&mut elem, &sel_str, quant, transforms)?;
                         }
                         _ => panic!("Unexpected behaviour"),
                     }
                 }
-                TokenType::Selector(selector, quantifier) => {
-                    elem.selector = Some(selector);
-                    elem.quantifier = Some(quantifier.into());
+                TokenType::Selector(selector, quantifier, transforms)
+                    if keyword_selector(&selector, "when").is_some() =>
+                {
+                    let guard = keyword_selector(&selector, "when").unwrap_or_default();
+                    if quantifier != Quantifier::Single || !transforms.is_empty() {
+                        return Err(Error::InvalidBlockError(
+                            "when",
+                            guard.to_string(),
+                            "a guard takes no quantifier or transforms".to_string(),
+                        ));
+                    }
+                    elem.selector = Some(self.resolve_selector(guard)?);
+                    elem.quantifier = Some(quantifier);
+                    elem.extraction = Some(Extraction::Text);
+                    elem.transforms = Some(vec![]);
+                    elem.guard = Some(true);
+                }
+                TokenType::Selector(selector, quantifier, transforms)
+                    if keyword_selector(&selector, "list").is_some() =>
+                {
+                    let list = keyword_selector(&selector, "list").unwrap_or_default();
+                    if !transforms.is_empty() {
+                        return Err(Error::InvalidBlockError(
+                            "list",
+                            list.to_string(),
+                            "a list takes no transforms".to_string(),
+                        ));
+                    }
+                    if parse_extraction(list)?.1 != Extraction::Text {
+                        return Err(Error::InvalidBlockError(
+                            "list",
+                            list.to_string(),
+                            "a list only scopes its block, it extracts nothing".to_string(),
+                        ));
+                    }
+                    elem.selector = Some(self.resolve_selector(list)?);
+                    elem.quantifier = Some(match quantifier {
+                        Quantifier::Single => Quantifier::All,
+                        quantifier => self.resolve_quantifier(quantifier)?,
+                    });
+                    elem.extraction = Some(Extraction::Text);
+                    elem.transforms = Some(vec![]);
+                    elem.list = Some(true);
+                }
+                TokenType::Selector(selector, _, _) if self.strict => {
+                    return Err(Error::AnonymousSelectorError(selector));
+                }
+                TokenType::Selector(selector, quantifier, transforms) => {
+                    self.set_selector(&mut elem, &selector, quantifier, transforms)?;
                 }
                 t => {
                     return Err(UnexpectedTokenValidManyError(
                         vec![
-                            TokenType::Literal(LiteralType::Identifier, any_string!()),
-                            TokenType::Selector(any_string!(), Quantifier::Any),
+                            TokenKind::Literal(LiteralType::Identifier),
+                            TokenKind::Selector,
                         ],
                         t,
                     ));
                 }
             }
+            elem.span = Some(self.lexer.token_start()..self.lexer.position());
+            let guard = *elem.guard.get_or_insert(false);
+            let list = *elem.list.get_or_insert(false);
             token = self.lexer.next_non_whitespace()?;
             if token.get_type() == TokenType::Paren(ParenType::BlockOpen) {
+                let selector = elem.selector.clone().unwrap_or_default();
+                let reason = match elem.extraction {
+                    Some(Extraction::Attributes) => Some((
+                        format!("{}@*", selector),
+                        "the attributes are the records of the element, it takes no block",
+                    )),
+                    Some(Extraction::Count) => Some((
+                        format!("count({})", selector),
+                        "count extracts a number, not matches a block could scope",
+                    )),
+                    Some(Extraction::JsonLd { .. }) => Some((
+                        selector,
+                        "JSON-LD items aren't elements a block could scope",
+                    )),
+                    _ => None,
+                };
+                if let Some((selector, reason)) = reason {
+                    return Err(Error::InvalidExtractionError(selector, reason.to_string()));
+                }
                 token = self.lexer.next_non_whitespace()?;
-                elem.children = Some(self.parse_block(token.clone())?); // TODO performance
+                // The block of a guard is still matched in the scope the guard is in.
+                let children_scoped = !guard || scoped;
+                self.depth += 1;
+                let children = self.parse_block(token.clone(), children_scoped);
+                self.depth -= 1;
+                elem.children = Some(children?); // TODO performance
                 token = self.lexer.next_non_whitespace()?;
+            } else if guard || list {
+                return Err(Error::InvalidBlockError(
+                    if guard { "when" } else { "list" },
+                    elem.selector.unwrap_or_default(),
+                    "it needs a block".to_string(),
+                ));
+            }
+            if let Some(selector) = elem.selector.as_ref().filter(|s| s.starts_with('>')) {
+                if !scoped {
+                    return Err(Error::UnscopedChildSelectorError(selector.clone()));
+                }
             }
+            elem.disabled.get_or_insert(false);
+            elem.optional.get_or_insert(false);
             elements.push(elem.into());
         }
         Ok(elements)
     }
 }
 
+/// An `assert` of a page, checked after its elements are scraped. The scrape of the page fails if
+/// it doesn't hold.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Assertion {
+    source: String,
+    subject: AssertionSubject,
+    predicate: Predicate,
+}
+
+impl Assertion {
+    /// The assertion as written, `assert count(.item) >= 10`.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    pub fn subject(&self) -> &AssertionSubject {
+        &self.subject
+    }
+    pub fn predicate(&self) -> &Predicate {
+        &self.predicate
+    }
+}
+
+/// What an [`Assertion`] is about.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AssertionSubject {
+    /// `$name`: the values of the element `$name` at the top of the page. Comparisons compare the
+    /// number of values.
+    Values(String),
+    /// `count(selector)`: the number of matches of `selector` in the document.
+    Count(String),
+}
+
+/// What an [`Assertion`] expects of its subject.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Predicate {
+    /// `matches /regex/`: there is a value and every value matches the regex somewhere.
+    Matches(String),
+    /// `nonempty`: some value isn't empty.
+    NonEmpty,
+    /// `>= 10` and the like, with `<`, `<=`, `==`, `!=`, `>=` or `>`.
+    Compare(Comparison, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    pub fn holds(&self, left: usize, right: usize) -> bool {
+        match self {
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::GreaterOrEqual => left >= right,
+            Comparison::Greater => left > right,
+        }
+    }
+}
+
+/// The operators of [`Comparison`], two char ones first so that `>=` isn't read as `>`.
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+/// Sets an option of the `page[key=value, ...]` list. Every option may be given once.
+fn set_page_option(page: &mut PartialPage, key: String, value: String) -> Result<()> {
+    let invalid = |reason: &str| Error::InvalidPageOptionError(key.clone(), reason.to_string());
+    let given = match key.as_str() {
+        "wait_for" => page.wait_for.replace(value).is_some(),
+        "root" => page.root.replace(value).is_some(),
+        "profile" => page.profile.replace(value).is_some(),
+        "screenshot" => {
+            let screenshot = match value.as_str() {
+                "true" => true,
+                "false" => false,
+                _ => return Err(invalid("expected true or false")),
+            };
+            page.screenshot.replace(screenshot).is_some()
+        }
+        "follow_next" => {
+            let pages = value
+                .parse::<usize>()
+                .ok()
+                .filter(|pages| *pages > 0)
+                .ok_or_else(|| invalid("expected a number of pages above 0"))?;
+            page.follow_next.replace(pages).is_some()
+        }
+        _ => return Err(invalid("unknown option")),
+    };
+    if given {
+        return Err(invalid("given more than once"));
+    }
+    Ok(())
+}
+
+/// A `merge "name" { page ... page ... }` group. The elements its pages scrape are merged into
+/// one [`crate::scrape::ScrapedPage`] with the url of the first page and the name of the group,
+/// or of the first page if the group has none. Two pages scraping an element of the same name
+/// fail the scrape, unless the group is marked `merge override`, in which case the later page
+/// wins. Records of `list` blocks are kept from every page, in order.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeGroup {
+    id: usize,
+    name: Option<String>,
+    overrides: bool,
+}
+
+impl MergeGroup {
+    pub fn name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    /// Whether the group is marked `merge override`.
+    pub fn overrides(&self) -> bool {
+        self.overrides
+    }
+}
+
 #[partial]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Page {
     url: String,
+    mirrors: Vec<String>,
     name: Option<String>,
     elements: Vec<Element>,
+    disabled: bool,
+    wait_for: Option<String>,
+    root: Option<String>,
+    follow_next: Option<usize>,
+    profile: Option<String>,
+    screenshot: bool,
+    assertions: Vec<Assertion>,
+    merge: Option<MergeGroup>,
+    source: Option<String>,
+    span: Option<Range<usize>>,
 }
 impl Page {
     pub fn url(&self) -> &String {
         &self.url
     }
 
+    /// Urls to try in order when the page url fails, from `page <url> <mirror> ... { }`.
+    pub fn mirrors(&self) -> &Vec<String> {
+        &self.mirrors
+    }
+
     pub fn name(&self) -> Option<&String> {
         self.name.as_ref()
     }
@@ -180,15 +1247,524 @@ impl Page {
     pub fn elements(&self) -> &Vec<Element> {
         &self.elements
     }
-}
 
-#[partial]
-#[derive(Debug)]
+    /// Whether the page is marked `page.disabled`. Disabled pages stay in the parsed tree but are
+    /// not meant to be scraped.
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// The selector of `page[wait_for=".content"]`. A headless browser rendering the page waits
+    /// until it matches, for content a site only fills in after a while.
+    pub fn wait_for(&self) -> Option<&String> {
+        self.wait_for.as_ref()
+    }
+
+    /// The selector of `page[root=main]`, whose first match in the document the elements are
+    /// matched in instead of `<body>`. `page[root=html]` matches in the whole document, `<head>`
+    /// included.
+    pub fn root(&self) -> Option<&String> {
+        self.root.as_ref()
+    }
+
+    /// The profile of `page[profile=staging]`, see [`Parser::with_profile`].
+    pub fn profile(&self) -> Option<&String> {
+        self.profile.as_ref()
+    }
+
+    /// Whether the page is marked `page[screenshot=true]`, so that a headless scrape saves a
+    /// screenshot of it, see `ScrapeOptions::screenshot_dir`. Scrapes without a headless backend
+    /// take none.
+    pub fn screenshot(&self) -> bool {
+        self.screenshot
+    }
+
+    /// How many further pages `page[follow_next=10]` follows the `rel="next"` links of. They are
+    /// scraped with the elements of this page, until a page has no next link.
+    pub fn follow_next(&self) -> Option<usize> {
+        self.follow_next
+    }
+
+    /// Copies the page for the next page of `page[follow_next=...]` at `url`, following one page
+    /// less.
+    #[cfg(feature = "scrape")]
+    pub(crate) fn next_page(&self, url: String) -> Page {
+        Page {
+            url,
+            mirrors: vec![],
+            follow_next: self.follow_next.and_then(|pages| pages.checked_sub(1)),
+            ..self.clone()
+        }
+    }
+
+    /// The `merge { ... }` group the page is in, whose pages are scraped into one record.
+    pub fn merge(&self) -> Option<&MergeGroup> {
+        self.merge.as_ref()
+    }
+
+    /// The `assert`s of the page, in order.
+    pub fn assertions(&self) -> &Vec<Assertion> {
+        &self.assertions
+    }
+
+    pub fn source(&self) -> Option<&String> {
+        self.source.as_ref()
+    }
+
+    /// Byte range of the `page` block this page was parsed from. Pages expanded from one url
+    /// template share it, and pages that were built instead of parsed have none.
+    pub fn span(&self) -> Option<&Range<usize>> {
+        self.span.as_ref()
+    }
+
+    /// Moves the span of the page and its elements from offset `from` to offset `to` of the
+    /// source, as if the bytes in between were inserted or removed.
+    fn move_spans(&mut self, from: usize, to: usize) {
+        let move_span = |span: &mut Option<Range<usize>>| {
+            if let Some(span) = span {
+                *span = span.start + to - from..span.end + to - from;
+            }
+        };
+        move_span(&mut self.span);
+        let mut elements: Vec<&mut Element> = self.elements.iter_mut().collect();
+        while let Some(element) = elements.pop() {
+            move_span(&mut element.span);
+            elements.extend(element.children.iter_mut().flatten());
+        }
+    }
+
+    /// Copies the page once for every url, the page itself is used for the last one.
+    fn with_urls(self, urls: Vec<(String, Vec<String>)>) -> Vec<Page> {
+        let mut pages: Vec<Page> = vec![];
+        let mut urls = urls.into_iter().peekable();
+        while let Some((url, mirrors)) = urls.next() {
+            if urls.peek().is_some() {
+                pages.push(Page {
+                    url,
+                    mirrors,
+                    ..self.clone()
+                });
+            } else {
+                pages.push(Page {
+                    url,
+                    mirrors,
+                    ..self
+                });
+                break;
+            }
+        }
+        pages
+    }
+
+    /// Renders the page as an S-expression, `(page "url" "name" (element ...) ...)`, with every
+    /// element on its own indented line so that renderings diff well.
+    pub fn to_sexpr(&self) -> String {
+        let mut sexpr = format!("(page {}", sexpr_string(&self.url));
+        if let Some(name) = &self.name {
+            sexpr.push(' ');
+            sexpr.push_str(&sexpr_string(name));
+        }
+        if !self.mirrors.is_empty() {
+            let mirrors: Vec<String> = self.mirrors.iter().map(|m| sexpr_string(m)).collect();
+            sexpr.push_str(&format!(" (mirrors {})", mirrors.join(" ")));
+        }
+        if self.disabled {
+            sexpr.push_str(" disabled");
+        }
+        if let Some(wait_for) = &self.wait_for {
+            sexpr.push_str(&format!(" (wait_for {})", sexpr_string(wait_for)));
+        }
+        if let Some(root) = &self.root {
+            sexpr.push_str(&format!(" (root {})", sexpr_string(root)));
+        }
+        if let Some(pages) = self.follow_next {
+            sexpr.push_str(&format!(" (follow_next {})", pages));
+        }
+        if let Some(profile) = &self.profile {
+            sexpr.push_str(&format!(" (profile {})", sexpr_string(profile)));
+        }
+        if self.screenshot {
+            sexpr.push_str(" screenshot");
+        }
+        if let Some(group) = &self.merge {
+            sexpr.push_str(" (merge");
+            if let Some(name) = &group.name {
+                sexpr.push_str(&format!(" {}", sexpr_string(name)));
+            }
+            if group.overrides {
+                sexpr.push_str(" override");
+            }
+            sexpr.push(')');
+        }
+        for element in &self.elements {
+            element.write_sexpr(&mut sexpr, 1);
+        }
+        for assertion in &self.assertions {
+            sexpr.push_str(&format!("\n  (assert {})", sexpr_string(&assertion.source)));
+        }
+        sexpr.push(')');
+        sexpr
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut markdown = format!(
+            "## {}\n\n<{}>\n\n",
+            self.name.as_ref().unwrap_or(&self.url),
+            self.url
+        );
+        for mirror in &self.mirrors {
+            markdown.push_str(&format!("Mirror: <{}>\n\n", mirror));
+        }
+        if self.disabled {
+            markdown.push_str("Disabled, this page is not scraped.\n\n");
+        }
+        if let Some(wait_for) = &self.wait_for {
+            markdown.push_str(&format!("Scraped once `{}` matches.\n\n", wait_for));
+        }
+        if let Some(root) = &self.root {
+            markdown.push_str(&format!("Scraped inside `{}`.\n\n", root));
+        }
+        if let Some(pages) = self.follow_next {
+            markdown.push_str(&format!("Follows up to {} next pages.\n\n", pages));
+        }
+        if let Some(profile) = &self.profile {
+            markdown.push_str(&format!("Only in profile `{}`.\n\n", profile));
+        }
+        if self.screenshot {
+            markdown.push_str("A screenshot is taken when scraped headless.\n\n");
+        }
+        if self.merge.is_some() {
+            markdown.push_str("Merged with the other pages of its group.\n\n");
+        }
+        if self.elements.is_empty() {
+            markdown.push_str("Nothing is scraped from this page.\n");
+        }
+        for element in &self.elements {
+            element.write_markdown(&mut markdown, 0);
+        }
+        for assertion in &self.assertions {
+            markdown.push_str(&format!("- Checks `{}`\n", assertion.source));
+        }
+        markdown
+    }
+
+    /// Iterates over all elements of the page, children included, in document order.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            stack: vec![self.elements.iter()],
+        }
+    }
+}
+
+pub struct Walk<'a> {
+    stack: Vec<std::slice::Iter<'a, Element>>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = &'a Element;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(elements) = self.stack.last_mut() {
+            match elements.next() {
+                Some(element) => {
+                    if let Some(children) = &element.children {
+                        self.stack.push(children.iter());
+                    }
+                    return Some(element);
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+        None
+    }
+}
+
+#[partial]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Element {
     identifier: Option<String>,
     selector: String,
     quantifier: Quantifier,
+    extraction: Extraction,
+    transforms: Vec<Transform>,
+    disabled: bool,
+    optional: bool,
+    guard: bool,
+    list: bool,
     children: Option<Vec<Element>>,
+    span: Option<Range<usize>>,
+    doc: Option<String>,
+}
+
+/// What is taken from the matches of an element.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Extraction {
+    /// The text of every match, the `content` of `<meta>` tags.
+    Text,
+    /// Only the text directly inside every match, without the text of its child elements, from
+    /// `own(.price)`.
+    OwnText,
+    /// The number of matches, from `count(.review)`. Every match counts, so there is no quantifier.
+    Count,
+    /// The position of every match among all matches, counting from 0, from `index(.item)`. A
+    /// quantifier keeps the positions of the matches it takes, so `index(.item)*2..4` is `2` and
+    /// `3`, in line with the values of `.item*2..4`.
+    Index,
+    /// An attribute of every match, from `a@href`. Matches without it get the `default` of
+    /// `a@href ?? "none"`, or are left out if there is none. The name is lowercased, attribute
+    /// names being the same in any case, so `a@HREF` is `a@href`.
+    Attribute {
+        name: String,
+        default: Option<String>,
+    },
+    /// Every attribute of every match, from `.product@*`, as a record per match with an element
+    /// per attribute, by name. Values are decoded like those of single attributes.
+    Attributes,
+    /// The JSON-LD items of `schema_type` in the `<script type="application/ld+json">` blocks of
+    /// the document, from `jsonld("Product")`. With a `path`, as in `jsonld("Product",
+    /// "offers.price")`, only that field of every item. Needs the `jsonld` feature to be scraped.
+    JsonLd {
+        schema_type: String,
+        path: Option<String>,
+    },
+    /// The rows of every matched table, from `table(.prices)`, each row the text of its `td` and
+    /// `th` cells. With `table(.prices, "headers")` the first row names the columns of the others.
+    Table { headers: bool },
+    /// The term and description pairs of every matched definition list, from `dl(.specs)`. Each
+    /// `dt` pairs with every `dd` that follows it, and terms that follow each other share theirs.
+    Definitions,
+}
+
+/// What follows `merge` in the header of a merge group, `merge` alone being a group too.
+fn merge_header(selector: &str) -> Option<&str> {
+    match selector {
+        "merge" => Some(""),
+        selector => keyword_selector(selector, "merge"),
+    }
+}
+
+/// Parses the optional `"name"` and `override` after `merge`.
+fn parse_merge_header(header: &str) -> std::result::Result<MergeGroup, String> {
+    let mut group = MergeGroup::default();
+    let mut rest = header;
+    if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted.find('"').ok_or("unclosed name")?;
+        group.name = Some(quoted[..end].to_string());
+        rest = quoted[end + 1..].trim_start();
+    }
+    match rest {
+        "" => {}
+        "override" => group.overrides = true,
+        rest => {
+            return Err(format!(
+                "expected a name in quotes or override, got {}",
+                rest
+            ))
+        }
+    }
+    Ok(group)
+}
+
+/// The selector of a `when <selector> { ... }` guard or a `list <selector> { ... }`, if `selector`
+/// starts with the `keyword`.
+fn keyword_selector<'a>(selector: &'a str, keyword: &str) -> Option<&'a str> {
+    selector
+        .strip_prefix(keyword)
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim)
+}
+
+/// Selects the script blocks [`Extraction::JsonLd`] reads.
+pub const JSONLD_SELECTOR: &str = "script[type=\"application/ld+json\"]";
+
+/// Parses the extraction written in a selector: a function such as `jsonld("type", "path")` or
+/// `count(selector)`, `index(selector)`, `own(selector)` or `table(selector)`, or a selector
+/// ending in `@attribute`, optionally followed by `?? "default"`. Returns the CSS selector the
+/// extraction applies to along with it.
+fn parse_extraction(selector: &str) -> Result<(String, Extraction)> {
+    let invalid =
+        |reason: &str| Error::InvalidExtractionError(selector.to_string(), reason.to_string());
+    if let Some((function, args)) = extraction_call(selector) {
+        if function == "jsonld" {
+            let mut args = extraction_args(args)
+                .map_err(|reason| invalid(&reason))?
+                .into_iter();
+            let schema_type = args.next().ok_or_else(|| invalid("the type is missing"))?;
+            let path = args.next();
+            if args.next().is_some() {
+                return Err(invalid("expected a type and an optional path"));
+            }
+            let extraction = Extraction::JsonLd { schema_type, path };
+            return Ok((JSONLD_SELECTOR.to_string(), extraction));
+        }
+        let (args, extraction) = match function {
+            "count" => (args, Extraction::Count),
+            "index" => (args, Extraction::Index),
+            "own" => (args, Extraction::OwnText),
+            "table" => match args
+                .trim_end()
+                .strip_suffix("\"headers\"")
+                .and_then(|args| args.trim_end().strip_suffix(','))
+            {
+                Some(args) => (args, Extraction::Table { headers: true }),
+                None => (args, Extraction::Table { headers: false }),
+            },
+            "dl" => (args, Extraction::Definitions),
+            _ => unreachable!("{} is in EXTRACTION_FUNCTIONS", function),
+        };
+        if args.trim().is_empty() {
+            return Err(invalid("the selector is missing"));
+        }
+        return Ok((args.trim().to_string(), extraction));
+    }
+    if let Some(at) = find_unquoted(selector, "@") {
+        let (name, default) = match find_unquoted(selector, "??") {
+            Some(start) if start > at => {
+                let default = extraction_args(&selector[start + 2..]).map_err(|r| invalid(&r))?;
+                match <[String; 1]>::try_from(default) {
+                    Ok([default]) => (&selector[at + 1..start], Some(default)),
+                    Err(_) => return Err(invalid("expected one default after '??'")),
+                }
+            }
+            Some(_) => return Err(invalid("a default needs an @attribute before it")),
+            None => (&selector[at + 1..], None),
+        };
+        let name = name.trim();
+        if name == "*" && default.is_none() {
+            return Ok((
+                selector[..at].trim_end().to_string(),
+                Extraction::Attributes,
+            ));
+        } else if name == "*" {
+            return Err(invalid("every attribute is taken, there is no default"));
+        }
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':'))
+        {
+            return Err(invalid("expected an attribute name after '@'"));
+        }
+        let extraction = Extraction::Attribute {
+            name: name.to_lowercase(),
+            default,
+        };
+        return Ok((open_graph(selector[..at].trim_end()), extraction));
+    }
+    Ok((open_graph(selector), Extraction::Text))
+}
+
+/// Why `extraction` can't go with `quantifier` and `transforms`, if it can't. The combinations
+/// rejected are those whose result would surprise:
+///
+/// - `count(...)` with a quantifier, since it counts every match,
+/// - `count(...)` with `unique` or `unique_taken`, since there is a single count to compare,
+/// - `@*` with `unique` or `unique_taken`, since the attributes are records, not values,
+/// - `unique` together with `unique_taken`, which take different matches.
+///
+/// A block after `count(...)`, `@*` or `jsonld(...)` and an extraction in the selector of a
+/// `list` are rejected as well, as they are parsed.
+fn extraction_conflict(
+    extraction: &Extraction,
+    quantifier: &Quantifier,
+    transforms: &[Transform],
+) -> Option<&'static str> {
+    let unique = transforms.contains(&Transform::Unique);
+    let unique_taken = transforms.contains(&Transform::UniqueTaken);
+    match extraction {
+        Extraction::Count if *quantifier != Quantifier::Single => {
+            Some("count takes no quantifier, it counts every match")
+        }
+        Extraction::Count if unique || unique_taken => {
+            Some("count extracts a single number, unique has nothing to compare")
+        }
+        Extraction::Attributes if unique || unique_taken => {
+            Some("the attributes are records, unique only compares values")
+        }
+        _ if unique && unique_taken => {
+            Some("unique and unique_taken take different matches, use one of them")
+        }
+        _ => None,
+    }
+}
+
+/// Expands an OpenGraph shorthand such as `og:title` or `og:image:width` into the selector of its
+/// `<meta property="og:title">` tag. Other selectors are kept.
+fn open_graph(selector: &str) -> String {
+    let property = selector.trim().strip_prefix("og:").filter(|property| {
+        property.starts_with(|c: char| c.is_ascii_alphabetic())
+            && property
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '-'))
+    });
+    match property {
+        Some(property) => format!("meta[property=\"og:{}\"]", property),
+        None => selector.to_string(),
+    }
+}
+
+/// The functions a selector can be wrapped in to change what is extracted.
+const EXTRACTION_FUNCTIONS: [&str; 6] = ["jsonld", "count", "index", "own", "table", "dl"];
+
+/// Splits a selector written as `function(args)` with one of the [`EXTRACTION_FUNCTIONS`].
+fn extraction_call(selector: &str) -> Option<(&str, &str)> {
+    EXTRACTION_FUNCTIONS.iter().find_map(|function| {
+        let args = selector
+            .strip_prefix(function)?
+            .strip_prefix('(')?
+            .strip_suffix(')')?;
+        Some((*function, args))
+    })
+}
+
+/// Finds `pattern` in `selector` outside of quotes and `[...]` attribute selectors.
+fn find_unquoted(selector: &str, pattern: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut brackets = 0;
+    for (i, c) in selector.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => brackets += 1,
+            (None, ']') => brackets -= 1,
+            (None, _) if brackets == 0 && selector[i..].starts_with(pattern) => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the arguments of an extraction, a comma separated list of quoted strings.
+fn extraction_args(args: &str) -> std::result::Result<Vec<String>, String> {
+    let mut values: Vec<String> = vec![];
+    let mut chars = args.trim().chars().peekable();
+    while chars.peek().is_some() {
+        if chars.next() != Some('"') {
+            return Err("arguments must be quoted strings".to_string());
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => value.extend(chars.next()),
+                Some(c) => value.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        values.push(value);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some(',') => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+            Some(_) => return Err("arguments must be separated by ','".to_string()),
+            None => {}
+        }
+    }
+    Ok(values)
 }
 
 impl Element {
@@ -201,12 +1777,623 @@ impl Element {
     pub fn quantifier(&self) -> &Quantifier {
         &self.quantifier
     }
+    pub fn extraction(&self) -> &Extraction {
+        &self.extraction
+    }
+    pub fn transforms(&self) -> &Vec<Transform> {
+        &self.transforms
+    }
+    /// Whether the element is marked `$identifier.disabled`. See [`Page::disabled`].
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+    /// Whether the element is marked `$identifier.optional`, so that it may scrape nothing even
+    /// with `fail_on_empty`.
+    pub fn optional(&self) -> bool {
+        self.optional
+    }
+    /// Whether the element is a `when <selector> { ... }` guard. Its block is scraped in the scope
+    /// the guard is in if the selector matches there, and left out of the results otherwise.
+    pub fn guard(&self) -> bool {
+        self.guard
+    }
+    /// Whether the element is a `list <selector> { ... }`. Its block is scraped once for every
+    /// match into a record of the block the list is in, and lists may be nested. Lists take every
+    /// match unless they have another quantifier.
+    pub fn list(&self) -> bool {
+        self.list
+    }
+    /// Byte range of the selector in the source the element was parsed from, `None` for elements
+    /// that were built instead.
+    pub fn span(&self) -> Option<&Range<usize>> {
+        self.span.as_ref()
+    }
+    /// The text of the `/** ... */` doc comment right before the element, describing it for
+    /// [`Parser::to_markdown`].
+    pub fn doc(&self) -> Option<&String> {
+        self.doc.as_ref()
+    }
+    /// Runs `value` through the transforms of the element in order. See [`Transform::apply`].
+    pub fn transform(&self, value: &str) -> Option<String> {
+        self.transforms
+            .iter()
+            .try_fold(value.to_string(), |value, transform| {
+                transform.apply(&value)
+            })
+    }
     pub fn children(&self) -> &Option<Vec<Element>> {
         &self.children
     }
+
+    /// Renders the element as an S-expression, `(element $identifier "selector" quantifier ...)`.
+    pub fn to_sexpr(&self) -> String {
+        let mut sexpr = String::new();
+        self.write_sexpr(&mut sexpr, 0);
+        sexpr
+    }
+
+    fn write_sexpr(&self, sexpr: &mut String, depth: usize) {
+        if depth > 0 {
+            sexpr.push('\n');
+            sexpr.push_str(&"  ".repeat(depth));
+        }
+        if self.list {
+            sexpr.push_str(&format!(
+                "(list {} {}",
+                sexpr_string(&self.selector),
+                sexpr_quantifier(&self.quantifier)
+            ));
+            for child in self.children.iter().flatten() {
+                child.write_sexpr(sexpr, depth + 1);
+            }
+            sexpr.push(')');
+            return;
+        }
+        if self.guard {
+            sexpr.push_str(&format!("(when {}", sexpr_string(&self.selector)));
+            for child in self.children.iter().flatten() {
+                child.write_sexpr(sexpr, depth + 1);
+            }
+            sexpr.push(')');
+            return;
+        }
+        sexpr.push_str("(element");
+        if let Some(identifier) = &self.identifier {
+            sexpr.push_str(" $");
+            sexpr.push_str(identifier);
+        }
+        sexpr.push(' ');
+        sexpr.push_str(&sexpr_string(&self.selector));
+        sexpr.push(' ');
+        sexpr.push_str(&sexpr_quantifier(&self.quantifier));
+        let (extraction, args) = match &self.extraction {
+            Extraction::Text => ("", vec![]),
+            Extraction::Count => ("count", vec![]),
+            Extraction::Index => ("index", vec![]),
+            Extraction::OwnText => ("own", vec![]),
+            Extraction::Table { headers: false } => ("table", vec![]),
+            Extraction::Table { headers: true } => ("table headers", vec![]),
+            Extraction::Definitions => ("dl", vec![]),
+            Extraction::Attributes => ("attrs", vec![]),
+            Extraction::Attribute { name, default } => ("attr", vec![Some(name), default.as_ref()]),
+            Extraction::JsonLd { schema_type, path } => {
+                ("jsonld", vec![Some(schema_type), path.as_ref()])
+            }
+        };
+        if !extraction.is_empty() {
+            sexpr.push_str(&format!(" ({}", extraction));
+            for arg in args.into_iter().flatten() {
+                sexpr.push(' ');
+                sexpr.push_str(&sexpr_string(arg));
+            }
+            sexpr.push(')');
+        }
+        if !self.transforms.is_empty() {
+            let transforms: Vec<String> = self.transforms.iter().map(sexpr_transform).collect();
+            sexpr.push_str(&format!(" (transforms {})", transforms.join(" ")));
+        }
+        if self.disabled {
+            sexpr.push_str(" disabled");
+        }
+        if self.optional {
+            sexpr.push_str(" optional");
+        }
+        for child in self.children.iter().flatten() {
+            child.write_sexpr(sexpr, depth + 1);
+        }
+        sexpr.push(')');
+    }
+}
+
+impl Element {
+    fn write_markdown(&self, markdown: &mut String, depth: usize) {
+        markdown.push_str(&"  ".repeat(depth));
+        let doc = self
+            .doc
+            .as_ref()
+            .map(|doc| format!(" {}", doc))
+            .unwrap_or_default();
+        if self.list {
+            markdown.push_str(&format!(
+                "- A record for {} of `{}`:{}\n",
+                markdown_quantifier(&self.quantifier),
+                self.selector,
+                doc
+            ));
+            for child in self.children.iter().flatten() {
+                child.write_markdown(markdown, depth + 1);
+            }
+            return;
+        }
+        if self.guard {
+            markdown.push_str(&format!("- When `{}` matches:{}\n", self.selector, doc));
+            for child in self.children.iter().flatten() {
+                child.write_markdown(markdown, depth + 1);
+            }
+            return;
+        }
+        match &self.identifier {
+            Some(identifier) => markdown.push_str(&format!("- `${}`: ", identifier)),
+            None => markdown.push_str("- Unnamed: "),
+        }
+        match &self.extraction {
+            Extraction::Text => markdown.push_str(&format!(
+                "{} of `{}`",
+                markdown_quantifier(&self.quantifier),
+                self.selector
+            )),
+            Extraction::OwnText => markdown.push_str(&format!(
+                "the own text of {} of `{}`",
+                markdown_quantifier(&self.quantifier),
+                self.selector
+            )),
+            Extraction::Count => {
+                markdown.push_str(&format!("the number of matches of `{}`", self.selector))
+            }
+            Extraction::Index => markdown.push_str(&format!(
+                "the positions of {} of `{}`, counting from 0",
+                markdown_quantifier(&self.quantifier),
+                self.selector
+            )),
+            Extraction::Table { headers } => {
+                markdown.push_str(&format!(
+                    "the rows of {} of `{}`",
+                    markdown_quantifier(&self.quantifier),
+                    self.selector
+                ));
+                if *headers {
+                    markdown.push_str(", by the headers in the first row");
+                }
+            }
+            Extraction::Attributes => markdown.push_str(&format!(
+                "every attribute of {} of `{}`",
+                markdown_quantifier(&self.quantifier),
+                self.selector
+            )),
+            Extraction::Definitions => markdown.push_str(&format!(
+                "the terms and descriptions of {} of `{}`",
+                markdown_quantifier(&self.quantifier),
+                self.selector
+            )),
+            Extraction::Attribute { name, default } => {
+                markdown.push_str(&format!(
+                    "attribute `{}` of {} of `{}`",
+                    name,
+                    markdown_quantifier(&self.quantifier),
+                    self.selector
+                ));
+                if let Some(default) = default {
+                    markdown.push_str(&format!(", `{}` if it is missing", default));
+                }
+            }
+            Extraction::JsonLd { schema_type, path } => {
+                markdown.push_str(&format!(
+                    "{} of the JSON-LD `{}` items",
+                    markdown_quantifier(&self.quantifier),
+                    schema_type
+                ));
+                if let Some(path) = path {
+                    markdown.push_str(&format!(", field `{}`", path));
+                }
+            }
+        }
+        if !self.transforms.is_empty() {
+            let transforms: Vec<String> = self.transforms.iter().map(transform_source).collect();
+            markdown.push_str(&format!(", through `{}`", transforms.join(" | ")));
+        }
+        if self.disabled {
+            markdown.push_str(" (disabled)");
+        }
+        if self.optional {
+            markdown.push_str(" (optional)");
+        }
+        if !doc.is_empty() {
+            markdown.push_str(&format!(".{}", doc));
+        }
+        markdown.push('\n');
+        for child in self.children.iter().flatten() {
+            child.write_markdown(markdown, depth + 1);
+        }
+    }
+}
+
+fn markdown_quantifier(quantifier: &Quantifier) -> String {
+    match quantifier {
+        Quantifier::Single => "the first match".to_string(),
+        Quantifier::All => "every match".to_string(),
+        Quantifier::Last => "the last match".to_string(),
+        Quantifier::Fixed(1) => "the first match".to_string(),
+        Quantifier::Fixed(amt) => format!("the first {} matches", amt),
+        Quantifier::Range(start, end) => format!("matches {} to {}", start + 1, end),
+        Quantifier::Skip(amt) => format!("every match after the first {}", amt),
+        Quantifier::When {
+            guard,
+            then,
+            otherwise,
+        } => format!(
+            "{} if `{}` matches, otherwise {}",
+            markdown_quantifier(then),
+            guard,
+            markdown_quantifier(otherwise)
+        ),
+    }
+}
+
+fn sexpr_string(str: &str) -> String {
+    format!("\"{}\"", str.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn sexpr_quantifier(quantifier: &Quantifier) -> String {
+    match quantifier {
+        Quantifier::Single => "single".to_string(),
+        Quantifier::All => "all".to_string(),
+        Quantifier::Last => "last".to_string(),
+        Quantifier::Fixed(amt) => format!("(fixed {})", amt),
+        Quantifier::Range(start, end) => format!("(range {} {})", start, end),
+        Quantifier::Skip(amt) => format!("(skip {})", amt),
+        Quantifier::When {
+            guard,
+            then,
+            otherwise,
+        } => format!(
+            "(when {} {} {})",
+            sexpr_string(guard),
+            sexpr_quantifier(then),
+            sexpr_quantifier(otherwise)
+        ),
+    }
+}
+
+fn sexpr_transform(transform: &Transform) -> String {
+    match transform {
+        Transform::Strip(text) => format!("(strip {})", sexpr_string(text)),
+        transform => transform_source(transform),
+    }
 }
 
-type Quantifier = lexer::Quantifier;
+fn transform_source(transform: &Transform) -> String {
+    match transform {
+        Transform::Trim => "trim".to_string(),
+        Transform::Lowercase => "lowercase".to_string(),
+        Transform::Uppercase => "uppercase".to_string(),
+        Transform::Strip(text) => format!("strip({})", sexpr_string(text)),
+        Transform::Number => "number".to_string(),
+        Transform::Unique => "unique".to_string(),
+        Transform::UniqueTaken => "unique_taken".to_string(),
+        Transform::Decode => "decode".to_string(),
+        Transform::Raw => "raw".to_string(),
+        Transform::DataUri => "data_uri".to_string(),
+        Transform::Pipeline(name) => name.clone(),
+    }
+}
+
+pub type Quantifier = lexer::Quantifier;
+pub type Transform = lexer::Transform;
+
+/// Builds an [`Element`] in code instead of parsing it. Only the selector is required, the
+/// quantifier defaults to [`Quantifier::Single`].
+#[derive(Default)]
+pub struct ElementBuilder {
+    partial: PartialElement,
+}
+
+impl ElementBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn identifier(mut self, identifier: &str) -> Self {
+        self.partial.identifier = Some(identifier.to_string());
+        self
+    }
+
+    pub fn selector(mut self, selector: &str) -> Self {
+        self.partial.selector = Some(selector.to_string());
+        self
+    }
+
+    pub fn quantifier(mut self, quantifier: Quantifier) -> Self {
+        self.partial.quantifier = Some(quantifier);
+        self
+    }
+
+    /// Sets how matches are extracted, [`Extraction::Text`] by default. A JSON-LD extraction reads
+    /// the script blocks of the document whatever the selector is.
+    pub fn extraction(mut self, extraction: Extraction) -> Self {
+        self.partial.extraction = Some(extraction);
+        self
+    }
+
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.partial
+            .transforms
+            .get_or_insert_with(Vec::new)
+            .push(transform);
+        self
+    }
+
+    pub fn doc(mut self, doc: &str) -> Self {
+        self.partial.doc = Some(doc.to_string());
+        self
+    }
+
+    pub fn child(mut self, child: Element) -> Self {
+        self.partial
+            .children
+            .get_or_insert_with(Vec::new)
+            .push(child);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Element> {
+        match &self.partial.selector {
+            Some(selector) if !selector.trim().is_empty() => {}
+            _ => {
+                return Err(Error::IncompleteDefinitionError(
+                    "element has no selector".to_string(),
+                ))
+            }
+        }
+        self.partial.quantifier = Some(match self.partial.quantifier {
+            None => Quantifier::Single,
+            Some(quantifier) => checked_quantifier(quantifier)?,
+        });
+        self.partial.extraction.get_or_insert(Extraction::Text);
+        self.partial.transforms.get_or_insert_with(Vec::new);
+        self.partial.disabled.get_or_insert(false);
+        self.partial.optional.get_or_insert(false);
+        self.partial.guard.get_or_insert(false);
+        self.partial.list.get_or_insert(false);
+        Ok(self.partial.into())
+    }
+}
+
+/// Checks counted quantifiers, also those in the branches of a `when` one, like parsed ones, so
+/// that none takes nothing.
+fn checked_quantifier(quantifier: Quantifier) -> Result<Quantifier> {
+    Ok(match quantifier {
+        Quantifier::Fixed(amt) => Quantifier::fixed(amt)?,
+        Quantifier::Range(start, end) => Quantifier::range(start, end)?,
+        Quantifier::When {
+            guard,
+            then,
+            otherwise,
+        } => Quantifier::When {
+            guard,
+            then: Box::new(checked_quantifier(*then)?),
+            otherwise: Box::new(checked_quantifier(*otherwise)?),
+        },
+        quantifier => quantifier,
+    })
+}
+
+/// Builds a [`Page`] in code instead of parsing it. The url is required and validated like a
+/// parsed one.
+#[derive(Default)]
+pub struct PageBuilder {
+    partial: PartialPage,
+}
+
+impl PageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn url(mut self, url: &str) -> Self {
+        self.partial.url = Some(url.to_string());
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.partial.name = Some(name.to_string());
+        self
+    }
+
+    pub fn element(mut self, element: Element) -> Self {
+        self.partial
+            .elements
+            .get_or_insert_with(Vec::new)
+            .push(element);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Page> {
+        let url = match &self.partial.url {
+            Some(url) => web_url(
+                url,
+                Url::parse(url.trim())
+                    .map_err(|err| Error::InvalidUrlError(url.clone(), err.to_string()))?,
+            )?,
+            None => {
+                return Err(Error::IncompleteDefinitionError(
+                    "page has no url".to_string(),
+                ))
+            }
+        };
+        self.partial.url = Some(url.to_string());
+        self.partial.mirrors.get_or_insert_with(Vec::new);
+        self.partial.assertions.get_or_insert_with(Vec::new);
+        self.partial.disabled.get_or_insert(false);
+        self.partial.screenshot.get_or_insert(false);
+        if self.partial.elements.is_none() {
+            self.partial.elements = Some(vec![]);
+        }
+        Ok(self.partial.into())
+    }
+}
+
+/// The pages of [`Parser::to_toml`], under a key since a TOML document is a table.
+#[cfg(feature = "toml")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TomlPages<P> {
+    pages: P,
+}
+
+/// Lists `(page name, identifier, selector)` for every element of every page, in document order.
+pub fn collect_selectors(pages: &[Page]) -> Vec<(Option<&str>, Option<&str>, &str)> {
+    pages
+        .iter()
+        .flat_map(|page| {
+            page.walk().map(|element| {
+                (
+                    page.name.as_deref(),
+                    element.identifier.as_deref(),
+                    element.selector.as_str(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Checks that what the definitions of `pages` refer to exists, collecting every dangling reference
+/// instead of stopping at the first. Parsing already rejects selectors referencing a name that
+/// isn't defined before them, so this reports the names left in pages built or changed without
+/// the parser, such as `PRODUCT_CARD` or `CARD`, but not uppercase tag names like `H1`. The
+/// `$name` of an `assert` must be an element the page scrapes at the top, directly or through
+/// unnamed blocks and guards, and not one inside the block of another element or a `list`.
+pub fn validate_references(pages: &[Page]) -> std::result::Result<(), Vec<Error>> {
+    let mut errors: Vec<Error> = vec![];
+    for page in pages {
+        let in_page = |err: Error| Error::PageError(format!("<{}>", page.url), Box::new(err));
+        for element in page.walk() {
+            for word in tag_words(&element.selector) {
+                let word = &element.selector[word];
+                if is_selector_reference(word) {
+                    errors.push(in_page(Error::UndefinedSelectorError(word.to_string())));
+                }
+            }
+        }
+        let mut identifiers: Vec<&str> = vec![];
+        top_level_identifiers(&page.elements, &mut identifiers);
+        for assertion in &page.assertions {
+            if let AssertionSubject::Values(name) = &assertion.subject {
+                if !identifiers.contains(&name.as_str()) {
+                    errors.push(in_page(Error::UndefinedIdentifierError(
+                        name.clone(),
+                        assertion.source.clone(),
+                    )));
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// The byte ranges of the words of `selector` in place of a tag name: at the start, after
+/// whitespace, a combinator, a `,` or the `(` of a pseudo-class such as `:not(...)`, up to the next
+/// char that isn't alphanumeric, `_` or `-`. Classes, ids, pseudo-classes and what is in quotes or
+/// attribute brackets are left out, so of `PRODUCT.active > a[title="NEW"]` only `PRODUCT` and
+/// `a` are.
+fn tag_words(selector: &str) -> Vec<Range<usize>> {
+    let mut words: Vec<Range<usize>> = vec![];
+    let mut quote: Option<char> = None;
+    let mut brackets = 0;
+    let mut start: Option<usize> = None;
+    let mut tag_position = true;
+    for (i, c) in selector.char_indices() {
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' || c == '-' {
+            if tag_position && brackets == 0 {
+                start.get_or_insert(i);
+            }
+            tag_position = false;
+            continue;
+        }
+        if let Some(start) = start.take() {
+            words.push(start..i);
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '[' => brackets += 1,
+            ']' if brackets > 0 => brackets -= 1,
+            _ => {}
+        }
+        tag_position = c.is_whitespace() || matches!(c, '>' | '+' | '~' | ',' | '(');
+    }
+    if let Some(start) = start {
+        words.push(start..selector.len());
+    }
+    words
+}
+
+/// Whether `word`, in place of a tag name, references a named selector: it is shaped like a name
+/// but isn't the name of an HTML tag in uppercase, as `H1` or `DIV` are.
+fn is_selector_reference(word: &str) -> bool {
+    is_selector_name(word)
+        && !HTML_TAGS
+            .split(' ')
+            .any(|tag| tag.eq_ignore_ascii_case(word))
+}
+
+/// The tag names of HTML, with those of the SVG and MathML roots it embeds, space separated.
+const HTML_TAGS: &str =
+    "a abbr address area article aside audio b base bdi bdo blockquote body br button canvas \
+     caption center cite code col colgroup data datalist dd del details dfn dialog div dl dt em \
+     embed fieldset figcaption figure font footer form h1 h2 h3 h4 h5 h6 head header hgroup hr \
+     html i iframe img input ins kbd label legend li link main map mark math menu meta meter \
+     nav noscript object ol optgroup option output p picture pre progress q rp rt ruby s samp \
+     script search section select slot small source span strong style sub summary sup svg table \
+     tbody td template textarea tfoot th thead time title tr track u ul var video wbr";
+
+/// Adds the identifiers of `elements` whose values end up at the top of the scraped page.
+fn top_level_identifiers<'a>(elements: &'a [Element], identifiers: &mut Vec<&'a str>) {
+    for element in elements {
+        match &element.identifier {
+            Some(identifier) => identifiers.push(identifier),
+            None if !element.list => {
+                top_level_identifiers(element.children.as_deref().unwrap_or_default(), identifiers)
+            }
+            None => {}
+        }
+    }
+}
+
+/// Hashes `pages` into a fingerprint that is the same for equivalent definitions. It is FNV-1a
+/// over [`Page::to_sexpr`], so it changes with anything the S-expressions hold: the url, mirrors,
+/// name and `[...]` options of a page, whether it is disabled, its `merge` group and its asserts,
+/// and for every element its identifier, selector, quantifier, `when ... else` guard included,
+/// extraction such as `@href`, `@*`, `dl(...)` or `jsonld(...)`, transforms, `.disabled` and
+/// `.optional` flags and whether it is a `when` or `list` block. Layout, comments and where the
+/// pages were read from don't count, and the hash is the same across runs and builds.
+pub fn fingerprint(pages: &[Page]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for page in pages {
+        for byte in page.to_sexpr().bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -216,15 +2403,131 @@ pub enum Error {
     #[error("Error while processing the source: {}", .0)]
     LexerError(String),
 
-    #[error("Unexpected token: expected {:?}, got {:?}", .0, .1)]
-    UnexpectedTokenError(TokenType, TokenType),
+    #[error("Unexpected token: expected {}, got {}", .0, .1)]
+    UnexpectedTokenError(TokenKind, Box<TokenType>),
 
-    #[error("Unexpected token: expected either of the following {:?}, got {:?}", .0, .1)]
-    UnexpectedTokenValidManyError(Vec<TokenType>, TokenType),
+    #[error("Unexpected token: expected {}, got {}", one_of(.0), .1)]
+    UnexpectedTokenValidManyError(Vec<TokenKind>, TokenType),
+
+    #[error("Invalid url <{}>: {}", .0, .1)]
+    InvalidUrlError(String, String),
+
+    #[error("Invalid url template <{}>: {}", .0, .1)]
+    InvalidUrlTemplateError(String, String),
+
+    #[error("Selector {} is defined more than once", .0)]
+    DuplicateSelectorError(String),
+
+    #[error("Selector {} is used before it is defined", .0)]
+    UndefinedSelectorError(String),
+
+    #[error("${} is not scraped at the top of the page, but {} refers to it", .0, .1)]
+    UndefinedIdentifierError(String, String),
+
+    #[error("Pipeline {} is used before it is defined", .0)]
+    UndefinedPipelineError(String),
+
+    #[error("Pipeline {} is defined more than once", .0)]
+    DuplicatePipelineError(String),
+
+    #[error("Selector {} has no identifier, which strict mode requires", .0)]
+    AnonymousSelectorError(String),
+
+    #[error("Selector {} starts with '>' but has no parent element", .0)]
+    UnscopedChildSelectorError(String),
+
+    #[error("Invalid {} {}: {}", .0, .1, .2)]
+    InvalidBlockError(&'static str, String, String),
+
+    #[error("Invalid page option {}: {}", .0, .1)]
+    InvalidPageOptionError(String, String),
+
+    #[error("Invalid assertion {}: {}", .0, .1)]
+    InvalidAssertionError(String, String),
+
+    #[error("Invalid extraction {}: {}", .0, .1)]
+    InvalidExtractionError(String, String),
+
+    #[error("Incomplete definition: {}", .0)]
+    IncompleteDefinitionError(String),
+
+    #[error("The parser has no file to parse")]
+    NoSourceError,
+
+    #[error("In {}: {}", .0, .1)]
+    FileError(String, Box<Error>),
+
+    #[error("In page {}: {}", .0, .1)]
+    PageError(String, Box<Error>),
+
+    #[error("Page <{}> is defined in both {} and {}", .0, .1, .2)]
+    DuplicatePageError(String, String, String),
 
     #[cfg(feature = "scrape")]
     #[error("Error while scraping the site: {}", .0)]
     ScraperError(String),
+
+    #[cfg(feature = "scrape")]
+    #[error("{}", .0)]
+    InvalidSelectorError(Box<InvalidSelector>),
+
+    #[cfg(feature = "scrape")]
+    #[error("{} matched nothing on {}", .0, .1)]
+    EmptyElementError(String, String),
+
+    #[cfg(feature = "scrape")]
+    #[error("${} is scraped by more than one page of merge group {}", .0, .1)]
+    MergeConflictError(String, String),
+
+    #[cfg(feature = "json")]
+    #[error("Error while writing the results: {}", .0)]
+    WriteError(String),
+
+    #[cfg(any(feature = "yaml", feature = "toml"))]
+    #[error("Error while converting pages from or to {}: {}", .0, .1)]
+    SerializationError(&'static str, String),
+}
+
+/// A selector the scraper couldn't compile, with where it is used and defined as far as known.
+#[cfg(feature = "scrape")]
+#[derive(Debug)]
+pub struct InvalidSelector {
+    pub selector: String,
+    pub reason: String,
+    /// The `$name` of the element with the selector, `None` if it has none.
+    pub element: Option<String>,
+    /// The url of the page the selector was used on.
+    pub url: Option<String>,
+    /// Byte offset of the selector in the source of the definition, see [`Element::span`].
+    pub offset: Option<usize>,
+    /// Line of the selector in the definition file, counting from 1, if the file is known.
+    pub line: Option<usize>,
+}
+
+#[cfg(feature = "scrape")]
+impl std::fmt::Display for InvalidSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid selector {}", self.selector)?;
+        if let Some(element) = &self.element {
+            write!(f, " of ${}", element)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, " on <{}>", url)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " at line {}", line)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+fn one_of(kinds: &[TokenKind]) -> String {
+    let names: Vec<String> = kinds.iter().map(|kind| kind.to_string()).collect();
+    match names.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => "nothing".to_string(),
+    }
 }
 
 impl From<ReaderError> for Error {
@@ -245,3 +2548,1038 @@ impl From<reqwest::Error> for Error {
         ScraperError(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Vec<Page>> {
+        Parser::new().parse_source(source)
+    }
+
+    fn error(source: &str) -> String {
+        parse(source).unwrap_err().to_string()
+    }
+
+    #[cfg(any(feature = "yaml", feature = "toml"))]
+    const SERIALIZED: &str = r#"merge "shop" {
+        page[wait_for=main, follow_next=2].disabled <https://a.com/{1..2}> = "Shop" {
+            $title = h1 | trim | strip("!");
+            $links.optional = a@href ?? "none" *all when .more else 2..4;
+            $rows = table(.prices, "headers");
+            list .item { $name = .name*3; $price.disabled = own(.price); }
+            assert $title matches /Shop/;
+            assert count(.item) >= 2;
+        }
+        page <https://b.com> { }
+    }"#;
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn round_trips_pages_through_yaml() {
+        let pages = parse(SERIALIZED).unwrap();
+        let yaml = Parser::to_yaml(&pages).unwrap();
+        assert!(yaml.contains("children: null"));
+        let read = Parser::from_yaml(&yaml).unwrap();
+        let sexprs = |pages: &[Page]| pages.iter().map(Page::to_sexpr).collect::<Vec<_>>();
+        assert_eq!(sexprs(&read), sexprs(&pages));
+        assert_eq!(read[0].span, pages[0].span);
+
+        let yaml = "- url: https://c.com/\n  mirrors: []\n  elements: []\n  disabled: false\n  \
+                    screenshot: false\n  assertions: []\n";
+        let read = Parser::from_yaml(yaml).unwrap();
+        assert_eq!(read[0].to_sexpr(), "(page \"https://c.com/\")");
+        assert!(matches!(
+            Parser::from_yaml("- url: 1"),
+            Err(Error::SerializationError("YAML", _))
+        ));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn round_trips_pages_through_toml() {
+        let pages = parse(SERIALIZED).unwrap();
+        let toml = Parser::to_toml(&pages).unwrap();
+        assert!(toml.starts_with("[[pages]]"));
+        assert!(toml.contains("[[pages.elements.children]]"));
+        assert!(!toml.contains("children = "));
+        let read = Parser::from_toml(&toml).unwrap();
+        let sexprs = |pages: &[Page]| pages.iter().map(Page::to_sexpr).collect::<Vec<_>>();
+        assert_eq!(sexprs(&read), sexprs(&pages));
+        assert!(read[0].elements()[0].children().is_none());
+        assert!(matches!(
+            Parser::from_toml("pages = 1"),
+            Err(Error::SerializationError("TOML", _))
+        ));
+    }
+
+    #[test]
+    fn reuses_named_pipelines() {
+        let pages = parse(
+            r#"pipeline PRICE = trim | strip("$") | number;
+            page <https://a.com> { $price = .price | PRICE; $old = .old | lowercase | PRICE; }
+            page <https://b.com> { $price = .price | PRICE; }"#,
+        )
+        .unwrap();
+        let price = vec![
+            Transform::Trim,
+            Transform::Strip("$".into()),
+            Transform::Number,
+        ];
+        assert_eq!(pages[0].elements()[0].transforms(), &price);
+        assert_eq!(pages[0].elements()[1].transforms()[0], Transform::Lowercase);
+        assert_eq!(pages[0].elements()[1].transforms()[1..], price);
+        assert_eq!(pages[1].elements()[0].transforms(), &price);
+        assert_eq!(pages[0].elements()[0].transform(" $1.50 ").unwrap(), "1.5");
+    }
+
+    #[test]
+    fn rejects_undefined_and_duplicate_pipelines() {
+        assert_eq!(
+            error("page <https://a.com> { $price = .price | PRICE; } pipeline PRICE = trim;"),
+            "In page <https://a.com>: Pipeline PRICE is used before it is defined"
+        );
+        assert_eq!(
+            error("pipeline PRICE = trim; pipeline PRICE = number;"),
+            "Pipeline PRICE is defined more than once"
+        );
+    }
+
+    #[test]
+    fn names_the_page_an_error_is_in() {
+        assert_eq!(
+            error("page <https://a.com> { $a = h1; } page <https://b.com> { $b = h1; = }"),
+            "In page <https://b.com>: Unexpected token: expected identifier or selector, got '='"
+        );
+        assert_eq!(
+            error("page <https://a.com> { } page[wait_for=a, wait_for=b] <https://b.com> { }"),
+            "In page #2: Invalid page option wait_for: given more than once"
+        );
+    }
+
+    #[test]
+    fn names_the_expected_tokens() {
+        assert_eq!(
+            error("page <https://a.com> { = }"),
+            "In page <https://a.com>: Unexpected token: expected '}', identifier or selector, got '='"
+        );
+        assert_eq!(
+            error("page = \"a\" { }"),
+            "In page #1: Unexpected token: expected URL literal, got '='"
+        );
+    }
+
+    #[test]
+    fn parses_json_ld_extractions() {
+        let pages =
+            parse("page <https://a.com> { $price = jsonld(\"Product\", \"offers.price\"); }")
+                .unwrap();
+        let element = &pages[0].elements()[0];
+        assert_eq!(element.selector(), JSONLD_SELECTOR);
+        assert_eq!(
+            element.extraction(),
+            &Extraction::JsonLd {
+                schema_type: "Product".to_string(),
+                path: Some("offers.price".to_string()),
+            }
+        );
+        assert_eq!(
+            element.to_sexpr(),
+            "(element $price \"script[type=\\\"application/ld+json\\\"]\" single (jsonld \"Product\" \"offers.price\"))"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $x = jsonld(Product); }"),
+            "In page <https://a.com>: Invalid extraction jsonld(Product): arguments must be quoted strings"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $x = jsonld(); }"),
+            "In page <https://a.com>: Invalid extraction jsonld(): the type is missing"
+        );
+    }
+
+    /// A fresh directory under the temp dir, removed again by [`TempDir::drop`].
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("pdml-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, file: &str, source: &str) {
+            let path = self.0.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parses_directories_with_the_parser_settings() {
+        let dir = TempDir::new("parse-dir");
+        dir.write("b.pdml", "page </b> { $b = p; }");
+        dir.write("a.pdml", "page </a> { $a = p; }");
+        dir.write("notes.txt", "not a definition");
+        dir.write("nested/c.pdml", "page </c> { $c = p; }");
+        let parser = Parser::new().with_base_url("https://example.com".to_string());
+        let urls =
+            |pages: Vec<Page>| -> Vec<String> { pages.iter().map(|p| p.url.clone()).collect() };
+        assert_eq!(
+            urls(parser.parse_dir(dir.path()).unwrap()),
+            ["https://example.com/a", "https://example.com/b"]
+        );
+        assert_eq!(
+            urls(parser.parse_dir_recursive(dir.path()).unwrap()),
+            [
+                "https://example.com/a",
+                "https://example.com/b",
+                "https://example.com/c",
+            ]
+        );
+        let pages = parser.parse_dir(dir.path()).unwrap();
+        assert!(pages[0].source().unwrap().ends_with("a.pdml"));
+        assert!(matches!(
+            Parser::new().parse_dir(dir.path()),
+            Err(Error::FileError(_, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_pages_defined_in_two_files() {
+        let dir = TempDir::new("duplicate-pages");
+        dir.write("a.pdml", "page <https://a.com> { $a = p; }");
+        dir.write("b.pdml", "page <https://a.com> { $b = p; }");
+        assert!(matches!(
+            Parser::new().parse_dir(dir.path()),
+            Err(Error::DuplicatePageError(_, _, _))
+        ));
+    }
+
+    #[test]
+    fn normalizes_page_urls() {
+        let url = |source: &str| parse(source).unwrap()[0].url.clone();
+        assert_eq!(url("page < https://a.com/list > { }"), "https://a.com/list");
+        assert_eq!(url("page <HTTP://A.com> { }"), "http://a.com/");
+        assert_eq!(
+            Parser::new()
+                .with_base_url("https://a.com/shop/".to_string())
+                .parse_source("page <list> { }")
+                .unwrap()[0]
+                .url,
+            "https://a.com/shop/list"
+        );
+        assert_eq!(
+            error("page <list> { }"),
+            "In page <list>: Invalid url <list>: relative URL without a base"
+        );
+        assert_eq!(
+            error("page <https://> { }"),
+            "In page <https://>: Invalid url <https://>: empty host"
+        );
+        for url in [
+            "example.com:8080/list",
+            "localhost:3000",
+            "mailto:a@b.c",
+            "ftp://a.com",
+        ] {
+            assert!(
+                error(&format!("page <{}> {{ }}", url)).contains("unsupported scheme"),
+                "{}",
+                url
+            );
+        }
+        assert!(PageBuilder::new().url("mailto:a@b.c").build().is_err());
+    }
+
+    #[test]
+    fn parses_guards() {
+        let pages = parse("page <https://a.com> { when .in-stock { $price = .price; } }").unwrap();
+        assert_eq!(
+            pages[0].to_sexpr(),
+            "(page \"https://a.com/\"\n  (when \".in-stock\"\n    (element $price \".price\" single)))"
+        );
+        assert!(parse("page <https://a.com> { .product { when >.sale { $p = >p; } } }").is_ok());
+        assert_eq!(
+            error("page <https://a.com> { when .in-stock; }"),
+            "In page <https://a.com>: Invalid when .in-stock: it needs a block"
+        );
+        assert_eq!(
+            error("page <https://a.com> { when .in-stock*all { $p = p; } }"),
+            "In page <https://a.com>: Invalid when .in-stock: a guard takes no quantifier or transforms"
+        );
+        assert!(Parser::new()
+            .strict()
+            .parse_source("page <https://a.com> { when .a { $p = p; } }")
+            .is_ok());
+    }
+
+    #[test]
+    fn substitutes_only_defined_selector_names() {
+        let selectors = |source: &str| -> Vec<String> {
+            parse(source).unwrap()[0]
+                .walk()
+                .map(|element| element.selector().to_string())
+                .collect()
+        };
+        assert_eq!(
+            selectors("selector PRODUCT = div.product.card;\npage <https://a.com> { $title = PRODUCT h2; $price = PRODUCT .price; }"),
+            ["div.product.card h2", "div.product.card .price"]
+        );
+        assert_eq!(
+            selectors("page <https://a.com> { $title = H1; $text = DIV p; $quoted = a[title=\"PRODUCT x\"]; }"),
+            ["H1", "DIV p", "a[title=\"PRODUCT x\"]"]
+        );
+        assert_eq!(
+            selectors(
+                "selector NAV = nav;\npage <https://a.com> { $link = a[title=\"in NAV here\"]; }"
+            ),
+            ["a[title=\"in NAV here\"]"]
+        );
+        assert_eq!(
+            selectors("selector PRODUCT = div.product;\npage <https://a.com> { $a = PRODUCT>h2; $b = PRODUCT.active; $c = PRODUCT,h3; $d = :not(PRODUCT) > .PRODUCT; $e = ul + PRODUCT~p; }"),
+            ["div.product>h2", "div.product.active", "div.product,h3", ":not(div.product) > .PRODUCT", "ul + div.product~p"]
+        );
+        assert_eq!(
+            error("page <https://a.com> { $title = PRODUCT>h2; }"),
+            "In page <https://a.com>: Selector PRODUCT is used before it is defined"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $title = .card CARD; } selector CARD = .card;"),
+            "In page <https://a.com>: Selector CARD is used before it is defined"
+        );
+        assert_eq!(
+            error("page <https://a.com> { list div when CARD else 2 { $a = b; } }"),
+            "In page <https://a.com>: Selector CARD is used before it is defined"
+        );
+    }
+
+    #[test]
+    fn limits_the_bytes_of_the_input_as_given() {
+        let dir = TempDir::new("input-bytes");
+        let file = dir.0.join("cafe.pdml");
+        let mut source = b"page <https://a.com/caf".to_vec();
+        source.push(0xE9);
+        source.extend(b"> { $title = h1; }");
+        assert_eq!(source.len(), 42);
+        fs::write(&file, &source).unwrap();
+        let file = file.to_string_lossy().into_owned();
+        let parser = |max| {
+            Parser::for_file(file.clone())
+                .with_encoding("windows-1252".into())
+                .with_max_input_bytes(max)
+        };
+        let pages = parser(42).parse().unwrap();
+        assert_eq!(pages[0].url(), "https://a.com/caf%C3%A9");
+        assert!(matches!(
+            parser(41).parse(),
+            Err(Error::ReaderError(message)) if message.contains("limit of 41 bytes")
+        ));
+
+        let source = "page <https://a.com> { $title = h1; }";
+        assert!(Parser::new()
+            .with_max_input_bytes(source.len())
+            .parse_source(source)
+            .is_ok());
+        assert!(Parser::new()
+            .with_max_input_bytes(source.len() - 1)
+            .parse_source(source)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_a_file_ending_in_a_truncated_page_keyword() {
+        let dir = TempDir::new("truncated-page");
+        dir.write("pag.pdml", "pag");
+        let file = dir.0.join("pag.pdml").to_string_lossy().into_owned();
+        assert!(Parser::for_file(file).parse().is_err());
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let pages = parse(
+            "/* a /* b */ c */\n/* page <https://b.com> { $b = p; } */\npage <https://a.com> {\n  /* x */$a = a[href^=\"/*\"];/* y */\n  $b = a[href=\"/x/\"];\n}",
+        )
+        .unwrap();
+        assert_eq!(pages.len(), 1);
+        let selectors: Vec<&str> = pages[0].walk().map(|e| e.selector()).collect();
+        assert_eq!(selectors, ["a[href^=\"/*\"]", "a[href=\"/x/\"]"]);
+        for source in ["/* a /* b */", "page <https://a.com> { /* $a = p; }"] {
+            assert!(
+                error(source).contains("Block comment is not closed"),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn describes_annotated_definitions_as_markdown() {
+        let pages = parse(
+            "page <https://shop.com/lamps> = \"Lamps\" {
+                /** The name of the shop, as in its header. */
+                $shop = header h1;
+                /* Not a doc comment. */
+                $count = count(.product);
+                /**
+                 * One per lamp
+                 * on the first page.
+                 */
+                list .product {
+                    /** In EUR. */ $price.optional = .price | trim;
+                    $link = a@href;
+                }
+            }
+            page <https://shop.com/about> { }",
+        )
+        .unwrap();
+        assert_eq!(
+            Parser::to_markdown(&pages),
+            "## Lamps
+
+<https://shop.com/lamps>
+
+- `$shop`: the first match of `header h1`. The name of the shop, as in its header.
+- `$count`: the number of matches of `.product`
+- A record for every match of `.product`: One per lamp on the first page.
+  - `$price`: the first match of `.price`, through `trim` (optional). In EUR.
+  - `$link`: attribute `href` of the first match of `a`
+
+## https://shop.com/about
+
+<https://shop.com/about>
+
+Nothing is scraped from this page.
+"
+        );
+        assert_eq!(
+            pages[0].elements()[0].doc().unwrap(),
+            "The name of the shop, as in its header."
+        );
+        assert_eq!(pages[0].elements()[1].doc(), None);
+    }
+
+    #[test]
+    fn parses_attribute_extractions() {
+        let pages = parse(
+            "page <https://a.com> { $a = a@href; $b = a[title=\"x@y\"] @data-id ?? \"N/A\" *all; $c = a.x; }",
+        )
+        .unwrap();
+        let sexprs: Vec<String> = pages[0].walk().map(Element::to_sexpr).collect();
+        assert_eq!(
+            sexprs,
+            [
+                "(element $a \"a\" single (attr \"href\"))",
+                "(element $b \"a[title=\\\"x@y\\\"]\" all (attr \"data-id\" \"N/A\"))",
+                "(element $c \"a.x\" single)",
+            ]
+        );
+        assert_eq!(
+            error("page <https://a.com> { $a = a@; }"),
+            "In page <https://a.com>: Invalid extraction a@: expected an attribute name after '@'"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $a = a@href ?? N/A; }"),
+            "In page <https://a.com>: Invalid extraction a@href ?? N/A: arguments must be quoted strings"
+        );
+    }
+
+    #[test]
+    fn ends_selectors_without_semicolons() {
+        let pages = parse(
+            "page <https://a.com> {\n  $title = h1\n  $list = ul,\n    ol\n  .card { $name = >h2 }\n  $last = p }",
+        )
+        .unwrap();
+        let selectors: Vec<&str> = pages[0].walk().map(|e| e.selector()).collect();
+        assert_eq!(selectors, ["h1", "ul,     ol", ".card", ">h2", "p"]);
+    }
+
+    #[test]
+    fn rejects_conflicting_quantifiers_and_extractions() {
+        let error = |element: &str| {
+            let message = error(&format!("page <https://a.com> {{ {} }}", element));
+            message.replace("In page <https://a.com>: ", "")
+        };
+        assert_eq!(
+            error("$n = count(.review) | unique;"),
+            "Invalid extraction count(.review): count extracts a single number, unique has nothing to compare"
+        );
+        assert_eq!(
+            error("$n = count(.review) { $a = h1; }"),
+            "Invalid extraction count(.review): count extracts a number, not matches a block could scope"
+        );
+        assert_eq!(
+            error("$n = .item@* | unique_taken;"),
+            "Invalid extraction .item@*: the attributes are records, unique only compares values"
+        );
+        assert_eq!(
+            error("$n = .item*5 | unique | unique_taken;"),
+            "Invalid extraction .item: unique and unique_taken take different matches, use one of them"
+        );
+        assert_eq!(
+            error(r#"$n = jsonld("Product") { $a = h1; }"#),
+            "Invalid extraction script[type=\"application/ld+json\"]: JSON-LD items aren't elements a block could scope"
+        );
+        assert_eq!(
+            error("list .item@href { $a = h1; }"),
+            "Invalid list .item@href: a list only scopes its block, it extracts nothing"
+        );
+        let valid = parse(
+            "page <https://a.com> { $n = count(.review); $i = index(.item)*2..4 | unique; \
+             $a = .item@* *last; $l = a@href*last | unique_taken; list .item { $p = .price; } }",
+        );
+        assert_eq!(valid.unwrap()[0].elements().len(), 5);
+    }
+
+    #[test]
+    fn parses_count_extractions() {
+        let pages = parse("page <https://a.com> { $reviews = count(.review > p); }").unwrap();
+        assert_eq!(
+            pages[0].elements()[0].to_sexpr(),
+            "(element $reviews \".review > p\" single (count))"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $reviews = count(.review)*all; }"),
+            "In page <https://a.com>: Invalid extraction count(.review): count takes no quantifier, it counts every match"
+        );
+        assert_eq!(
+            error("page <https://a.com> { $reviews = count( ); }"),
+            "In page <https://a.com>: Invalid extraction count( ): the selector is missing"
+        );
+    }
+
+    #[test]
+    fn expands_url_templates() {
+        let urls = |source: &str| -> Vec<String> {
+            parse(source)
+                .unwrap()
+                .iter()
+                .map(|p| p.url.clone())
+                .collect()
+        };
+        assert_eq!(
+            urls("page <https://a.com/items?offset={0,20,40..100..20}> { }"),
+            [
+                "https://a.com/items?offset=0",
+                "https://a.com/items?offset=20",
+                "https://a.com/items?offset=40",
+                "https://a.com/items?offset=60",
+                "https://a.com/items?offset=80",
+                "https://a.com/items?offset=100",
+            ]
+        );
+        assert_eq!(
+            urls("page <https://a.com/{en,de}/{1..2}> { }"),
+            [
+                "https://a.com/en/1",
+                "https://a.com/en/2",
+                "https://a.com/de/1",
+                "https://a.com/de/2",
+            ]
+        );
+        assert_eq!(
+            urls("page <https://a.com/{1..100}/{1..100}> { }").len(),
+            10_000
+        );
+        for template in [
+            "{0..100000000}",
+            "{1..101}/{1..100}",
+            "{0..9223372036854775807}",
+        ] {
+            assert_eq!(
+                error(&format!("page <https://a.com/{}> {{ }}", template)),
+                format!(
+                    "In page <https://a.com/{0}>: Invalid url template <https://a.com/{0}>: expands into more than 10000 urls",
+                    template
+                )
+            );
+        }
+        assert!(error("page <https://a.com/{3..1}> { }").contains("empty range 3..1"));
+    }
+
+    #[test]
+    fn parses_own_text_extractions() {
+        let pages = parse("page <https://a.com> { $price = own(.price) * all | trim; }").unwrap();
+        assert_eq!(
+            pages[0].elements()[0].to_sexpr(),
+            "(element $price \".price\" all (own) (transforms trim))"
+        );
+    }
+
+    #[test]
+    fn parses_definition_list_extractions() {
+        let pages = parse("page <https://a.com> { $specs = dl(.specs); $dl = dl; }").unwrap();
+        assert_eq!(
+            pages[0].elements()[0].extraction(),
+            &Extraction::Definitions
+        );
+        assert_eq!(
+            pages[0].elements()[0].to_sexpr(),
+            "(element $specs \".specs\" single (dl))"
+        );
+        assert_eq!(pages[0].elements()[1].extraction(), &Extraction::Text);
+    }
+
+    #[test]
+    fn parses_attribute_map_extractions() {
+        let pages = parse("page <https://a.com> { $data = .product@*; }").unwrap();
+        assert_eq!(pages[0].elements()[0].extraction(), &Extraction::Attributes);
+        assert_eq!(
+            pages[0].elements()[0].to_sexpr(),
+            "(element $data \".product\" single (attrs))"
+        );
+        assert_eq!(
+            error(r#"page <https://a.com> { $data = .product@* ?? "x"; }"#),
+            r#"In page <https://a.com>: Invalid extraction .product@* ?? "x": every attribute is taken, there is no default"#
+        );
+        assert_eq!(
+            error("page <https://a.com> { $data = .product@* { $name = h2; } }"),
+            "In page <https://a.com>: Invalid extraction .product@*: the attributes are the records of the element, it takes no block"
+        );
+    }
+
+    #[test]
+    fn parses_table_extractions() {
+        let pages = parse(
+            r#"page <https://a.com> { $rows = table(#prices)*all; $by_header = table(table, "headers"); }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            pages[0].elements()[0].to_sexpr(),
+            "(element $rows \"#prices\" all (table))"
+        );
+        assert_eq!(
+            pages[0].elements()[1].to_sexpr(),
+            "(element $by_header \"table\" single (table headers))"
+        );
+        assert_eq!(
+            error(r#"page <https://a.com> { $rows = table(, "headers"); }"#),
+            r#"In page <https://a.com>: Invalid extraction table(, "headers"): the selector is missing"#
+        );
+    }
+
+    #[test]
+    fn builds_elements_with_valid_quantifiers_only() {
+        let build = |quantifier| {
+            ElementBuilder::new()
+                .selector("li")
+                .quantifier(quantifier)
+                .build()
+        };
+        assert_eq!(
+            build(Quantifier::Fixed(2)).unwrap().quantifier(),
+            &Quantifier::Fixed(2)
+        );
+        assert_eq!(
+            build(Quantifier::Fixed(0)).unwrap_err().to_string(),
+            "Error while processing the source: Invalid quantifier encountered: a count must be at least 1"
+        );
+        assert!(build(Quantifier::Range(1, 1)).is_err());
+        assert!(build(Quantifier::Skip(0)).is_ok());
+    }
+
+    #[test]
+    fn stores_heavily_indented_selectors_bare() {
+        let source = "page <https://a.com> {\n\t \t   \t$title =\t  \t h1.title  \t ;\n            \t\t\t$items =\n\t\t\t\t\t\t    li.item   *   all \t\n\t\t\t\t.product {\n\t\t\t\t\t\t\t\t    $name =   h2\t\t;\n\t\t\t\t}\n}";
+        let pages = parse(source).unwrap();
+        let elements = pages[0].elements();
+        assert_eq!(elements[0].selector(), "h1.title");
+        assert_eq!(elements[1].selector(), "li.item");
+        assert_eq!(elements[1].quantifier(), &Quantifier::All);
+        assert_eq!(elements[2].selector(), ".product");
+        let name = &elements[2].children().as_ref().unwrap()[0];
+        assert_eq!(name.selector(), "h2");
+        // The indentation isn't part of the selector token either.
+        for element in [&elements[0], &elements[1], &elements[2], name] {
+            let span = element.span().unwrap().clone();
+            assert!(!source[span].starts_with(char::is_whitespace));
+        }
+    }
+
+    #[test]
+    fn parses_element_flags() {
+        let pages = parse(
+            "page <https://a.com> { $a.optional = h1; $b.disabled.optional = h2; $c.optional.disabled = h3; $d = .optional; }",
+        )
+        .unwrap();
+        let flags: Vec<_> = pages[0]
+            .elements()
+            .iter()
+            .map(|element| (element.disabled(), element.optional()))
+            .collect();
+        assert_eq!(
+            flags,
+            [(false, true), (true, true), (true, true), (false, false)]
+        );
+        assert_eq!(pages[0].elements()[3].selector(), ".optional");
+        assert_eq!(
+            pages[0].elements()[1].to_sexpr(),
+            "(element $b \"h2\" single disabled optional)"
+        );
+    }
+
+    #[test]
+    fn parses_conditional_quantifiers() {
+        let pages = parse(
+            "selector MORE = .show-all\npage <https://a.com> { $items = .item*all when MORE else 10; }",
+        )
+        .unwrap();
+        let element = &pages[0].elements()[0];
+        assert_eq!(
+            element.quantifier(),
+            &Quantifier::When {
+                guard: ".show-all".to_string(),
+                then: Box::new(Quantifier::All),
+                otherwise: Box::new(Quantifier::Fixed(10)),
+            }
+        );
+        assert_eq!(
+            element.to_sexpr(),
+            "(element $items \".item\" (when \".show-all\" all (fixed 10)))"
+        );
+        assert!(Parser::to_markdown(&pages).contains(
+            "- `$items`: every match if `.show-all` matches, otherwise the first 10 matches of `.item`"
+        ));
+        let built = ElementBuilder::new()
+            .selector(".item")
+            .quantifier(Quantifier::When {
+                guard: ".show-all".to_string(),
+                then: Box::new(Quantifier::All),
+                otherwise: Box::new(Quantifier::Fixed(0)),
+            })
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[test]
+    fn parses_index_extractions() {
+        let pages = parse("page <https://a.com> { $at = index(.item)*2..4; }").unwrap();
+        let element = &pages[0].elements()[0];
+        assert_eq!(element.extraction(), &Extraction::Index);
+        assert_eq!(
+            element.to_sexpr(),
+            "(element $at \".item\" (range 2 4) (index))"
+        );
+    }
+
+    #[test]
+    fn parses_assertions() {
+        let pages = parse(
+            r"page <https://a.com> { assert count(.item:nth-child(2n)) >= 10; $price = .price; assert $price matches /^\$\d+(;\/)?$/
+                assert $price nonempty; }",
+        )
+        .unwrap();
+        let assertions = pages[0].assertions();
+        assert_eq!(
+            assertions[0].subject(),
+            &AssertionSubject::Count(".item:nth-child(2n)".into())
+        );
+        assert_eq!(
+            assertions[0].predicate(),
+            &Predicate::Compare(Comparison::GreaterOrEqual, 10)
+        );
+        assert_eq!(
+            assertions[1].predicate(),
+            &Predicate::Matches(r"^\$\d+(;/)?$".into())
+        );
+        assert_eq!(assertions[2].source(), "assert $price nonempty");
+        assert_eq!(pages[0].elements().len(), 1);
+        for (source, reason) in [
+            ("assert $price matches /(/", "regex parse error"),
+            (
+                "assert count(.item) nonempty",
+                "a count can only be compared",
+            ),
+            ("assert $price > ten", "expected a number to compare with"),
+            (
+                "assert .price nonempty",
+                "expected $name or count(selector)",
+            ),
+            (
+                ".item { assert $x nonempty; }",
+                "assertions go in the block of the page",
+            ),
+        ] {
+            let message = error(&format!("page <https://a.com> {{ {} }}", source));
+            assert!(message.contains(reason), "{}", message);
+        }
+    }
+
+    #[test]
+    fn parses_lists() {
+        let pages = parse(
+            "page <https://a.com> { list .category { $name = >h2; list .product*2 { $title = h3; } } }",
+        )
+        .unwrap();
+        assert_eq!(
+            pages[0].to_sexpr(),
+            "(page \"https://a.com/\"\n  (list \".category\" all\n    (element $name \">h2\" single)\n    (list \".product\" (fixed 2)\n      (element $title \"h3\" single))))"
+        );
+        assert_eq!(
+            error("page <https://a.com> { list .product; }"),
+            "In page <https://a.com>: Invalid list .product: it needs a block"
+        );
+    }
+
+    #[test]
+    fn parses_page_options() {
+        let pages = parse(r#"page[wait_for=".content > p"].disabled <https://a.com> { }"#).unwrap();
+        assert_eq!(pages[0].wait_for().unwrap(), ".content > p");
+        assert!(pages[0].disabled());
+        assert_eq!(
+            pages[0].to_sexpr(),
+            "(page \"https://a.com/\" disabled (wait_for \".content > p\"))"
+        );
+        let pages = parse("page[wait_for=main] <https://a.com> { }").unwrap();
+        assert_eq!(pages[0].wait_for().unwrap(), "main");
+        assert!(parse("page [wait_for=main] <https://a.com> { }").is_err());
+        assert_eq!(
+            error("page[wait=main] <https://a.com> { }"),
+            "In page #1: Invalid page option wait: unknown option"
+        );
+        let pages = parse("page[follow_next=3, wait_for=main] <https://a.com> { }").unwrap();
+        assert_eq!(pages[0].follow_next(), Some(3));
+        let pages = parse("page[root=html] <https://a.com> { }").unwrap();
+        assert_eq!(pages[0].root().unwrap(), "html");
+        assert_eq!(
+            pages[0].to_sexpr(),
+            "(page \"https://a.com/\" (root \"html\"))"
+        );
+        assert_eq!(
+            error("page[follow_next=0] <https://a.com> { }"),
+            "In page #1: Invalid page option follow_next: expected a number of pages above 0"
+        );
+        assert_eq!(
+            error("page[wait_for=a, wait_for=b] <https://a.com> { }"),
+            "In page #1: Invalid page option wait_for: given more than once"
+        );
+        assert_eq!(
+            error("page[wait_for] <https://a.com> { }"),
+            "In page #1: Error while processing the source: Invalid page options: expected key=value, found 'wait_for'"
+        );
+    }
+
+    #[test]
+    fn parses_the_screenshot_option() {
+        let pages =
+            parse("page[screenshot=true] <https://a.com> { } page <https://b.com> { }").unwrap();
+        assert!(pages[0].screenshot());
+        assert!(!pages[1].screenshot());
+        assert_eq!(pages[0].to_sexpr(), "(page \"https://a.com/\" screenshot)");
+        assert!(!parse("page[screenshot=false] <https://a.com> { }").unwrap()[0].screenshot());
+        assert_eq!(
+            error("page[screenshot=yes] <https://a.com> { }"),
+            "In page #1: Invalid page option screenshot: expected true or false"
+        );
+    }
+
+    #[test]
+    fn fingerprints_equivalent_definitions_alike() {
+        let source = "page <https://a.com> <https://b.com> = \"shop\" {
+            $title = h1 | trim;
+            $links = a@href*all;
+            .product { $name = h2; }
+        }";
+        let relaid = "/* the shop */ page <https://a.com>  <https://b.com> = \"shop\"
+        {
+            $title = h1  |  trim;
+            $links = a@href*all;    .product
+            {
+                $name = h2;
+            }
+        }";
+        let hash = fingerprint(&parse(source).unwrap());
+        assert_eq!(fingerprint(&parse(relaid).unwrap()), hash);
+        assert_eq!(fingerprint(&parse(source).unwrap()), hash);
+        for changed in [
+            source.replace("h2", "h3"),
+            source.replace("<https://b.com>", ""),
+            source.replace("$title", "$heading"),
+            source.replace("*all", "*2"),
+            source.replace("| trim", ""),
+            source.replace("$name = h2", "$name.optional = h2"),
+            source.replace("page <", "page.disabled <"),
+            source.replace("a@href", "a@*"),
+            source.replace("page <", "page[root=main] <"),
+            format!("merge {{ {} }}", source),
+        ] {
+            assert_ne!(fingerprint(&parse(&changed).unwrap()), hash, "{}", changed);
+        }
+    }
+
+    #[test]
+    fn parses_merge_groups() {
+        let source = "page <https://a.com/0> { $id = h1; }
+            merge \"product\" {
+                page <https://a.com/1> { $name = h1; }
+                page <https://a.com/2> { $price = .price; }
+            }
+            merge override { page <https://a.com/3> { $name = h1; } }
+            page <https://a.com/4> { $id = h1; }";
+        let pages = parse(source).unwrap();
+        assert_eq!(pages.len(), 5);
+        assert_eq!(pages[0].merge(), None);
+        assert_eq!(pages[1].merge(), pages[2].merge());
+        assert_eq!(pages[1].merge().unwrap().name().unwrap(), "product");
+        assert!(!pages[1].merge().unwrap().overrides());
+        assert_ne!(pages[3].merge(), pages[1].merge());
+        assert!(pages[3].merge().unwrap().overrides());
+        assert_eq!(pages[4].merge(), None);
+        assert_eq!(
+            pages[1].to_sexpr(),
+            "(page \"https://a.com/1\" (merge \"product\")\n  (element $name \"h1\" single))"
+        );
+        assert!(pages[3]
+            .to_sexpr()
+            .starts_with("(page \"https://a.com/3\" (merge override)"));
+
+        // Editing a page of a group keeps it in the group.
+        let at = source.find("$price = .price").unwrap() + "$price = ".len();
+        let edited = source.replacen(".price", ".cost", 1);
+        let reparsed = Parser::new()
+            .reparse(&edited, pages.clone(), at..at + 2, ".cost".len())
+            .unwrap();
+        assert_eq!(reparsed[2].elements()[0].selector(), ".cost");
+        assert_eq!(reparsed[2].merge(), pages[2].merge());
+
+        assert_eq!(
+            error("merge { merge { page <https://a.com> { } } }"),
+            "Invalid merge group: merge groups can't be nested"
+        );
+        assert_eq!(
+            error("merge \"a\" b { page <https://a.com> { } }"),
+            "Invalid merge \"a\" b: expected a name in quotes or override, got b"
+        );
+        assert!(error("merge { page <https://a.com> { }").contains("got end of file"));
+    }
+
+    #[test]
+    fn reparses_only_the_edited_page() {
+        let dir = TempDir::new("reparse");
+        let before = "page <https://a.com> { $a = h1; }\npage <https://b.com> { $b = h1; }\npage <https://c.com> { $c = h1; }\n";
+        dir.write("pages.pdml", before);
+        let path = format!("{}/pages.pdml", dir.path());
+        let previous = Parser::new().parse_path(&path).unwrap();
+        let at = before.find("$b = h1").unwrap() + "$b = ".len();
+        let after = before.replacen("$b = h1", "$b = h2.title", 1);
+        // Mark the pages around the edit to tell whether they are kept or parsed again.
+        let mut marked = previous.clone();
+        marked[0].name = Some("kept".to_string());
+        marked[2].name = Some("kept".to_string());
+        let pages = Parser::new()
+            .reparse(&after, marked, at..at + 2, "h2.title".len())
+            .unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].name(), Some(&"kept".to_string()));
+        assert_eq!(pages[1].name(), None);
+        assert_eq!(pages[1].elements()[0].selector(), "h2.title");
+        assert_eq!(pages[2].name(), Some(&"kept".to_string()));
+        let third = pages[2].span().unwrap().clone();
+        assert_eq!(&after[third], "page <https://c.com> { $c = h1; }");
+        let fresh = Parser::new().parse_source(&after).unwrap();
+        for (page, fresh) in pages.iter().zip(&fresh) {
+            assert_eq!(page.elements()[0].span(), fresh.elements()[0].span());
+        }
+        let selector = pages[1].elements()[0].span().unwrap().start;
+        assert!(after[selector..].starts_with("h2.title;"));
+        assert!(pages.iter().all(|page| page.source() == Some(&path)));
+
+        // An edit between pages parses the whole source again, keeping the source.
+        let gap = before.find('\n').unwrap();
+        let pages = Parser::new()
+            .reparse(before, previous, gap..gap + 1, 1)
+            .unwrap();
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|page| page.source() == Some(&path)));
+    }
+
+    #[test]
+    fn reparses_pages_with_the_names_defined_before_them() {
+        let before = "selector PRICE = .price;\npipeline CLEAN = trim | number;\npage <https://a.com> { $a = h1; }\npage <https://b.com> { $b = h1; }\n";
+        let previous = parse(before).unwrap();
+        let at = before.find("$b = h1").unwrap() + "$b = ".len();
+        let after = before.replacen("$b = h1", "$b = PRICE | CLEAN", 1);
+        let pages = Parser::new()
+            .reparse(&after, previous, at..at + 2, "PRICE | CLEAN".len())
+            .unwrap();
+        let fresh = parse(&after).unwrap();
+        assert_eq!(pages[1].elements()[0].selector(), ".price");
+        assert_eq!(pages[1].to_sexpr(), fresh[1].to_sexpr());
+    }
+
+    #[test]
+    fn keeps_the_pages_of_the_selected_profile() {
+        let source = "page[profile=prod] <https://shop.com> page[profile=staging] <https://staging.shop.com> { $title = h1; }
+            page <https://status.com> { $up = .up; }";
+        let urls = |parser: Parser| -> Vec<String> {
+            let pages = parser.parse_source(source).unwrap();
+            pages.iter().map(|page| page.url().clone()).collect()
+        };
+        assert_eq!(
+            urls(Parser::new().with_profile("prod".into())),
+            ["https://shop.com/", "https://status.com/"]
+        );
+        assert_eq!(
+            urls(Parser::new().with_profile("staging".into())),
+            ["https://staging.shop.com/", "https://status.com/"]
+        );
+        assert_eq!(urls(Parser::new()).len(), 3);
+        let pages = parse(source).unwrap();
+        assert_eq!(pages[0].profile().unwrap(), "prod");
+        assert_eq!(pages[0].elements()[0].selector(), "h1");
+        assert_eq!(pages[0].span(), pages[1].span());
+        assert_eq!(pages[2].profile(), None);
+        assert!(error("page[profile=prod] <https://shop.com> $title = h1;")
+            .contains("expected '=', '{' or 'page'"));
+    }
+
+    #[test]
+    fn reports_every_dangling_reference() {
+        let mut pages = parse(
+            r#"selector PRODUCT_CARD = .product
+            page <https://a.com> {
+                $title = H1;
+                $cards = PRODUCT_CARD*all { $name = h2; }
+                $price = .price;
+                .sidebar { $tags = a[title="SEE NOT_A_NAME"]*all; }
+                when .sale { $discount = .discount; }
+                list .review { $stars = .stars; }
+                assert $title nonempty;
+                assert $tags nonempty;
+                assert $discount nonempty;
+                assert $name nonempty;
+                assert $stars > 2;
+            }"#,
+        )
+        .unwrap();
+        // Parsing rejects undefined names, but pages can be changed after it.
+        pages[0].elements[2].selector = "PRICE_TAG".to_string();
+        let errors: Vec<String> = validate_references(&pages)
+            .unwrap_err()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "In page <https://a.com/>: Selector PRICE_TAG is used before it is defined",
+                "In page <https://a.com/>: $name is not scraped at the top of the page, but assert $name nonempty refers to it",
+                "In page <https://a.com/>: $stars is not scraped at the top of the page, but assert $stars > 2 refers to it",
+            ]
+        );
+        assert!(validate_references(
+            &parse("page <https://a.com> { $x = H1; assert $x nonempty; }").unwrap()
+        )
+        .is_ok());
+    }
+}
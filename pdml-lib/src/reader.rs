@@ -1,9 +1,24 @@
+use encoding_rs::Encoding;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Cursor, Seek, SeekFrom};
 use thiserror::Error;
 
+/// Input a [`CharReader`] can read from, seekable so that it can start over.
+trait Input: BufRead + Seek + Send {}
+
+impl<T: BufRead + Seek + Send> Input for T {}
+
 pub struct CharReader {
-    reader: BufReader<File>,
+    reader: Box<dyn Input>,
+    lookahead: VecDeque<u8>,
+    position: usize,
+    marked: Option<Vec<u8>>,
+    length: Option<u64>,
+    /// Length of the input before it was decoded, which is what [`CharReader::with_limit`] limits.
+    raw_length: Option<u64>,
+    decoded: bool,
+    limit: Option<usize>,
 }
 
 type Result<T> = std::result::Result<T, ReaderError>;
@@ -11,68 +26,193 @@ type Result<T> = std::result::Result<T, ReaderError>;
 impl CharReader {}
 
 impl CharReader {
-    pub fn from_file(path: &String) -> Result<Self> {
-        let file = File::open(&path)?;
-        Ok(Self {
-            reader: BufReader::new(file),
-        })
+    pub fn from_file(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let length = file.metadata()?.len();
+        Ok(Self::new(Box::new(BufReader::new(file)), Some(length)))
     }
 
-    pub fn reader(&self) -> &BufReader<File> {
-        &self.reader
+    /// Reads a file in another encoding than UTF-8, such as windows-1252, by decoding it up front.
+    pub fn from_file_with_encoding(path: &str, encoding: &'static Encoding) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let (source, _, had_errors) = encoding.decode(&bytes);
+        if had_errors {
+            return Err(ReaderError::ReadError(format!(
+                "source is not valid {}",
+                encoding.name()
+            )));
+        }
+        let mut reader = Self::from_source(&source);
+        reader.raw_length = Some(bytes.len() as u64);
+        reader.decoded = true;
+        Ok(reader)
     }
 
-    pub fn next_char(&mut self) -> Result<char> {
-        let mut buf: [u8; 1] = [0];
-        if self.reader.read(&mut buf)? == 0 {
-            return Err(ReaderError::EOF);
+    pub fn from_source(source: &str) -> Self {
+        Self::new(
+            Box::new(Cursor::new(source.as_bytes().to_vec())),
+            Some(source.len() as u64),
+        )
+    }
+
+    fn new(reader: Box<dyn Input>, length: Option<u64>) -> Self {
+        Self {
+            reader,
+            lookahead: VecDeque::new(),
+            position: 0,
+            marked: None,
+            length,
+            raw_length: length,
+            decoded: false,
+            limit: None,
         }
-        Ok(char::from(buf[0]))
     }
 
-    pub fn next_chars(&mut self, amt: usize) -> Result<Vec<char>> {
-        let mut buf: Vec<u8> = vec![0; amt];
-        let read_bytes = self.reader.read(&mut buf)?;
-        if read_bytes <= 0 {
-            return Err(ReaderError::EOF);
+    /// Fails with [`ReaderError::TooLarge`] as soon as more than `limit` bytes are read, or right
+    /// away if the input is known to be longer. Files in another encoding count their bytes before
+    /// decoding, all of which were read already.
+    pub fn with_limit(mut self, limit: usize) -> Result<Self> {
+        if self.raw_length.is_some_and(|length| length > limit as u64) {
+            return Err(ReaderError::TooLarge(limit));
         }
-        if amt != read_bytes {
-            return Err(ReaderError::ReadError(format!(
-                "Read wrong amount of bytes. Expected {}, read {}",
-                amt, read_bytes
-            )));
+        if !self.decoded {
+            self.limit = Some(limit);
         }
-        Ok(buf.iter().map(|u| char::from(*u)).collect())
+        Ok(self)
     }
 
-    pub fn peek(&mut self) -> Result<char> {
-        return match self.reader.fill_buf() {
-            Ok(buf) => {
-                return if buf.len() == 0 {
-                    Err(ReaderError::EOF)
-                } else {
-                    Ok(char::from(buf[0]))
+    /// Byte offset of the next char in the source.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes left to read, if the length of the input is known. Files in another encoding
+    /// count their decoded bytes.
+    pub fn remaining(&self) -> Option<u64> {
+        self.length
+            .map(|length| length.saturating_sub(self.position as u64))
+    }
+
+    /// Goes back to the start of the input, forgetting any lookahead and mark.
+    pub fn rewind(&mut self) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.lookahead.clear();
+        self.position = 0;
+        self.marked = None;
+        Ok(())
+    }
+
+    /// Starts remembering consumed chars so that [`CharReader::reset`] can return to this point.
+    pub fn mark(&mut self) {
+        self.marked = Some(vec![]);
+    }
+
+    /// Puts everything consumed since the last [`CharReader::mark`] back in front of the input.
+    pub fn reset(&mut self) {
+        if let Some(consumed) = self.marked.take() {
+            self.position -= consumed.len();
+            for byte in consumed.into_iter().rev() {
+                self.lookahead.push_front(byte);
+            }
+        }
+    }
+
+    fn consume(&mut self, byte: u8) {
+        self.position += 1;
+        if let Some(consumed) = self.marked.as_mut() {
+            consumed.push(byte);
+        }
+    }
+
+    /// Moves bytes from the underlying reader into the lookahead until it holds `amt` bytes or the
+    /// input is exhausted. Unlike `fill_buf` alone this also crosses the end of the current buffer.
+    fn fill_lookahead(&mut self, amt: usize) -> Result<()> {
+        while self.lookahead.len() < amt {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let take = buf.len().min(amt - self.lookahead.len());
+            self.lookahead.extend(&buf[..take]);
+            self.reader.consume(take);
+            if let Some(limit) = self.limit {
+                if self.position + self.lookahead.len() > limit {
+                    return Err(ReaderError::TooLarge(limit));
                 }
             }
-            Err(_) => Err(ReaderError::ReadError("Could not peek".to_string())),
+        }
+        Ok(())
+    }
+
+    /// Decodes the UTF-8 char starting `offset` bytes into the lookahead, returning it with its
+    /// length in bytes, or `None` at the end of the input.
+    fn decode_at(&mut self, offset: usize) -> Result<Option<(char, usize)>> {
+        self.fill_lookahead(offset + 1)?;
+        let len = match self.lookahead.get(offset) {
+            Some(first) => match first.leading_ones() {
+                0 => 1,
+                ones @ 2..=4 => ones as usize,
+                _ => return Err(self.invalid_utf8(offset)),
+            },
+            None => return Ok(None),
         };
+        self.fill_lookahead(offset + len)?;
+        let bytes: Vec<u8> = self.lookahead.range(offset..).take(len).copied().collect();
+        match std::str::from_utf8(&bytes) {
+            Ok(decoded) if bytes.len() == len => Ok(decoded.chars().next().map(|c| (c, len))),
+            _ => Err(self.invalid_utf8(offset)),
+        }
+    }
+
+    fn invalid_utf8(&self, offset: usize) -> ReaderError {
+        ReaderError::ReadError(format!("invalid UTF-8 at byte {}", self.position + offset))
+    }
+
+    pub fn next_char(&mut self) -> Result<char> {
+        match self.decode_at(0)? {
+            Some((c, len)) => {
+                for _ in 0..len {
+                    if let Some(byte) = self.lookahead.pop_front() {
+                        self.consume(byte);
+                    }
+                }
+                Ok(c)
+            }
+            None => Err(ReaderError::Eof),
+        }
     }
 
+    pub fn peek(&mut self) -> Result<char> {
+        match self.decode_at(0)? {
+            Some((c, _)) => Ok(c),
+            None => Err(ReaderError::Eof),
+        }
+    }
+
+    /// Peeks at up to `amt` chars. Near the end of the input fewer, or none, are returned.
     pub fn peek_many(&mut self, amt: usize) -> Result<Vec<char>> {
-        return match self.reader.fill_buf() {
-            Ok(buf) => {
-                return if buf.len() == 0 {
-                    Err(ReaderError::EOF)
-                } else {
-                    Ok(buf.iter().take(amt).map(|u| char::from(*u)).collect())
+        let mut chars: Vec<char> = vec![];
+        let mut offset = 0;
+        while chars.len() < amt {
+            match self.decode_at(offset)? {
+                Some((c, len)) => {
+                    chars.push(c);
+                    offset += len;
                 }
+                None => break,
             }
-            Err(_) => Err(ReaderError::ReadError("Could not peek".to_string())),
-        };
+        }
+        Ok(chars)
     }
 
+    /// Skips `amt` chars. Chars are always peeked before they are skipped, so they are already
+    /// decoded without errors.
     pub fn advance(&mut self, amt: usize) {
-        self.reader.consume(amt)
+        for _ in 0..amt {
+            if self.next_char().is_err() {
+                break;
+            }
+        }
     }
 }
 
@@ -85,7 +225,10 @@ pub enum ReaderError {
     ReadError(String),
 
     #[error("Reader reached eof")]
-    EOF,
+    Eof,
+
+    #[error("Input is larger than the limit of {} bytes", .0)]
+    TooLarge(usize),
 }
 
 impl From<std::io::Error> for ReaderError {
@@ -93,3 +236,17 @@ impl From<std::io::Error> for ReaderError {
         ReaderError::IoError(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peeks_what_is_left_near_the_end() {
+        let mut reader = CharReader::from_source("pag");
+        assert_eq!(reader.peek_many(4).unwrap(), ['p', 'a', 'g']);
+        reader.advance(3);
+        assert!(reader.peek_many(4).unwrap().is_empty());
+        assert!(matches!(reader.peek(), Err(ReaderError::Eof)));
+    }
+}
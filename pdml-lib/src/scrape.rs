@@ -1,39 +1,4854 @@
+use crate::parser::{
+    Assertion, AssertionSubject, Element, Extraction, InvalidSelector, MergeGroup, Page, Predicate,
+    Quantifier, Transform,
+};
 use crate::{Error, Parser};
 use async_trait::async_trait;
+use ego_tree::{NodeId, NodeMut, NodeRef};
+use futures_util::future::{self, Either};
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use regex::Regex;
+use reqwest::redirect::Policy;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+#[cfg(feature = "headless")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+use url::Url;
 
 type Result<T> = std::result::Result<T, Error>;
 
+pub use pdml_macros::ScrapeBindable;
+
 pub trait ScrapeBindable {
     fn bind(page: &ScrapedPage) -> Self;
 }
 
-#[derive(Debug)]
+/// Binds one record of a `list` block, so that a page binds to a `Vec` of them.
+pub trait ScrapeRecordBindable {
+    fn bind(record: &ScrapedRecord) -> Self;
+}
+
+impl<T: ScrapeRecordBindable> ScrapeBindable for Vec<T> {
+    fn bind(page: &ScrapedPage) -> Self {
+        page.records.iter().map(T::bind).collect()
+    }
+}
+
+/// Binds a field of a `#[derive(ScrapeBindable)]` struct to the scraped element of its name, `None`
+/// if nothing was scraped under it. A `String` is the first value, or empty without one, an
+/// `Option` is `None` then and a `Vec` has every value. A struct binds to the first record of its
+/// element, from a nested block like `$address { $street = .street; }`, and a `Vec` of structs to
+/// every record.
+pub trait BindField {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self;
+}
+
+impl BindField for String {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        Option::bind_field(element).unwrap_or_default()
+    }
+}
+
+impl BindField for Option<String> {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        element.and_then(|element| element.values.first().cloned())
+    }
+}
+
+impl BindField for Vec<String> {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        element.map(|element| element.values.clone()).unwrap_or_default()
+    }
+}
+
+impl<T: ScrapeRecordBindable> BindField for T {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        match element.and_then(|element| element.records.first()) {
+            Some(record) => T::bind(record),
+            None => T::bind(&ScrapedRecord::default()),
+        }
+    }
+}
+
+impl<T: ScrapeRecordBindable> BindField for Option<T> {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        element
+            .and_then(|element| element.records.first())
+            .map(T::bind)
+    }
+}
+
+impl<T: ScrapeRecordBindable> BindField for Vec<T> {
+    fn bind_field(element: Option<&ScrapedElement>) -> Self {
+        let records = element.map(|element| element.records.iter().map(T::bind));
+        records.into_iter().flatten().collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ScrapedPage {
     url: String,
     name: Option<String>,
     elements: Vec<ScrapedElement>,
+    records: Vec<ScrapedRecord>,
+    fetched_at: SystemTime,
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+    final_url: Option<String>,
+    mirror: Option<String>,
+    preview: Option<String>,
+    merge: Option<MergeGroup>,
+    #[cfg(feature = "headless")]
+    screenshot: Option<PathBuf>,
+}
+
+impl ScrapedPage {
+    pub fn new(url: String, name: Option<String>, elements: Vec<ScrapedElement>) -> Self {
+        Self {
+            url,
+            name,
+            elements,
+            records: vec![],
+            fetched_at: SystemTime::now(),
+            etag: None,
+            last_modified: None,
+            final_url: None,
+            mirror: None,
+            preview: None,
+            merge: None,
+            #[cfg(feature = "headless")]
+            screenshot: None,
+        }
+    }
+
+    pub fn with_records(mut self, records: Vec<ScrapedRecord>) -> Self {
+        self.records = records;
+        self
+    }
+
+    /// Sets when the page was fetched and the `ETag` and `Last-Modified` of the response.
+    pub fn with_freshness(
+        mut self,
+        fetched_at: SystemTime,
+        etag: Option<String>,
+        last_modified: Option<SystemTime>,
+    ) -> Self {
+        self.fetched_at = fetched_at;
+        self.etag = etag;
+        self.last_modified = last_modified;
+        self
+    }
+
+    /// When the response of the page arrived, or when it was extracted if it wasn't fetched.
+    pub fn fetched_at(&self) -> SystemTime {
+        self.fetched_at
+    }
+
+    /// The `ETag` header of the response, if it had one.
+    pub fn etag(&self) -> Option<&String> {
+        self.etag.as_ref()
+    }
+
+    /// The `Last-Modified` header of the response, if it had a valid one.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
+    /// Sets the url the page was redirected to.
+    pub fn with_final_url(mut self, final_url: String) -> Self {
+        self.final_url = Some(final_url);
+        self
+    }
+
+    /// Sets the mirror the page was fetched from.
+    pub fn with_mirror(mut self, mirror: String) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// The mirror the page was fetched from because its url failed, if it was.
+    pub fn mirror(&self) -> Option<&String> {
+        self.mirror.as_ref()
+    }
+
+    /// The start of the fetched document, see [`ScrapeOptions::preview_chars`].
+    pub fn preview(&self) -> Option<&String> {
+        self.preview.as_ref()
+    }
+
+    /// Where the screenshot of a `page[screenshot=true]` was saved, see
+    /// [`ScrapeOptions::screenshot_dir`].
+    #[cfg(feature = "headless")]
+    pub fn screenshot(&self) -> Option<&PathBuf> {
+        self.screenshot.as_ref()
+    }
+
+    /// The url the page was fetched from after following redirects, if it isn't [`Self::url`].
+    /// A page that leads to a login wall shows up here.
+    pub fn final_url(&self) -> Option<&String> {
+        self.final_url.as_ref()
+    }
+
+    pub fn url(&self) -> &String {
+        &self.url
+    }
+
+    pub fn name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    pub fn elements(&self) -> &Vec<ScrapedElement> {
+        &self.elements
+    }
+
+    pub fn element(&self, name: &str) -> Option<&ScrapedElement> {
+        self.elements.iter().find(|e| e.name == name)
+    }
+
+    /// The records of the `list` blocks at the top of the page, one per match in document order.
+    pub fn records(&self) -> &Vec<ScrapedRecord> {
+        &self.records
+    }
+
+    /// Adds the elements and records of `other`, another page of `group`, see [`MergeGroup`].
+    fn merge_page(&mut self, other: ScrapedPage, group: &MergeGroup) -> Result<()> {
+        for element in other.elements {
+            match self.elements.iter().position(|e| e.name == element.name) {
+                Some(existing) if group.overrides() => self.elements[existing] = element,
+                Some(_) => {
+                    let group = group.name().cloned().unwrap_or_else(|| self.url.clone());
+                    return Err(Error::MergeConflictError(element.name, group));
+                }
+                None => self.elements.push(element),
+            }
+        }
+        self.records.extend(other.records);
+        Ok(())
+    }
+
+    /// Compares the elements of two scrapes of a page by name, `self` being the older one.
+    /// Elements of `self` come first, in order, followed by the ones only `other` has. Only values
+    /// are compared, not the records of blocks.
+    pub fn diff(&self, other: &ScrapedPage) -> Vec<FieldChange> {
+        let mut changes: Vec<FieldChange> = vec![];
+        for element in &self.elements {
+            match other.element(&element.name) {
+                Some(new) if new.values != element.values => changes.push(FieldChange::Changed(
+                    element.name.clone(),
+                    element.values.clone(),
+                    new.values.clone(),
+                )),
+                Some(_) => {}
+                None => changes.push(FieldChange::Removed(
+                    element.name.clone(),
+                    element.values.clone(),
+                )),
+            }
+        }
+        for element in &other.elements {
+            if self.element(&element.name).is_none() {
+                changes.push(FieldChange::Added(
+                    element.name.clone(),
+                    element.values.clone(),
+                ));
+            }
+        }
+        changes
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ScrapedElement {
     name: String,
     values: Vec<String>,
+    records: Vec<ScrapedRecord>,
+    locations: Vec<MatchLocation>,
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    pairs: Vec<(String, String)>,
+    data: Vec<Option<DataUri>>,
 }
 
-#[async_trait]
-pub trait ParserExt {
-    async fn scrape<T>(&mut self) -> Result<Vec<T>>
+impl ScrapedElement {
+    pub fn new(name: String, values: Vec<String>) -> Self {
+        Self {
+            name,
+            values,
+            records: vec![],
+            locations: vec![],
+            headers: vec![],
+            rows: vec![],
+            pairs: vec![],
+            data: vec![],
+        }
+    }
+
+    pub fn with_records(mut self, records: Vec<ScrapedRecord>) -> Self {
+        self.records = records;
+        self
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn values(&self) -> &Vec<String> {
+        &self.values
+    }
+
+    /// The elements of the block of the element, one record per match. Empty for elements without
+    /// a block.
+    pub fn records(&self) -> &Vec<ScrapedRecord> {
+        &self.records
+    }
+
+    /// Where the matches of the element are in the fetched HTML, in match order. Only filled by
+    /// [`extract_page_with_locations`], and matches the HTML parser inserted itself have none.
+    pub fn locations(&self) -> &Vec<MatchLocation> {
+        &self.locations
+    }
+
+    /// The rows of the tables of a `table(...)` extraction, each the text of its cells, in order.
+    /// Cells spanning several columns or rows count once. Without `"headers"` the first row is
+    /// included.
+    pub fn rows(&self) -> &Vec<Vec<String>> {
+        &self.rows
+    }
+
+    /// The rows of a `table(..., "headers")` extraction by the column names of the first row.
+    /// Cells without a header are left out, as are the columns a short row has no cells for.
+    pub fn headed_rows(&self) -> Vec<HashMap<String, String>> {
+        self.rows
+            .iter()
+            .map(|row| {
+                self.headers
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().cloned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The attributes of every match of a `@*` extraction by name, the records of the element.
+    pub fn attributes(&self) -> Vec<HashMap<String, String>> {
+        self.records
+            .iter()
+            .map(|record| {
+                let attributes = record.elements.iter();
+                attributes
+                    .map(|attribute| (attribute.name.clone(), attribute.values.concat()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The term and description pairs of the lists of a `dl(...)` extraction, in order. A term
+    /// with several descriptions has a pair for each, and a term without any a pair with an empty
+    /// description. Descriptions before the first term are left out.
+    pub fn pairs(&self) -> &Vec<(String, String)> {
+        &self.pairs
+    }
+
+    /// The decoded payloads of an element with `| data_uri`, one per value, `None` for values that
+    /// aren't `data:` uris. Empty for elements without the transform.
+    pub fn data(&self) -> &Vec<Option<DataUri>> {
+        &self.data
+    }
+
+    /// The descriptions of a `dl(...)` extraction by their terms. The descriptions of a term with
+    /// several are joined by newlines.
+    pub fn definitions(&self) -> HashMap<String, String> {
+        let mut definitions: HashMap<String, String> = HashMap::new();
+        for (term, description) in &self.pairs {
+            match definitions.get_mut(term) {
+                Some(joined) => {
+                    joined.push('\n');
+                    joined.push_str(description);
+                }
+                None => {
+                    definitions.insert(term.clone(), description.clone());
+                }
+            }
+        }
+        definitions
+    }
+
+    /// Adds the values and records of another scrape of the same element, as when an unnamed parent
+    /// matches several times.
+    fn merge(&mut self, other: ScrapedElement) {
+        self.values.extend(other.values);
+        self.records.extend(other.records);
+        self.locations.extend(other.locations);
+        if self.headers.is_empty() {
+            self.headers = other.headers;
+        }
+        self.rows.extend(other.rows);
+        self.pairs.extend(other.pairs);
+    }
+}
+
+/// The byte range of the start tag of a match in the fetched HTML, `<a href="/x">` of a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchLocation {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The payload of a `data:` uri decoded by `| data_uri`, such as the bytes of an inline image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUri {
+    media_type: String,
+    bytes: Vec<u8>,
+}
+
+impl DataUri {
+    /// Decodes `value` if it is a `data:` uri, base64 or percent-encoded. Fails on a `data:` uri
+    /// without a `,` before its payload or with a payload that isn't valid base64.
+    pub fn decode(value: &str) -> Result<Option<Self>> {
+        let value = value.trim();
+        let Some(uri) = value
+            .get(..5)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+            .map(|_| &value[5..])
+        else {
+            return Ok(None);
+        };
+        let invalid = |reason: &str| {
+            let shown: String = value.chars().take(40).collect();
+            Error::ScraperError(format!("Invalid data uri {}...: {}", shown, reason))
+        };
+        let (header, payload) = uri.split_once(',').ok_or_else(|| invalid("no payload"))?;
+        let (media_type, base64) = match header.strip_suffix(";base64") {
+            Some(media_type) => (media_type, true),
+            None => (header, false),
+        };
+        let payload = percent_encoding::percent_decode_str(payload).collect::<Vec<u8>>();
+        let bytes = match base64 {
+            true => {
+                use base64::Engine;
+                let payload: Vec<u8> =
+                    payload.into_iter().filter(|b| !b.is_ascii_whitespace()).collect();
+                base64::engine::general_purpose::STANDARD
+                    .decode(payload)
+                    .map_err(|err| invalid(&err.to_string()))?
+            }
+            false => payload,
+        };
+        let media_type = match media_type.trim() {
+            "" => "text/plain;charset=US-ASCII",
+            media_type => media_type,
+        };
+        Ok(Some(DataUri {
+            media_type: media_type.to_string(),
+            bytes,
+        }))
+    }
+
+    /// The media type of the payload with its parameters, `text/plain;charset=US-ASCII` if the uri
+    /// has none.
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// The elements scraped inside one match of an element with a block or of a `list`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScrapedRecord {
+    elements: Vec<ScrapedElement>,
+    records: Vec<ScrapedRecord>,
+}
+
+impl ScrapedRecord {
+    pub fn new(elements: Vec<ScrapedElement>) -> Self {
+        Self {
+            elements,
+            records: vec![],
+        }
+    }
+
+    pub fn with_records(mut self, records: Vec<ScrapedRecord>) -> Self {
+        self.records = records;
+        self
+    }
+
+    pub fn elements(&self) -> &Vec<ScrapedElement> {
+        &self.elements
+    }
+
+    /// The records of the `list` blocks nested in this one.
+    pub fn records(&self) -> &Vec<ScrapedRecord> {
+        &self.records
+    }
+
+    pub fn element(&self, name: &str) -> Option<&ScrapedElement> {
+        self.elements.iter().find(|e| e.name == name)
+    }
+}
+
+/// A difference between two scrapes of a page, see [`ScrapedPage::diff`].
+#[derive(Debug, PartialEq)]
+pub enum FieldChange {
+    /// An element only the newer scrape has, with its values.
+    Added(String, Vec<String>),
+    /// An element only the older scrape has, with its values.
+    Removed(String, Vec<String>),
+    /// An element whose values differ, with the old and the new values.
+    Changed(String, Vec<String>, Vec<String>),
+}
+
+/// Binds scraped pages to different types depending on their url. Patterns are globs where `*`
+/// matches any run of chars, and the first route whose pattern matches the url wins.
+pub struct ScrapeRouter<R> {
+    routes: Vec<(String, Binder<R>)>,
+}
+
+type Binder<R> = Box<dyn Fn(&ScrapedPage) -> R + Send + Sync>;
+
+impl<R> ScrapeRouter<R> {
+    pub fn new() -> Self {
+        Self { routes: vec![] }
+    }
+
+    /// Binds pages matching `pattern` to `T` and wraps them with `wrap`, usually an enum variant.
+    pub fn route<T, F>(mut self, pattern: &str, wrap: F) -> Self
     where
-        T: ScrapeBindable;
+        T: ScrapeBindable,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        self.routes.push((
+            pattern.to_string(),
+            Box::new(move |page| wrap(T::bind(page))),
+        ));
+        self
+    }
+
+    /// Binds `page` with the first matching route, or returns `None` if no route matches.
+    pub fn dispatch(&self, page: &ScrapedPage) -> Option<R> {
+        self.routes
+            .iter()
+            .find(|(pattern, _)| glob_matches(pattern, &page.url))
+            .map(|(_, bind)| bind(page))
+    }
+}
+
+impl<R> Default for ScrapeRouter<R> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Settings for the HTTP side of scraping.
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeOptions {
+    /// How long establishing a connection may take. A dead host fails after this.
+    pub connect_timeout: Option<Duration>,
+    /// How long a whole request may take, connecting and downloading the body included.
+    pub timeout: Option<Duration>,
+    /// How many redirects a request follows, 10 if unset. With `Some(0)` redirects are not
+    /// followed and a page that redirects fails, naming where it redirects to. Where a followed
+    /// redirect leads is [`ScrapedPage::final_url`].
+    pub max_redirects: Option<usize>,
+    /// How often a request is retried after a timeout, a failed connection or a 429, 502, 503 or
+    /// 504 response. 0 if unset.
+    pub retries: usize,
+    /// How long to wait before the first retry, doubled for every further one. 1 second if unset.
+    /// A `Retry-After` header of the response is waited for instead.
+    pub retry_backoff: Option<Duration>,
+    /// The longest wait before a retry, whatever the backoff or `Retry-After` say. 60 seconds if
+    /// unset.
+    pub max_retry_delay: Option<Duration>,
+    /// How long the [`ScrapeOptions::headless`] backend waits for the selector of a
+    /// `page[wait_for=...]` to match in the rendered page, 10 seconds if unset. The page fails if
+    /// the selector still doesn't match after that.
+    pub wait_timeout: Option<Duration>,
+    /// How often the [`ScrapeOptions::headless`] backend checks whether the selector of a
+    /// `page[wait_for=...]` matches, every half a second if unset.
+    pub wait_interval: Option<Duration>,
+    /// What goes between the texts of a match, as in `<p>Hello<b>World</b></p>`, unless one of them
+    /// has whitespace already. A single space if unset, `<br>` always being a newline.
+    pub text_separator: Option<String>,
+    /// Query parameters added to every request url, such as `("lang", "en")`. A parameter the url
+    /// already has, from its template for example, is kept and the extra one is left out. They are
+    /// only sent, the url of a [`ScrapedPage`] is the one of its definition.
+    pub extra_query: Vec<(String, String)>,
+    /// How many pages a [`Scraper`] scrapes at most, next pages included. Once they are scraped,
+    /// scraping stops and the pages scraped so far are returned. Unlimited if unset.
+    pub max_total_pages: Option<usize>,
+    /// Permits shared by every scraper given these options, or a clone of them, so that scrapes
+    /// of several definitions together make no more requests at once than it has permits. A
+    /// permit is held for each request until its body is read, not while waiting to retry.
+    pub request_permits: Option<Arc<Semaphore>>,
+    /// Keeps this many chars from the start of every fetched document as
+    /// [`ScrapedPage::preview`], to see what came back when selectors match nothing, such as a
+    /// captcha page. No previews if unset.
+    pub preview_chars: Option<usize>,
+    /// Fails the scrape at the first named element that scrapes nothing, unless it is marked
+    /// `$name.optional`, naming the element and the page. Meant for checking in CI that the
+    /// selectors of a definition still match. Unnamed elements, lists and guards aren't checked.
+    pub fail_on_empty: bool,
+    /// Lets named `*all` elements match nothing, scraping no values. Otherwise such an element
+    /// fails the scrape like with `fail_on_empty`, unless it is marked `$name.optional`, since an
+    /// `*all` selector matching nothing is almost always broken. Elements taking a single match
+    /// may always come back empty.
+    pub allow_empty_all: bool,
+    /// Extracts from the content of `<template>` and `<noscript>` tags too, which isn't rendered
+    /// and so isn't part of the page otherwise. It is scoped as if it were written in place of
+    /// the tag, with the tag as its parent: `noscript > img` matches the images of a `<noscript>`
+    /// and a `template { ... }` block scrapes the content of templates. Without it, the markup of
+    /// a `<noscript>` is only its text.
+    pub template_content: bool,
+    /// Counts the pages of every scraper given these options, or a clone of them, so that the
+    /// [`ScrapeSummary`] of scrapes that take the scraper, such as streams, can be read after.
+    /// Every scraper counts on its own if unset, see [`Scraper::summary`].
+    pub counter: Option<Arc<ScrapeCounter>>,
+    /// Keeps the `@href` and `@src` values of elements and `@*` records as the page has them.
+    /// Otherwise they are resolved against the url the page was fetched from after redirects, so
+    /// `/page/2` and `//cdn.com/a.png` come back absolute. Values that are absolute already or
+    /// aren't urls, `| raw` values and the `?? "default"` of a missing attribute are kept either
+    /// way.
+    pub relative_links: bool,
+    /// The headless browser taking the screenshots of `page[screenshot=true]` pages and rendering
+    /// the `page[wait_for=...]` ones. Without one these pages are scraped like the others, from the
+    /// HTML as it is fetched and without a screenshot.
+    #[cfg(feature = "headless")]
+    pub headless: Option<Arc<dyn HeadlessBackend>>,
+    /// Where screenshots are saved, `screenshots` in the current directory if unset, which is
+    /// created if it is missing. Each is a PNG named after the url the page was fetched from
+    /// after redirects, `https://a.com/shop?p=2` as `a.com_shop_p_2.png`, replacing an older one.
+    #[cfg(feature = "headless")]
+    pub screenshot_dir: Option<PathBuf>,
+}
+
+/// A headless browser rendering pages for [`ScrapeOptions::headless`], such as a Chrome driven
+/// over the DevTools protocol.
+#[cfg(feature = "headless")]
 #[async_trait]
-impl ParserExt for Parser {
-    async fn scrape<T>(&mut self) -> Result<Vec<T>>
+pub trait HeadlessBackend: std::fmt::Debug + Send + Sync {
+    /// Loads `url` and takes a PNG screenshot of the rendered page.
+    async fn screenshot(&self, url: &str) -> Result<Vec<u8>>;
+
+    /// Loads `url` and checks every `interval` whether `selector` matches in the rendered page,
+    /// for at most `timeout`. The HTML of the rendered page once it matches, or `None` if it didn't
+    /// in time.
+    async fn render(
+        &self,
+        url: &str,
+        selector: &str,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<Option<String>>;
+}
+
+/// How many pages a scrape planned, fetched and left out, see [`Scraper::summary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrapeSummary {
+    /// The pages handed to the scraper, not counting the next pages of a `page[follow_next=...]`.
+    pub planned: usize,
+    /// The pages requested, next pages included, whether they were scraped then or not.
+    pub fetched: usize,
+    /// The fetched pages that were scraped.
+    pub succeeded: usize,
+    /// The fetched pages that failed, because of their response or what was extracted from it.
+    pub failed: usize,
+    /// The pages left out because they are `page.disabled` or [`ScrapeOptions::max_total_pages`]
+    /// were scraped already. Planned pages after one that failed a scrape stopping at the first
+    /// failure aren't counted at all.
+    pub skipped: usize,
+}
+
+/// Counts pages into a [`ScrapeSummary`] while scraping, see [`ScrapeOptions::counter`].
+#[derive(Debug, Default)]
+pub struct ScrapeCounter {
+    planned: AtomicUsize,
+    fetched: AtomicUsize,
+    succeeded: AtomicUsize,
+    failed: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl ScrapeCounter {
+    /// What was counted so far.
+    pub fn summary(&self) -> ScrapeSummary {
+        ScrapeSummary {
+            planned: self.planned.load(Ordering::SeqCst),
+            fetched: self.fetched.load(Ordering::SeqCst),
+            succeeded: self.succeeded.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+            skipped: self.skipped.load(Ordering::SeqCst),
+        }
+    }
+
+    fn count(counter: &AtomicUsize, pages: usize) {
+        counter.fetch_add(pages, Ordering::SeqCst);
+    }
+}
+
+impl ScrapeOptions {
+    /// Builds the HTTP client for these options. Fails if the connect timeout exceeds the total.
+    pub fn client(&self) -> Result<reqwest::Client> {
+        if let (Some(connect), Some(total)) = (self.connect_timeout, self.timeout) {
+            if connect > total {
+                return Err(Error::ScraperError(format!(
+                    "connect timeout {:?} exceeds the total timeout {:?}",
+                    connect, total
+                )));
+            }
+        }
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect) = self.connect_timeout {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(total) = self.timeout {
+            builder = builder.timeout(total);
+        }
+        match self.max_redirects {
+            Some(0) => builder = builder.redirect(Policy::none()),
+            Some(max) => builder = builder.redirect(Policy::limited(max)),
+            None => {}
+        }
+        Ok(builder.build()?)
+    }
+
+    /// How long to wait before retry number `attempt`, counting from 0, if the server asked for
+    /// `retry_after`.
+    fn retry_delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        let backoff = self.retry_backoff.unwrap_or(Duration::from_secs(1));
+        let delay = retry_after
+            .unwrap_or_else(|| backoff.saturating_mul(2u32.saturating_pow(attempt as u32)));
+        delay.min(self.max_retry_delay.unwrap_or(Duration::from_secs(60)))
+    }
+
+    /// Appends the extra query parameters to `url`, leaving out those whose name it already has.
+    pub fn request_url(&self, url: &str) -> Result<Url> {
+        let mut request_url = Url::parse(url)
+            .map_err(|err| Error::InvalidUrlError(url.to_string(), err.to_string()))?;
+        let existing: Vec<String> = request_url
+            .query_pairs()
+            .map(|(name, _)| name.into_owned())
+            .collect();
+        let extra: Vec<&(String, String)> = self
+            .extra_query
+            .iter()
+            .filter(|(name, _)| !existing.contains(name))
+            .collect();
+        if !extra.is_empty() {
+            request_url.query_pairs_mut().extend_pairs(extra);
+        }
+        Ok(request_url)
+    }
+}
+
+/// Fetches pages and extracts their elements, sharing one HTTP client and the compiled selectors.
+pub struct Scraper {
+    client: reqwest::Client,
+    options: ScrapeOptions,
+    selectors: SelectorCache,
+    cache: Option<Mutex<HashMap<String, CachedPage>>>,
+    scraped_pages: AtomicUsize,
+    counter: Arc<ScrapeCounter>,
+}
+
+/// A page scraped before, with the url of its next page if it follows next pages.
+#[derive(Clone)]
+struct CachedPage {
+    page: ScrapedPage,
+    next: Option<String>,
+}
+
+impl Scraper {
+    pub fn new(options: ScrapeOptions) -> Result<Self> {
+        Ok(Self {
+            client: options.client()?,
+            counter: options.counter.clone().unwrap_or_default(),
+            options,
+            selectors: SelectorCache::new(SELECTOR_CACHE_SIZE),
+            cache: None,
+            scraped_pages: AtomicUsize::new(0),
+        })
+    }
+
+    /// How many pages this scraper planned, fetched and left out so far, along with the other
+    /// scrapers sharing its [`ScrapeOptions::counter`].
+    pub fn summary(&self) -> ScrapeSummary {
+        self.counter.summary()
+    }
+
+    fn plan(&self, pages: usize) {
+        ScrapeCounter::count(&self.counter.planned, pages);
+    }
+
+    /// Counts a page against [`ScrapeOptions::max_total_pages`], or `false` if there is no page
+    /// left to scrape, counting it as skipped.
+    fn take_page(&self) -> bool {
+        let Some(max) = self.options.max_total_pages else {
+            return true;
+        };
+        let taken = self
+            .scraped_pages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |scraped| {
+                (scraped < max).then_some(scraped + 1)
+            })
+            .is_ok();
+        if !taken {
+            ScrapeCounter::count(&self.counter.skipped, 1);
+        }
+        taken
+    }
+
+    /// Keeps the last scrape of every url, starting with `pages`, scraped earlier perhaps by
+    /// another run. A page with an `ETag` or `Last-Modified` is then fetched with `If-None-Match`
+    /// or `If-Modified-Since`, and on a `304 Not Modified` the cached scrape is taken again
+    /// without extracting anything. Without a cache, the default, requests are unconditional.
+    pub fn with_page_cache(mut self, pages: Vec<ScrapedPage>) -> Self {
+        let pages = pages.into_iter().map(|page| {
+            let cached = CachedPage { page, next: None };
+            (cached.page.url.clone(), cached)
+        });
+        self.cache = Some(Mutex::new(pages.collect()));
+        self
+    }
+
+    /// The pages in the cache, see [`Scraper::with_page_cache`].
+    pub fn cached_pages(&self) -> Vec<ScrapedPage> {
+        let Some(cache) = &self.cache else {
+            return vec![];
+        };
+        let cache = cache.lock().unwrap_or_else(|err| err.into_inner());
+        cache.values().map(|cached| cached.page.clone()).collect()
+    }
+
+    fn cached(&self, url: &str) -> Option<CachedPage> {
+        let cache = self.cache.as_ref()?;
+        let cache = cache.lock().unwrap_or_else(|err| err.into_inner());
+        cache.get(url).cloned()
+    }
+
+    fn cache(&self, page: &ScrapedPage, next: Option<&Page>) {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap_or_else(|err| err.into_inner());
+            let cached = CachedPage {
+                page: page.clone(),
+                next: next.map(|next| next.url().clone()),
+            };
+            cache.insert(page.url.clone(), cached);
+        }
+    }
+
+    pub fn options(&self) -> &ScrapeOptions {
+        &self.options
+    }
+
+    /// The selectors compiled by this scraper so far.
+    pub fn selectors(&self) -> &SelectorCache {
+        &self.selectors
+    }
+
+    /// Scrapes `pages` one after another, in order, leaving out disabled ones. The pages of a
+    /// `merge { ... }` group are merged into one where the first of them is, see [`MergeGroup`].
+    pub async fn scrape_pages(&self, pages: &[Page]) -> Result<Vec<ScrapedPage>> {
+        self.plan(pages.len());
+        let mut scraped: Vec<ScrapedPage> = vec![];
+        for page in pages {
+            self.scrape_following(page, &mut scraped).await?;
+        }
+        merge_pages(scraped)
+    }
+
+    /// Scrapes `page` into `scraped`, followed by the next pages of a `page[follow_next=...]` in
+    /// order. Following stops at a page without a next link or with one to a page scraped before.
+    /// Disabled pages and pages beyond [`ScrapeOptions::max_total_pages`] are left out.
+    async fn scrape_following(&self, page: &Page, scraped: &mut Vec<ScrapedPage>) -> Result<()> {
+        let mut visited: HashSet<String> = HashSet::new();
+        if page.disabled() {
+            ScrapeCounter::count(&self.counter.skipped, 1);
+            return Ok(());
+        }
+        if !self.take_page() {
+            return Ok(());
+        }
+        let (first, mut next) = self.scrape_with_next(page).await?;
+        visited.insert(first.url.clone());
+        scraped.push(first);
+        while let Some(page) = next
+            .take()
+            .filter(|page| visited.insert(page.url().clone()) && self.take_page())
+        {
+            let (page, following) = self.scrape_with_next(&page).await?;
+            scraped.push(page);
+            next = following;
+        }
+        Ok(())
+    }
+
+    /// Scrapes `pages` in order like [`Scraper::scrape_pages`], writing every page into `sink` as
+    /// soon as it is scraped. The sink is flushed at the end, and before returning the error of a
+    /// page that fails, so that it holds the pages scraped until then. The pages of a
+    /// `merge { ... }` group are written one by one, unmerged.
+    #[cfg(feature = "json")]
+    pub async fn scrape_into(&self, pages: &[Page], sink: &mut impl PageSink) -> Result<()> {
+        self.plan(pages.len());
+        for page in pages {
+            let mut scraped: Vec<ScrapedPage> = vec![];
+            let result = self.scrape_following(page, &mut scraped).await;
+            for scraped in &scraped {
+                if let Err(err) = sink.write_page(page, scraped) {
+                    let _ = sink.flush();
+                    return Err(err);
+                }
+            }
+            if let Err(err) = result {
+                sink.flush()?;
+                return Err(err);
+            }
+        }
+        sink.flush()
+    }
+
+    /// Scrapes `pages` and binds each with the first route of `router` matching its url. Pages no
+    /// route matches are left out.
+    pub async fn scrape_routed<R>(
+        &self,
+        pages: &[Page],
+        router: &ScrapeRouter<R>,
+    ) -> Result<Vec<R>> {
+        let scraped = self.scrape_pages(pages).await?;
+        Ok(scraped
+            .iter()
+            .filter_map(|page| router.dispatch(page))
+            .collect())
+    }
+
+    /// Like [`Scraper::scrape_pages`], but stops once `cancel` completes, for example on Ctrl-C or
+    /// when a time budget runs out. The request in flight is dropped and the pages scraped until
+    /// then are returned.
+    pub async fn scrape_pages_until(
+        &self,
+        pages: &[Page],
+        cancel: impl Future<Output = ()>,
+    ) -> Result<Vec<ScrapedPage>> {
+        self.plan(pages.len());
+        let mut cancel = std::pin::pin!(cancel);
+        let mut scraped: Vec<ScrapedPage> = vec![];
+        for page in pages {
+            let scrape = std::pin::pin!(self.scrape_following(page, &mut scraped));
+            match future::select(scrape, cancel.as_mut()).await {
+                Either::Left((result, _)) => result?,
+                Either::Right(_) => break,
+            }
+        }
+        merge_pages(scraped)
+    }
+
+    /// Scrapes `pages` in order like [`Scraper::scrape_pages`], but yields every page bound to `T`
+    /// as soon as it is scraped. A page is only fetched once the one before has been taken from
+    /// the stream, and a failed page doesn't end it, while reaching
+    /// [`ScrapeOptions::max_total_pages`] does. The pages of a `merge { ... }` group are yielded
+    /// one by one, unmerged.
+    pub fn scrape_stream<T>(self, pages: Vec<Page>) -> impl Stream<Item = Result<T>> + Send
     where
         T: ScrapeBindable,
     {
-        todo!("Not implemented!");
+        self.plan(pages.len());
+        let state = (self, pages.into_iter(), None::<Page>, HashSet::new());
+        stream::unfold(
+            state,
+            |(scraper, mut pages, next, mut visited)| async move {
+                let page = match next.filter(|page| !visited.contains(page.url())) {
+                    Some(page) => page,
+                    None => {
+                        visited.clear();
+                        loop {
+                            let page = pages.next()?;
+                            if !page.disabled() {
+                                break page;
+                            }
+                            ScrapeCounter::count(&scraper.counter.skipped, 1);
+                        }
+                    }
+                };
+                visited.insert(page.url().clone());
+                if !scraper.take_page() {
+                    ScrapeCounter::count(&scraper.counter.skipped, pages.len());
+                    return None;
+                }
+                let (bound, next) = match scraper.scrape_with_next(&page).await {
+                    Ok((page, next)) => (Ok(T::bind(&page)), next),
+                    Err(err) => (Err(err), None),
+                };
+                Some((bound, (scraper, pages, next, visited)))
+            },
+        )
+    }
+
+    /// Fetches `page` and extracts its elements. Responses with an error status fail, unless one
+    /// of the page mirrors answers instead. A `page[wait_for=...]` is extracted from the page as
+    /// the [`ScrapeOptions::headless`] backend renders it once its selector matches, see
+    /// [`ScrapeOptions::wait_timeout`], or as it is fetched without a backend. A disabled page is
+    /// scraped all the same when asked for by itself.
+    pub async fn scrape_page(&self, page: &Page) -> Result<ScrapedPage> {
+        self.plan(1);
+        Ok(self.scrape_with_next(page).await?.0)
+    }
+
+    fn extract(&self, page: &Page, fetched: Fetched) -> Result<ScrapedPage> {
+        let document = parse_document(&fetched.html, &self.options);
+        let scraped = Extractor::new(&self.options, &self.selectors)
+            .with_base(&fetched.final_url)
+            .extract(page, &document)?;
+        let mut scraped =
+            scraped.with_freshness(fetched.fetched_at, fetched.etag, fetched.last_modified);
+        scraped.preview = self.options.preview_chars.map(|chars| {
+            let end = fetched.html.char_indices().nth(chars);
+            fetched.html[..end.map_or(fetched.html.len(), |(end, _)| end)].to_string()
+        });
+        if fetched.url != *page.url() {
+            scraped = scraped.with_mirror(fetched.url.clone());
+        }
+        scraped.merge = page.merge().cloned();
+        match fetched.request_url != fetched.final_url {
+            true => Ok(scraped.with_final_url(fetched.final_url)),
+            false => Ok(scraped),
+        }
+    }
+
+    /// Fetches `page` like [`Scraper::scrape_page`], but returns its visible text instead of its
+    /// elements, see [`page_text`].
+    pub async fn scrape_text(&self, page: &Page) -> Result<String> {
+        let fetched = self
+            .fetch_page(page, None)
+            .await?
+            .expect("only conditional requests are not modified");
+        let document = parse_document(&fetched.html, &self.options);
+        Ok(visible_text(page_root(page, &document, &self.selectors)?))
+    }
+
+    /// Scrapes `page` like [`Scraper::scrape_page`], along with the page its `rel="next"` link
+    /// leads to if it follows next pages.
+    async fn scrape_with_next(&self, page: &Page) -> Result<(ScrapedPage, Option<Page>)> {
+        ScrapeCounter::count(&self.counter.fetched, 1);
+        let scraped = self.fetch_with_next(page).await;
+        match &scraped {
+            Ok(_) => ScrapeCounter::count(&self.counter.succeeded, 1),
+            Err(_) => ScrapeCounter::count(&self.counter.failed, 1),
+        }
+        scraped
+    }
+
+    /// [`Scraper::scrape_with_next`] without counting the page.
+    async fn fetch_with_next(&self, page: &Page) -> Result<(ScrapedPage, Option<Page>)> {
+        let cached = self.cached(page.url());
+        let follows = page.follow_next().is_some_and(|pages| pages > 0);
+        let Some(fetched) = self.fetch_page(page, cached.as_ref()).await? else {
+            let cached = cached.expect("only pages in the cache are fetched conditionally");
+            let next = cached
+                .next
+                .filter(|_| follows)
+                .map(|url| page.next_page(url));
+            let scraped = cached.page;
+            let (etag, last_modified) = (scraped.etag.clone(), scraped.last_modified);
+            let scraped = scraped.with_freshness(SystemTime::now(), etag, last_modified);
+            self.cache(&scraped, next.as_ref());
+            return Ok((scraped, next));
+        };
+        let next = match follows {
+            true => next_link(&Html::parse_document(&fetched.html), &fetched.final_url)
+                .map(|url| page.next_page(url)),
+            false => None,
+        };
+        let scraped = self.extract(page, fetched)?;
+        #[cfg(feature = "headless")]
+        let scraped = self.take_screenshot(page, scraped).await?;
+        self.cache(&scraped, next.as_ref());
+        Ok((scraped, next))
+    }
+
+    /// Saves a screenshot of `scraped` if `page` is marked `page[screenshot=true]` and there is a
+    /// [`ScrapeOptions::headless`] backend to take it.
+    #[cfg(feature = "headless")]
+    async fn take_screenshot(&self, page: &Page, mut scraped: ScrapedPage) -> Result<ScrapedPage> {
+        let Some(backend) = self.options.headless.as_ref().filter(|_| page.screenshot()) else {
+            return Ok(scraped);
+        };
+        let url = scraped.final_url().or(scraped.mirror()).unwrap_or(scraped.url()).clone();
+        let png = backend.screenshot(&url).await?;
+        let dir = match &self.options.screenshot_dir {
+            Some(dir) => dir.clone(),
+            None => PathBuf::from("screenshots"),
+        };
+        let file = dir.join(format!("{}.png", screenshot_name(&url)));
+        std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&file, png))
+            .map_err(|err| {
+                Error::ScraperError(format!("Could not save the screenshot of {}: {}", url, err))
+            })?;
+        scraped.screenshot = Some(file);
+        Ok(scraped)
+    }
+
+    /// Fetches the HTML of `page`, rendered by the [`ScrapeOptions::headless`] backend for a
+    /// `page[wait_for=...]`. `None` if it wasn't modified since `cached`, which was rendered
+    /// already.
+    async fn fetch_page(
+        &self,
+        page: &Page,
+        cached: Option<&CachedPage>,
+    ) -> Result<Option<Fetched>> {
+        let cached = cached.map(|cached| &cached.page);
+        let Some(fetched) = self.fetch_mirrored(page, cached).await? else {
+            return Ok(None);
+        };
+        #[cfg(feature = "headless")]
+        let fetched = self.render(page, fetched).await?;
+        Ok(Some(fetched))
+    }
+
+    /// Replaces the HTML of `fetched` with the page as the [`ScrapeOptions::headless`] backend
+    /// renders it once the selector of a `page[wait_for=...]` matches. Fails if it doesn't match
+    /// within [`ScrapeOptions::wait_timeout`].
+    #[cfg(feature = "headless")]
+    async fn render(&self, page: &Page, mut fetched: Fetched) -> Result<Fetched> {
+        let (Some(wait_for), Some(backend)) = (page.wait_for(), &self.options.headless) else {
+            return Ok(fetched);
+        };
+        let timeout = self.options.wait_timeout.unwrap_or(Duration::from_secs(10));
+        let interval = self
+            .options
+            .wait_interval
+            .unwrap_or(Duration::from_millis(500));
+        let rendered = backend
+            .render(&fetched.final_url, wait_for, timeout, interval)
+            .await?;
+        fetched.html = rendered.ok_or_else(|| {
+            Error::ScraperError(format!(
+                "{} did not match on {} within {:?}",
+                wait_for,
+                page.url(),
+                timeout
+            ))
+        })?;
+        Ok(fetched)
+    }
+
+    /// Fetches the page url, or each of the page mirrors in order while fetching fails. The error
+    /// of every url is reported once all of them failed.
+    async fn fetch_mirrored(
+        &self,
+        page: &Page,
+        cached: Option<&ScrapedPage>,
+    ) -> Result<Option<Fetched>> {
+        if page.mirrors().is_empty() {
+            return self.fetch(page.url(), cached).await;
+        }
+        let mut failures: Vec<String> = vec![];
+        for url in std::iter::once(page.url()).chain(page.mirrors()) {
+            match self.fetch(url, cached).await {
+                Ok(fetched) => return Ok(fetched),
+                Err(err) => failures.push(format!("{}: {}", url, err)),
+            }
+        }
+        Err(Error::ScraperError(format!(
+            "{} and its mirrors failed: {}",
+            page.url(),
+            failures.join("; ")
+        )))
+    }
+
+    /// Fetches the body of `url`, retrying as often as the options allow. With the `ETag` or
+    /// `Last-Modified` of a `cached` scrape the request is conditional, and `None` if the page
+    /// wasn't modified.
+    async fn fetch(&self, url: &str, cached: Option<&ScrapedPage>) -> Result<Option<Fetched>> {
+        let request_url = self.options.request_url(url)?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.get(request_url.clone());
+            if let Some(etag) = cached.and_then(|cached| cached.etag()) {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(date) = cached.and_then(|cached| cached.last_modified()) {
+                request = request.header(
+                    reqwest::header::IF_MODIFIED_SINCE,
+                    httpdate::fmt_http_date(date),
+                );
+            }
+            let permit = match &self.options.request_permits {
+                Some(permits) => Some(permits.acquire().await.map_err(|_| {
+                    Error::ScraperError("the request permits were closed".to_string())
+                })?),
+                None => None,
+            };
+            let response = request.send().await;
+            let retry = match &response {
+                Ok(response) if RETRY_STATUSES.contains(&response.status().as_u16()) => {
+                    Some(retry_after(response))
+                }
+                Err(err) if err.is_timeout() || err.is_connect() => Some(None),
+                _ => None,
+            };
+            match retry {
+                Some(retry_after) if attempt < self.options.retries => {
+                    drop(permit);
+                    tokio::time::sleep(self.options.retry_delay(attempt, retry_after)).await;
+                    attempt += 1;
+                }
+                _ => {
+                    let response = response?.error_for_status()?;
+                    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+                        return match cached {
+                            Some(_) => Ok(None),
+                            None => Err(Error::ScraperError(format!(
+                                "{} answered 304 Not Modified to an unconditional request",
+                                url
+                            ))),
+                        };
+                    }
+                    let fetched_at = SystemTime::now();
+                    let header = |name| {
+                        let value = response.headers().get(name)?.to_str().ok()?;
+                        Some(value.trim().to_string())
+                    };
+                    let etag = header(reqwest::header::ETAG);
+                    let last_modified = header(reqwest::header::LAST_MODIFIED)
+                        .and_then(|date| httpdate::parse_http_date(&date).ok());
+                    if response.status().is_redirection() {
+                        let location = header(reqwest::header::LOCATION).unwrap_or_default();
+                        return Err(Error::ScraperError(format!(
+                            "{} redirects to {}, but redirects are not followed",
+                            url, location
+                        )));
+                    }
+                    let final_url = response.url().to_string();
+                    return Ok(Some(Fetched {
+                        url: url.to_string(),
+                        request_url: request_url.to_string(),
+                        final_url,
+                        html: response.text().await?,
+                        fetched_at,
+                        etag,
+                        last_modified,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// A fetched document and what its response says about its freshness.
+struct Fetched {
+    html: String,
+    url: String,
+    /// The url requested, `url` with the [`ScrapeOptions::extra_query`].
+    request_url: String,
+    final_url: String,
+    fetched_at: SystemTime,
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+const RETRY_STATUSES: [u16; 4] = [429, 502, 503, 504];
+
+/// How many compiled selectors a [`Scraper`] keeps.
+const SELECTOR_CACHE_SIZE: usize = 512;
+
+/// Compiled selectors by their source, so that a selector used on many pages or records is only
+/// compiled once. Once full, the selector used least recently is dropped for a new one.
+pub struct SelectorCache {
+    capacity: usize,
+    entries: Mutex<SelectorEntries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Default)]
+struct SelectorEntries {
+    selectors: HashMap<String, (Arc<Selector>, u64)>,
+    uses: u64,
+}
+
+impl SelectorCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(SelectorEntries::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// The compiled `selector`, compiling it unless it is cached.
+    fn get(&self, selector: &str) -> Result<Arc<Selector>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.uses += 1;
+        let uses = entries.uses;
+        if let Some((compiled, used)) = entries.selectors.get_mut(selector) {
+            *used = uses;
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(compiled.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let compiled = Arc::new(compile_selector(selector)?);
+        if entries.selectors.len() >= self.capacity {
+            let oldest = entries
+                .selectors
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(selector, _)| selector.clone());
+            if let Some(oldest) = oldest {
+                entries.selectors.remove(&oldest);
+            }
+        }
+        entries
+            .selectors
+            .insert(selector.to_string(), (compiled.clone(), uses));
+        Ok(compiled)
+    }
+
+    /// How often a selector was taken from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How often a selector had to be compiled.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// How many selectors are cached.
+    pub fn len(&self) -> usize {
+        let entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.selectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The url of the first `<link rel="next">` or `<a rel="next">` of `document`, resolved against
+/// `url`, the url of the document. `None` without one or if it isn't a web url.
+fn next_link(document: &Html, url: &str) -> Option<String> {
+    let selector = Selector::parse(r#"link[rel~="next"][href], a[rel~="next"][href]"#)
+        .expect("next links have a valid selector");
+    let href = document.select(&selector).next()?.value().attr("href")?;
+    let next = Url::parse(url).ok()?.join(href.trim()).ok()?;
+    matches!(next.scheme(), "http" | "https").then(|| next.to_string())
+}
+
+/// The wait a response asks for with `Retry-After`, in seconds or until a date. `None` if it has
+/// none or it is malformed.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+/// The file name of the screenshot of `url`, its host, port, path and query without the scheme,
+/// with every run of other chars than letters, digits, `.` and `-` as one `_`.
+#[cfg(feature = "headless")]
+fn screenshot_name(url: &str) -> String {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let mut name = String::new();
+    for c in url.chars() {
+        match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+            true => name.push(c),
+            false if !name.ends_with('_') => name.push('_'),
+            false => {}
+        }
+    }
+    name.trim_matches('_').to_string()
+}
+
+/// An upper bound of how many HTTP requests scraping `pages` makes, without fetching anything. Url
+/// templates are already expanded into pages, each of which may request its url and all of its
+/// mirrors, every one of them tried `1 + opts.retries` times. A `page[follow_next=n]` counts for
+/// `1 + n` pages and a disabled page for none. With [`ScrapeOptions::max_total_pages`] only that
+/// many pages count, those with the most mirrors, whichever of them are scraped first. Without
+/// failures, next pages, disabled pages or a cap it is `pages.len()`. What the
+/// [`ScrapeOptions::headless`] backend loads isn't counted.
+pub fn estimate_requests(pages: &[Page], opts: &ScrapeOptions) -> usize {
+    // The requests of each page along with how many pages it counts for.
+    let mut costs: Vec<(usize, usize)> = pages
+        .iter()
+        .filter(|page| !page.disabled())
+        .map(|page| {
+            let requests = (1 + page.mirrors().len()) * (1 + opts.retries);
+            (requests, 1 + page.follow_next().unwrap_or_default())
+        })
+        .collect();
+    costs.sort_by_key(|&(requests, _)| std::cmp::Reverse(requests));
+    let mut left = opts.max_total_pages.unwrap_or(usize::MAX);
+    let mut estimate = 0;
+    for (requests, pages) in costs {
+        let pages = pages.min(left);
+        estimate += requests * pages;
+        left -= pages;
     }
+    estimate
+}
+
+/// Extracts the elements of `page` from `html`, the document fetched from its url. Selectors match
+/// inside `<body>`, or anywhere in documents without one, such as framesets, unless the page has
+/// another [`Page::root`]. Selectors of `<meta>` and `<title>` tags, which are in the `<head>`,
+/// match anywhere at the top level of the page, so `$title = title;` is the title of the
+/// document, or nothing without one. In a `list` or a block they match inside its matches.
+pub fn extract_page(page: &Page, html: &str) -> Result<ScrapedPage> {
+    extract_page_with(page, html, &ScrapeOptions::default())
+}
+
+/// Like [`extract_page`], with the extraction settings of `options` such as
+/// [`ScrapeOptions::text_separator`].
+pub fn extract_page_with(page: &Page, html: &str, options: &ScrapeOptions) -> Result<ScrapedPage> {
+    let document = parse_document(html, options);
+    let selectors = SelectorCache::new(SELECTOR_CACHE_SIZE);
+    Extractor::new(options, &selectors)
+        .with_base(page.url())
+        .extract(page, &document)
+}
+
+/// The visible text of `html`, the document fetched from the url of `page`, for indexing it.
+/// That's the text inside `<body>` or the [`Page::root`] without the content of `<script>`,
+/// `<style>`, `<noscript>` and `<template>` tags, with each run of whitespace made a single space.
+/// The elements of `page` don't matter.
+pub fn page_text(page: &Page, html: &str) -> Result<String> {
+    let document = Html::parse_document(html);
+    let selectors = SelectorCache::new(SELECTOR_CACHE_SIZE);
+    Ok(visible_text(page_root(page, &document, &selectors)?))
 }
 
+/// Like [`extract_page`], but also records where the matches are in `html`, see
+/// [`ScrapedElement::locations`]. Meant for tools that highlight matches in the source.
+pub fn extract_page_with_locations(page: &Page, html: &str) -> Result<ScrapedPage> {
+    let document = Html::parse_document(html);
+    let options = ScrapeOptions::default();
+    let selectors = SelectorCache::new(SELECTOR_CACHE_SIZE);
+    Extractor {
+        locations: locate_elements(&document, html),
+        ..Extractor::new(&options, &selectors).with_base(page.url())
+    }
+    .extract(page, &document)
+}
+
+/// The element of `document` the selectors of `page` match inside, see [`extract_page`].
+fn page_root<'a>(
+    page: &Page,
+    document: &'a Html,
+    selectors: &SelectorCache,
+) -> Result<ElementRef<'a>> {
+    match page.root() {
+        Some(root) => {
+            let selector = selectors
+                .get(root)
+                .map_err(|err| locate_on_page(err, page))?;
+            document.select(&selector).next().ok_or_else(|| {
+                Error::ScraperError(format!("root {} matched nothing on {}", root, page.url()))
+            })
+        }
+        None => {
+            let body = Selector::parse("body").expect("body is a valid selector");
+            Ok(document
+                .select(&body)
+                .next()
+                .unwrap_or_else(|| document.root_element()))
+        }
+    }
+}
+
+/// Tags whose content [`page_text`] leaves out.
+const HIDDEN_TAGS: [&str; 4] = ["script", "style", "noscript", "template"];
+
+/// Tags [`page_text`] runs into the text around them, so `<b>H</b>ello` stays one word. Other tags
+/// separate their text from what's around them.
+const INLINE_TAGS: [&str; 24] = [
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "dfn", "em", "i", "kbd", "mark", "q",
+    "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u", "var",
+];
+
+/// The text of `root` for [`page_text`].
+fn visible_text(root: ElementRef) -> String {
+    fn push_text(node: ElementRef, text: &mut String) {
+        for child in node.children() {
+            if let Some(part) = child.value().as_text() {
+                text.push_str(part);
+            } else if let Some(element) = ElementRef::wrap(child) {
+                let name = element.value().name();
+                if HIDDEN_TAGS.contains(&name) {
+                    continue;
+                }
+                let inline = INLINE_TAGS.contains(&name);
+                if !inline {
+                    text.push(' ');
+                }
+                push_text(element, text);
+                if !inline {
+                    text.push(' ');
+                }
+            }
+        }
+    }
+    let mut text = String::new();
+    push_text(root, &mut text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Parses `html` for extraction with its tag and attribute names lowercased, unfolding its
+/// templates if [`ScrapeOptions::template_content`] is set.
+fn parse_document(html: &str, options: &ScrapeOptions) -> Html {
+    let mut document = Html::parse_document(html);
+    lowercase_names(&mut document);
+    if options.template_content {
+        unfold_templates(&mut document);
+    }
+    document
+}
+
+/// Lowercases the tag and attribute names of `document`, like those of selectors, see
+/// [`lowercase_selector_names`]. The HTML parser lowercases them already, except for the camel
+/// case names of SVG such as `<foreignObject>` and `viewBox`.
+fn lowercase_names(document: &mut Html) {
+    let has_upper = |name: &str| name.chars().any(char::is_uppercase);
+    for node in document.tree.values_mut() {
+        let Node::Element(element) = node else {
+            continue;
+        };
+        if has_upper(&element.name.local) {
+            element.name.local = element.name.local.to_lowercase().into();
+        }
+        if element.attrs.keys().any(|name| has_upper(&name.local)) {
+            let attrs = std::mem::take(&mut element.attrs).into_iter();
+            element.attrs = attrs
+                .map(|(mut name, value)| {
+                    name.local = name.local.to_lowercase().into();
+                    (name, value)
+                })
+                .collect();
+        }
+    }
+}
+
+/// Makes the content of every `<template>` and `<noscript>` in `document` children of the tag.
+/// The parser keeps the content of a template in a fragment of its own and the markup of a
+/// `<noscript>` as its text, which is parsed here.
+fn unfold_templates(document: &mut Html) {
+    fn graft(target: &mut NodeMut<Node>, source: NodeRef<Node>) {
+        for child in source.children() {
+            graft(&mut target.append(child.value().clone()), child);
+        }
+    }
+    let tags: Vec<NodeId> = document
+        .root_element()
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter(|node| matches!(node.value().name(), "template" | "noscript"))
+        .map(|node| node.id())
+        .collect();
+    for tag in tags {
+        let node = document.tree.get(tag).expect("the tag is in the document");
+        let children: Vec<NodeId> = node.children().map(|child| child.id()).collect();
+        if node.value().as_element().is_some_and(|tag| tag.name() == "template") {
+            let content: Vec<NodeId> = node
+                .children()
+                .filter(|fragment| fragment.value().is_fragment())
+                .flat_map(|fragment| fragment.children().map(|child| child.id()))
+                .collect();
+            let mut template = document.tree.get_mut(tag).expect("the tag is in the document");
+            for child in content {
+                template.append_id(child);
+            }
+        } else {
+            let markup: String = node
+                .children()
+                .filter_map(|child| child.value().as_text())
+                .map(|text| &**text)
+                .collect();
+            let parsed = Html::parse_fragment(&markup);
+            for child in children {
+                if let Some(mut child) = document.tree.get_mut(child) {
+                    child.detach();
+                }
+            }
+            let mut noscript = document.tree.get_mut(tag).expect("the tag is in the document");
+            graft(&mut noscript, *parsed.root_element());
+        }
+    }
+}
+
+/// Finds the start tags of the source of `document` and pairs them with its elements in document
+/// order. Elements the parser inserted, such as a missing `<tbody>`, don't pair with the next tag
+/// and get no location.
+fn locate_elements(document: &Html, html: &str) -> HashMap<NodeId, MatchLocation> {
+    let mut tags = start_tags(html).into_iter().peekable();
+    let mut locations: HashMap<NodeId, MatchLocation> = HashMap::new();
+    for node in document
+        .root_element()
+        .descendants()
+        .filter_map(ElementRef::wrap)
+    {
+        if let Some((_, location)) =
+            tags.next_if(|(name, _)| name.eq_ignore_ascii_case(node.value().name()))
+        {
+            locations.insert(node.id(), location);
+        }
+    }
+    locations
+}
+
+/// The names and locations of the start tags in `html`, skipping comments and the text of raw
+/// text elements like `<script>`.
+fn start_tags(html: &str) -> Vec<(String, MatchLocation)> {
+    let mut tags: Vec<(String, MatchLocation)> = vec![];
+    let mut i = 0;
+    while let Some(offset) = html[i..].find('<') {
+        let start = i + offset;
+        let rest = &html[start..];
+        if rest.starts_with("<!--") {
+            i = start + rest.find("-->").map_or(rest.len(), |end| end + 3);
+            continue;
+        }
+        let name_len = rest[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(rest.len() - 1);
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            i = start + 1;
+            continue;
+        }
+        let name = rest[1..1 + name_len].to_ascii_lowercase();
+        let mut quote: Option<char> = None;
+        let mut end = rest.len();
+        for (j, c) in rest.char_indices().skip(1 + name_len) {
+            match (quote, c) {
+                (Some(q), c) if q == c => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, '>') => {
+                    end = j + 1;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        i = start + end;
+        if matches!(name.as_str(), "script" | "style" | "textarea" | "title") {
+            let close = format!("</{}", name);
+            i += html[i..]
+                .to_ascii_lowercase()
+                .find(&close)
+                .unwrap_or(html.len() - i);
+        }
+        tags.push((
+            name,
+            MatchLocation {
+                start,
+                end: start + end,
+            },
+        ));
+    }
+    tags
+}
+
+/// The state shared while extracting the elements of one page.
+struct Extractor<'o> {
+    locations: HashMap<NodeId, MatchLocation>,
+    separator: &'o str,
+    selectors: &'o SelectorCache,
+    fail_on_empty: bool,
+    allow_empty_all: bool,
+    relative_links: bool,
+    base: Option<Url>,
+    /// The element the selectors of the page match inside, set while extracting a page.
+    root: Option<NodeId>,
+}
+
+impl<'o> Extractor<'o> {
+    fn new(options: &'o ScrapeOptions, selectors: &'o SelectorCache) -> Self {
+        Self {
+            locations: HashMap::new(),
+            separator: options.text_separator.as_deref().unwrap_or(" "),
+            selectors,
+            fail_on_empty: options.fail_on_empty,
+            allow_empty_all: options.allow_empty_all,
+            relative_links: options.relative_links,
+            base: None,
+            root: None,
+        }
+    }
+
+    /// Resolves links against `url`, see [`ScrapeOptions::relative_links`].
+    fn with_base(mut self, url: &str) -> Self {
+        if !self.relative_links {
+            self.base = Url::parse(url).ok();
+        }
+        self
+    }
+
+    fn extract(mut self, page: &Page, document: &Html) -> Result<ScrapedPage> {
+        let root = page_root(page, document, self.selectors)?;
+        self.root = Some(root.id());
+        let mut scraped = ScrapedRecord::default();
+        for element in page.elements() {
+            self.get_element_data(element, root, &mut scraped)
+                .map_err(|err| locate_on_page(err, page))?;
+        }
+        let scraped = ScrapedPage::new(page.url().clone(), page.name().cloned(), scraped.elements)
+            .with_records(scraped.records);
+        for assertion in page.assertions() {
+            check_assertion(assertion, &scraped, document, self.selectors)
+                .map_err(|err| locate_on_page(err, page))?;
+        }
+        Ok(scraped)
+    }
+
+    /// Matches `element` inside `scope` and adds what it scrapes to `scraped`, see
+    /// [`Extractor::element_data`]. An invalid selector names the innermost element it belongs to.
+    fn get_element_data(
+        &self,
+        element: &Element,
+        scope: ElementRef,
+        scraped: &mut ScrapedRecord,
+    ) -> Result<()> {
+        let mut result = self.element_data(element, scope, scraped);
+        if let Err(Error::InvalidSelectorError(invalid)) = &mut result {
+            if invalid.url.is_none() && invalid.offset.is_none() && invalid.element.is_none() {
+                invalid.element = element.identifier().clone();
+                invalid.offset = element.span().map(|span| span.start);
+            }
+        }
+        result
+    }
+
+    /// Fails if `scraped` has neither values nor records while [`ScrapeOptions::fail_on_empty`]
+    /// is set, or `quantifier` takes every match and [`ScrapeOptions::allow_empty_all`] isn't,
+    /// unless `element` is optional.
+    fn check_empty(
+        &self,
+        element: &Element,
+        quantifier: &Quantifier,
+        scraped: &ScrapedElement,
+    ) -> Result<()> {
+        let all = matches!(quantifier, Quantifier::All) && !self.allow_empty_all;
+        if !(self.fail_on_empty || all)
+            || element.optional()
+            || !scraped.values.is_empty()
+            || !scraped.records.is_empty()
+        {
+            return Ok(());
+        }
+        Err(Error::EmptyElementError(
+            format!("${} = {}", scraped.name, element.selector()),
+            String::new(),
+        ))
+    }
+
+    /// The branch of a `when ... else` quantifier its guard picks in `scope`, which is the scope of
+    /// the element like for a `when` block, or `quantifier` itself.
+    fn quantifier<'q>(
+        &self,
+        quantifier: &'q Quantifier,
+        scope: ElementRef,
+    ) -> Result<&'q Quantifier> {
+        match quantifier {
+            Quantifier::When {
+                guard,
+                then,
+                otherwise,
+            } => match select(scope, guard, self.selectors)?.is_empty() {
+                true => self.quantifier(otherwise, scope),
+                false => self.quantifier(then, scope),
+            },
+            quantifier => Ok(quantifier),
+        }
+    }
+
+    /// Matches `element` inside `scope` and adds what it scrapes to `scraped`. A named element adds
+    /// itself, with a record per match if it has a block. An unnamed one only scopes its block,
+    /// whose elements are added for every match as if they were written in place of it. A `list`
+    /// adds a record per match to the records of `scraped`. A disabled element adds nothing, nor
+    /// does its block.
+    fn element_data<'a>(
+        &self,
+        element: &Element,
+        scope: ElementRef<'a>,
+        scraped: &mut ScrapedRecord,
+    ) -> Result<()> {
+        if element.disabled() {
+            return Ok(());
+        }
+        if element.guard() {
+            if !select(scope, element.selector(), self.selectors)?.is_empty() {
+                for child in element.children().iter().flatten() {
+                    self.get_element_data(child, scope, scraped)?;
+                }
+            }
+            return Ok(());
+        }
+        let quantifier = self.quantifier(element.quantifier(), scope)?;
+        if element.list() {
+            let matches = quantifier.apply(select(scope, element.selector(), self.selectors)?);
+            for node in matches {
+                let mut record = ScrapedRecord::default();
+                for child in element.children().iter().flatten() {
+                    self.get_element_data(child, node, &mut record)?;
+                }
+                scraped.records.push(record);
+            }
+            return Ok(());
+        }
+        if let Extraction::JsonLd { schema_type, path } = element.extraction() {
+            let selector = self.selectors.get(element.selector())?;
+            let scripts = scope
+                .tree()
+                .root()
+                .descendants()
+                .filter_map(ElementRef::wrap)
+                .filter(|node| selector.matches(node))
+                .map(|node| node.text().collect::<String>());
+            let values = jsonld_values(scripts, schema_type, path.as_deref())?;
+            if let Some(name) = element.identifier() {
+                let values = quantifier
+                    .apply(values)
+                    .into_iter()
+                    .map(|value| transformed(element, value))
+                    .collect::<Result<_>>()?;
+                let scraped_element = ScrapedElement::new(name.clone(), values);
+                self.check_empty(element, quantifier, &scraped_element)?;
+                add_element(&mut scraped.elements, scraped_element);
+            }
+            return Ok(());
+        }
+        let top_level = self.root == Some(scope.id());
+        let scope = match top_level && is_head_selector(element.selector()) {
+            true => document_element(scope),
+            false => scope,
+        };
+        let matches = select(scope, element.selector(), self.selectors)?;
+        if let (Extraction::Count, Some(name)) = (element.extraction(), element.identifier()) {
+            let count = vec![transformed(element, matches.len().to_string())?];
+            add_element(
+                &mut scraped.elements,
+                ScrapedElement::new(name.clone(), count),
+            );
+            return Ok(());
+        }
+        let base = self.base.as_ref();
+        let unique =
+            |matches: Vec<ElementRef<'a>>| unique_matches(matches, element, self.separator, base);
+        let positions: HashMap<NodeId, usize> = match element.extraction() {
+            Extraction::Index => matches
+                .iter()
+                .enumerate()
+                .map(|(position, node)| (node.id(), position))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        let matches = match element.transforms().iter().find_map(|t| match t {
+            Transform::Unique => Some(true),
+            Transform::UniqueTaken => Some(false),
+            _ => None,
+        }) {
+            Some(true) => quantifier.apply(unique(matches)?),
+            Some(false) => unique(quantifier.apply(matches))?,
+            None => quantifier.apply(matches),
+        };
+        match (element.identifier(), element.children()) {
+            (Some(name), children) => {
+                let values = match element.extraction() {
+                    Extraction::Index => matches
+                        .iter()
+                        .map(|node| transformed(element, positions[&node.id()].to_string()))
+                        .collect::<Result<_>>()?,
+                    _ => matches
+                        .iter()
+                        .filter_map(|node| {
+                            element_value(*node, element, self.separator, base).transpose()
+                        })
+                        .collect::<Result<_>>()?,
+                };
+                let mut records: Vec<ScrapedRecord> = vec![];
+                for node in matches.iter().filter(|_| children.is_some()) {
+                    let mut record = ScrapedRecord::default();
+                    for child in children.iter().flatten() {
+                        self.get_element_data(child, *node, &mut record)?;
+                    }
+                    records.push(record);
+                }
+                let mut scraped_element =
+                    ScrapedElement::new(name.clone(), values).with_records(records);
+                scraped_element.locations = matches
+                    .iter()
+                    .filter_map(|node| self.locations.get(&node.id()).copied())
+                    .collect();
+                if let Extraction::Table { headers } = element.extraction() {
+                    for node in &matches {
+                        let mut rows = table_rows(*node, self.separator, self.selectors)?;
+                        if *headers && scraped_element.headers.is_empty() && !rows.is_empty() {
+                            scraped_element.headers = rows.remove(0);
+                        } else if *headers && !rows.is_empty() {
+                            rows.remove(0);
+                        }
+                        scraped_element.rows.extend(rows);
+                    }
+                }
+                if let Extraction::Attributes = element.extraction() {
+                    for node in &matches {
+                        let record = attribute_record(*node, element, base)?;
+                        scraped_element.records.push(record);
+                    }
+                }
+                if let Extraction::Definitions = element.extraction() {
+                    for node in &matches {
+                        let pairs = definition_pairs(*node, self.separator);
+                        scraped_element.pairs.extend(pairs);
+                    }
+                }
+                if element.transforms().contains(&Transform::DataUri) {
+                    let values = scraped_element.values.iter();
+                    let data = values.map(|value| DataUri::decode(value));
+                    scraped_element.data = data.collect::<Result<_>>()?;
+                }
+                self.check_empty(element, quantifier, &scraped_element)?;
+                add_element(&mut scraped.elements, scraped_element);
+            }
+            (None, Some(children)) => {
+                for node in matches {
+                    for child in children {
+                        self.get_element_data(child, node, scraped)?;
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Fails with the assertion and what was found instead if `assertion` doesn't hold for `scraped`.
+fn check_assertion(
+    assertion: &Assertion,
+    scraped: &ScrapedPage,
+    document: &Html,
+    selectors: &SelectorCache,
+) -> Result<()> {
+    let values = match assertion.subject() {
+        AssertionSubject::Values(name) => scraped
+            .element(name)
+            .map(|element| element.values().clone())
+            .unwrap_or_default(),
+        AssertionSubject::Count(selector) => {
+            vec![select(document.root_element(), selector, selectors)?
+                .len()
+                .to_string()]
+        }
+    };
+    let holds = match assertion.predicate() {
+        Predicate::Matches(pattern) => {
+            let regex = Regex::new(pattern).map_err(|err| Error::ScraperError(err.to_string()))?;
+            !values.is_empty() && values.iter().all(|value| regex.is_match(value))
+        }
+        Predicate::NonEmpty => values.iter().any(|value| !value.is_empty()),
+        Predicate::Compare(comparison, right) => {
+            let left = match assertion.subject() {
+                AssertionSubject::Values(_) => values.len(),
+                AssertionSubject::Count(_) => values[0].parse().unwrap_or_default(),
+            };
+            comparison.holds(left, *right)
+        }
+    };
+    if holds {
+        return Ok(());
+    }
+    Err(Error::ScraperError(format!(
+        "{} failed on {}, found {:?}",
+        assertion.source(),
+        scraped.url(),
+        values
+    )))
+}
+
+/// Whether the values of `element` get their entities decoded, see [`Transform::Decode`].
+fn decodes(element: &Element) -> bool {
+    let transforms = element.transforms();
+    match element.extraction() {
+        Extraction::Attribute { .. } => !transforms.contains(&Transform::Raw),
+        _ => transforms.contains(&Transform::Decode),
+    }
+}
+
+/// Decodes the HTML entities of `value` the way they are decoded in the text of a document, which
+/// leaves incomplete ones like the `&T` of `AT&T` as they are.
+fn decode_entities(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+    let fragment = Html::parse_fragment(&value.replace('<', "&lt;"));
+    fragment.root_element().text().collect()
+}
+
+/// Tags of the `<head>` whose selectors match anywhere in the document at the top level of a
+/// page, see [`extract_page`].
+const HEAD_TAGS: [&str; 2] = ["meta", "title"];
+
+/// Whether `selector` matches tags of the `<head>`, as `meta[name=description]` and `title` do.
+fn is_head_selector(selector: &str) -> bool {
+    HEAD_TAGS.iter().any(|tag| {
+        selector
+            .get(..tag.len())
+            .filter(|start| start.eq_ignore_ascii_case(tag))
+            .map(|_| &selector[tag.len()..])
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '-'))
+    })
+}
+
+/// The root element of the document `node` is in, `<html>` for HTML documents.
+fn document_element(node: ElementRef) -> ElementRef {
+    node.tree()
+        .root()
+        .children()
+        .find_map(ElementRef::wrap)
+        .unwrap_or(node)
+}
+
+/// Leaves out the matches whose value an earlier one has, for the `unique` transforms. Values are
+/// compared as the other transforms of `element` leave them, so `trim | unique` ignores spacing.
+fn unique_matches<'a>(
+    matches: Vec<ElementRef<'a>>,
+    element: &Element,
+    separator: &str,
+    base: Option<&Url>,
+) -> Result<Vec<ElementRef<'a>>> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut unique: Vec<ElementRef<'a>> = vec![];
+    for node in matches {
+        if element_value(node, element, separator, base)?.is_none_or(|value| seen.insert(value)) {
+            unique.push(node);
+        }
+    }
+    Ok(unique)
+}
+
+/// The value of one match of `element`, extracted, decoded and transformed, see
+/// [`extract_value`] and [`transformed`].
+fn element_value(
+    node: ElementRef,
+    element: &Element,
+    separator: &str,
+    base: Option<&Url>,
+) -> Result<Option<String>> {
+    let Some(value) = extract_value(node, element.extraction(), separator) else {
+        return Ok(None);
+    };
+    let value = match decodes(element) {
+        true => decode_entities(&value),
+        false => value,
+    };
+    let raw = element.transforms().contains(&Transform::Raw);
+    let value = match (element.extraction(), base) {
+        (Extraction::Attribute { name, .. }, Some(base))
+            if !raw
+                && LINK_ATTRIBUTES.contains(&name.as_str())
+                && node.value().attr(name).is_some() =>
+        {
+            absolute_link(value, base)
+        }
+        _ => value,
+    };
+    transformed(element, value).map(Some)
+}
+
+/// The attributes holding a url that are resolved, see [`ScrapeOptions::relative_links`].
+const LINK_ATTRIBUTES: [&str; 2] = ["href", "src"];
+
+/// `link` resolved against `base`, or as it is if it isn't a url, like `http://[oops`.
+fn absolute_link(link: String, base: &Url) -> String {
+    match base.join(link.trim()) {
+        Ok(url) => url.to_string(),
+        Err(_) => link,
+    }
+}
+
+/// Runs `value` through the transforms of `element` in order, see [`Element::transform`]. A
+/// value one of them fails on, as `number` does on text, fails the scrape.
+fn transformed(element: &Element, value: String) -> Result<String> {
+    if element.transforms().is_empty() {
+        return Ok(value);
+    }
+    element.transform(&value).ok_or_else(|| {
+        let element = match element.identifier() {
+            Some(name) => format!("${}", name),
+            None => element.selector().to_string(),
+        };
+        Error::ScraperError(format!(
+            "the transforms of {} failed on {:?}",
+            element, value
+        ))
+    })
+}
+
+/// Extracts the value of one match, or `None` if it has none, like a link without the `href` that
+/// is extracted, or a match whose attributes are extracted, which go into records instead. The
+/// text of a `<meta>` tag is its `content`.
+fn extract_value(node: ElementRef, extraction: &Extraction, separator: &str) -> Option<String> {
+    match extraction {
+        Extraction::Text if node.value().name() == "meta" => {
+            node.value().attr("content").map(str::to_string)
+        }
+        Extraction::Attribute { name, default } => node
+            .value()
+            .attr(name)
+            .map(str::to_string)
+            .or_else(|| default.clone()),
+        Extraction::OwnText => Some(node_text(node, separator, false)),
+        Extraction::Attributes => None,
+        _ => Some(node_text(node, separator, true)),
+    }
+}
+
+/// Joins the text nodes of `node`, of its whole subtree if `nested` or only its own otherwise.
+/// `separator` goes between two texts unless one of them already has whitespace there, and a
+/// `<br>` is a newline.
+fn node_text(node: ElementRef, separator: &str, nested: bool) -> String {
+    let mut text = String::new();
+    let parts: Box<dyn Iterator<Item = _>> = if nested {
+        Box::new(node.descendants().skip(1))
+    } else {
+        Box::new(node.children())
+    };
+    for part in parts {
+        if let Some(part) = part.value().as_text() {
+            if !text.is_empty()
+                && !text.ends_with(char::is_whitespace)
+                && !part.starts_with(char::is_whitespace)
+            {
+                text.push_str(separator);
+            }
+            text.push_str(part);
+        } else if part.value().as_element().is_some_and(|e| e.name() == "br") {
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// The text of the `td` and `th` cells of every row of `table`, leaving out the rows of tables
+/// nested in it.
+fn table_rows(
+    table: ElementRef,
+    separator: &str,
+    selectors: &SelectorCache,
+) -> Result<Vec<Vec<String>>> {
+    let rows = selectors.get("tr")?;
+    let cells = selectors.get(":scope > td, :scope > th")?;
+    Ok(table
+        .select(&rows)
+        .filter(|row| {
+            row.ancestors()
+                .filter_map(ElementRef::wrap)
+                .find(|node| node.value().name() == "table")
+                .is_some_and(|owner| owner.id() == table.id())
+        })
+        .map(|row| {
+            row.select(&cells)
+                .map(|cell| node_text(cell, separator, true).trim().to_string())
+                .collect()
+        })
+        .collect())
+}
+
+/// Every attribute of `node` as an element of a record, in order of their names. Values are
+/// decoded unless `raw` and transformed like the values of `element`.
+fn attribute_record(
+    node: ElementRef,
+    element: &Element,
+    base: Option<&Url>,
+) -> Result<ScrapedRecord> {
+    let mut attributes: Vec<(&str, &str)> = node.value().attrs().collect();
+    attributes.sort();
+    let mut record = ScrapedRecord::default();
+    let raw = element.transforms().contains(&Transform::Raw);
+    for (name, value) in attributes {
+        let value = match raw {
+            true => value.to_string(),
+            false => decode_entities(value),
+        };
+        let value = match base {
+            Some(base) if !raw && LINK_ATTRIBUTES.contains(&name) => absolute_link(value, base),
+            _ => value,
+        };
+        let value = transformed(element, value)?;
+        record
+            .elements
+            .push(ScrapedElement::new(name.to_string(), vec![value]));
+    }
+    Ok(record)
+}
+
+/// The term and description pairs of the definition list `list`, see [`ScrapedElement::pairs`].
+/// Its `dt` and `dd` are its children, or those of the `div`s grouping them.
+fn definition_pairs(list: ElementRef, separator: &str) -> Vec<(String, String)> {
+    let items = list
+        .children()
+        .filter_map(ElementRef::wrap)
+        .flat_map(|child| match child.value().name() {
+            "div" => child.children().filter_map(ElementRef::wrap).collect(),
+            _ => vec![child],
+        });
+    let mut pairs: Vec<(String, String)> = vec![];
+    let mut terms: Vec<String> = vec![];
+    let mut described = false;
+    for item in items {
+        let text = node_text(item, separator, true).trim().to_string();
+        match item.value().name() {
+            "dt" if described => {
+                terms = vec![text];
+                described = false;
+            }
+            "dt" => terms.push(text),
+            "dd" => {
+                described = true;
+                pairs.extend(terms.iter().map(|term| (term.clone(), text.clone())));
+            }
+            _ => {}
+        }
+    }
+    if !described {
+        pairs.extend(terms.into_iter().map(|term| (term, String::new())));
+    }
+    pairs
+}
+
+/// Finds the JSON-LD items of `schema_type` in the text of `scripts`, and takes `path` of each.
+/// Items are searched in arrays and `@graph`s too, the whole document is searched wherever the
+/// element is. Strings are taken as they are, other values as JSON. Blocks that aren't valid JSON
+/// are left out.
+#[cfg(feature = "jsonld")]
+fn jsonld_values(
+    scripts: impl Iterator<Item = String>,
+    schema_type: &str,
+    path: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut items: Vec<serde_json::Value> = vec![];
+    for script in scripts {
+        if let Ok(value) = serde_json::from_str(&script) {
+            collect_jsonld_items(value, schema_type, &mut items);
+        }
+    }
+    Ok(items
+        .iter()
+        .filter_map(|item| match path {
+            Some(path) => jsonld_field(item, path),
+            None => Some(item),
+        })
+        .map(|value| match value {
+            serde_json::Value::String(text) => text.clone(),
+            value => value.to_string(),
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "jsonld"))]
+fn jsonld_values(
+    _scripts: impl Iterator<Item = String>,
+    schema_type: &str,
+    _path: Option<&str>,
+) -> Result<Vec<String>> {
+    Err(Error::ScraperError(format!(
+        "jsonld(\"{}\") needs the jsonld feature",
+        schema_type
+    )))
+}
+
+#[cfg(feature = "jsonld")]
+fn collect_jsonld_items(
+    value: serde_json::Value,
+    schema_type: &str,
+    items: &mut Vec<serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_jsonld_items(value, schema_type, items);
+            }
+        }
+        serde_json::Value::Object(mut object) => {
+            if let Some(graph) = object.remove("@graph") {
+                collect_jsonld_items(graph, schema_type, items);
+            }
+            let matches = match object.get("@type") {
+                Some(serde_json::Value::String(t)) => t == schema_type,
+                Some(serde_json::Value::Array(types)) => {
+                    types.iter().any(|t| t.as_str() == Some(schema_type))
+                }
+                _ => false,
+            };
+            if matches {
+                items.push(serde_json::Value::Object(object));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Follows a dotted `path` such as `offers.price` or `image.0` into `value`.
+#[cfg(feature = "jsonld")]
+fn jsonld_field<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(value, |value, key| match value {
+        serde_json::Value::Array(values) => values.get(key.parse::<usize>().ok()?),
+        value => value.get(key),
+    })
+}
+
+/// Merges the scraped pages of every merge group into the first of them, which takes the name of
+/// the group if it has one, keeping what the first is paired with.
+fn merge_groups<T>(scraped: Vec<(T, ScrapedPage)>) -> Result<Vec<(T, ScrapedPage)>> {
+    let mut merged: Vec<(T, ScrapedPage)> = vec![];
+    for (with, mut page) in scraped {
+        let Some(group) = page.merge.clone() else {
+            merged.push((with, page));
+            continue;
+        };
+        match merged
+            .iter_mut()
+            .find(|(_, first)| first.merge.as_ref() == Some(&group))
+        {
+            Some((_, first)) => first.merge_page(page, &group)?,
+            None => {
+                if let Some(name) = group.name() {
+                    page.name = Some(name.clone());
+                }
+                merged.push((with, page));
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// [`merge_groups`] for pages paired with nothing.
+fn merge_pages(scraped: Vec<ScrapedPage>) -> Result<Vec<ScrapedPage>> {
+    let merged = merge_groups(scraped.into_iter().map(|page| ((), page)).collect())?;
+    Ok(merged.into_iter().map(|(_, page)| page).collect())
+}
+
+fn add_element(scraped: &mut Vec<ScrapedElement>, element: ScrapedElement) {
+    match scraped.iter_mut().find(|e| e.name == element.name) {
+        Some(existing) => existing.merge(element),
+        None => scraped.push(element),
+    }
+}
+
+/// Matches `selector` inside `scope`. CSS positions among siblings are supported, `:first-child`,
+/// `:last-child`, `:only-child`, `:nth-child(An+B)`, `:nth-last-child(An+B)` and their `-of-type`
+/// forms, which count the siblings of the same tag only. They count in the document, a quantifier
+/// counts among the matches instead. Attribute values match exactly unless followed by CSS's `i`
+/// flag, so `[data-kind="sale" i]` matches `SALE` too, while attributes HTML compares without
+/// case such as `type` take `s` to match exactly. Besides CSS, a selector may hold one
+/// `:contains("text")`, which keeps the elements whose text contains `text`. It can be followed
+/// by a combinator, so that `dt:contains("Price") + dd` matches the `dd` right after the `dt`
+/// labelled `Price`.
+fn select<'a>(
+    scope: ElementRef<'a>,
+    selector: &str,
+    selectors: &SelectorCache,
+) -> Result<Vec<ElementRef<'a>>> {
+    let Some((anchor, text, rest)) = split_contains(selector)? else {
+        return Ok(scope.select(&*selectors.get(selector)?).collect());
+    };
+    let anchor = selectors.get(&anchor)?;
+    let anchors = scope
+        .select(&anchor)
+        .filter(|node| node.text().collect::<String>().contains(&text));
+    let (combinator, target) = match rest.chars().next() {
+        None => return Ok(anchors.collect()),
+        Some(c @ ('+' | '~' | '>')) => (c, rest[1..].trim()),
+        Some(_) => (' ', rest.as_str()),
+    };
+    let target = match combinator {
+        '>' => selectors.get(&format!("> {}", target))?,
+        _ => selectors.get(target)?,
+    };
+    let mut matches: Vec<ElementRef> = vec![];
+    for anchor in anchors {
+        let mut siblings = anchor.next_siblings().filter_map(ElementRef::wrap);
+        let found: Vec<ElementRef> = match combinator {
+            '+' => siblings
+                .next()
+                .filter(|node| target.matches(node))
+                .into_iter()
+                .collect(),
+            '~' => siblings.filter(|node| target.matches(node)).collect(),
+            _ => anchor.select(&target).collect(),
+        };
+        for node in found {
+            if !matches.iter().any(|m| m.id() == node.id()) {
+                matches.push(node);
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Splits a selector with `:contains("text")` into the selector of the element it applies to,
+/// the text, and the rest of the selector after that element.
+fn split_contains(selector: &str) -> Result<Option<(String, String, String)>> {
+    let Some(start) = selector.find(":contains(") else {
+        return Ok(None);
+    };
+    let invalid = |reason: &str| invalid_selector(selector, reason);
+    let mut chars = selector[start + ":contains(".len()..].char_indices();
+    let quote = match chars.next() {
+        Some((_, c @ ('"' | '\''))) => c,
+        _ => return Err(invalid(":contains takes a quoted text")),
+    };
+    let (end, _) = chars
+        .find(|(_, c)| *c == quote)
+        .ok_or_else(|| invalid("unterminated text in :contains"))?;
+    let after = &selector[start + ":contains(".len()..];
+    let text = after[1..end].to_string();
+    let rest = after[end + 1..]
+        .strip_prefix(')')
+        .ok_or_else(|| invalid("expected ')' after the text of :contains"))?;
+    if rest.contains(":contains(") {
+        return Err(invalid("only one :contains is supported"));
+    }
+    let compound = rest
+        .find(|c: char| c.is_whitespace() || matches!(c, '+' | '~' | '>'))
+        .unwrap_or(rest.len());
+    let anchor = match format!("{}{}", &selector[..start], &rest[..compound]) {
+        anchor if anchor.trim().is_empty() => "*".to_string(),
+        anchor => anchor,
+    };
+    Ok(Some((anchor, text, rest[compound..].trim().to_string())))
+}
+
+/// Quotes the unquoted values of attribute selectors, so `[data-x=1]` is `[data-x="1"]`. CSS
+/// takes an unquoted value only if it is an identifier.
+fn quote_attribute_values(selector: &str) -> String {
+    let mut css = String::with_capacity(selector.len());
+    let mut quote: Option<char> = None;
+    let mut in_attribute = false;
+    let mut chars = selector.chars().peekable();
+    while let Some(c) = chars.next() {
+        css.push(c);
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => in_attribute = true,
+            (None, ']') => in_attribute = false,
+            (None, '=')
+                if in_attribute
+                    && chars
+                        .peek()
+                        .is_some_and(|c| !matches!(c, '"' | '\'') && !c.is_whitespace()) =>
+            {
+                css.push('"');
+                while let Some(c) = chars.next_if(|c| *c != ']' && !c.is_whitespace()) {
+                    if c == '"' || c == '\\' {
+                        css.push('\\');
+                    }
+                    css.push(c);
+                }
+                css.push('"');
+            }
+            _ => {}
+        }
+    }
+    css
+}
+
+/// Lowercases the tag and attribute names of `selector`, so that `DIV[Data-Id]` matches
+/// `<div data-id>` and `foreignObject` the `<foreignObject>` of an SVG, whose name is lowercased
+/// in the document as well. Classes, ids, attribute values and quoted strings keep their case.
+fn lowercase_selector_names(selector: &str) -> String {
+    let is_name = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_') || !c.is_ascii();
+    let mut css = String::with_capacity(selector.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    // Where in the selector the chars are: in a tag name, in a class, id or pseudo-class name
+    // kept as is, in the name or in the value of an attribute selector.
+    let (mut tag, mut kept, mut attribute_name, mut in_attribute) = (false, false, false, false);
+    // Whether a tag name may start here, at the start of a compound selector.
+    let mut compound_start = true;
+    for c in selector.chars() {
+        if escaped || quote.is_some() {
+            css.push(c);
+            if !escaped && quote == Some(c) {
+                quote = None;
+            }
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' | '\'' => quote = Some(c),
+            '[' => (in_attribute, attribute_name) = (true, true),
+            ']' => (in_attribute, attribute_name) = (false, false),
+            '=' | '~' | '|' | '^' | '$' | '*' if in_attribute => attribute_name = false,
+            _ if in_attribute => {}
+            '.' | '#' | ':' => kept = true,
+            ' ' | '\t' | '\n' | '>' | '+' | '~' | ',' | '(' => compound_start = true,
+            _ if is_name(c) && !kept && (tag || compound_start) => tag = true,
+            _ => {}
+        }
+        let name = is_name(c) && ((in_attribute && attribute_name) || (!in_attribute && tag));
+        match name {
+            true => css.extend(c.to_lowercase()),
+            false => css.push(c),
+        }
+        if !is_name(c) {
+            tag = false;
+            kept &= matches!(c, '.' | '#' | ':');
+        }
+        if !matches!(c, ' ' | '\t' | '\n' | '>' | '+' | '~' | ',' | '(') {
+            compound_start = false;
+        }
+    }
+    css
+}
+
+/// Compiles a selector of a definition. One starting with the `>` child combinator matches the
+/// children of its scope. Tag and attribute names match in any case, see
+/// [`lowercase_selector_names`].
+fn compile_selector(selector: &str) -> Result<Selector> {
+    let selector = &quote_attribute_values(selector);
+    let css = lowercase_selector_names(selector);
+    let css = match css.starts_with('>') {
+        true => format!(":scope {}", css),
+        false => css,
+    };
+    Selector::parse(&css).map_err(|err| invalid_selector(selector, &err.to_string()))
+}
+
+/// An [`Error::InvalidSelectorError`] without context, which callers add as far as they know it.
+fn invalid_selector(selector: &str, reason: &str) -> Error {
+    Error::InvalidSelectorError(Box::new(InvalidSelector {
+        selector: selector.to_string(),
+        reason: reason.to_string(),
+        element: None,
+        url: None,
+        offset: None,
+        line: None,
+    }))
+}
+
+/// Adds the url of `page` and the line of the selector in its definition file to a selector error
+/// that doesn't name a page yet, and the url to an element that matched nothing.
+fn locate_on_page(mut err: Error, page: &Page) -> Error {
+    if let Error::EmptyElementError(_, url) = &mut err {
+        if url.is_empty() {
+            url.clone_from(page.url());
+        }
+    }
+    if let Error::InvalidSelectorError(invalid) = &mut err {
+        if invalid.url.is_none() {
+            invalid.url = Some(page.url().clone());
+            invalid.line = invalid
+                .offset
+                .zip(page.source())
+                .and_then(|(offset, path)| {
+                    let source = std::fs::read(path).ok()?;
+                    let before = source.get(..offset)?;
+                    Some(1 + before.iter().filter(|byte| **byte == b'\n').count())
+                });
+        }
+    }
+    err
+}
+
+#[async_trait]
+pub trait ParserExt {
+    async fn scrape<T>(&mut self) -> Result<Vec<T>>
+    where
+        T: ScrapeBindable;
+
+    async fn scrape_with<T>(&mut self, options: &ScrapeOptions) -> Result<Vec<T>>
+    where
+        T: ScrapeBindable;
+
+    /// Parses the file of the parser and binds its pages by url. See [`Scraper::scrape_routed`].
+    async fn scrape_routed<R>(
+        &mut self,
+        options: &ScrapeOptions,
+        router: &ScrapeRouter<R>,
+    ) -> Result<Vec<R>>
+    where
+        R: Send;
+
+    /// Parses the file of the parser and streams its pages. See [`Scraper::scrape_stream`].
+    fn scrape_stream<T>(
+        &mut self,
+        options: &ScrapeOptions,
+    ) -> Result<BoxStream<'static, Result<T>>>
+    where
+        T: ScrapeBindable + Send + 'static;
+
+    /// Parses the file of the parser and scrapes every page into a JSON object, see [`page_json`].
+    /// A `merge { ... }` group is one object with the elements of all of its pages.
+    #[cfg(feature = "json")]
+    async fn scrape_json(&mut self) -> Result<Vec<serde_json::Value>>;
+}
+
+#[async_trait]
+impl ParserExt for Parser {
+    /// Parses the file of the parser and scrapes its pages with the default [`ScrapeOptions`].
+    async fn scrape<T>(&mut self) -> Result<Vec<T>>
+    where
+        T: ScrapeBindable,
+    {
+        self.scrape_with(&ScrapeOptions::default()).await
+    }
+
+    async fn scrape_with<T>(&mut self, options: &ScrapeOptions) -> Result<Vec<T>>
+    where
+        T: ScrapeBindable,
+    {
+        let pages = self.parse()?;
+        let scraped = Scraper::new(options.clone())?.scrape_pages(&pages).await?;
+        Ok(scraped.iter().map(T::bind).collect())
+    }
+
+    async fn scrape_routed<R>(
+        &mut self,
+        options: &ScrapeOptions,
+        router: &ScrapeRouter<R>,
+    ) -> Result<Vec<R>>
+    where
+        R: Send,
+    {
+        let pages = self.parse()?;
+        Scraper::new(options.clone())?
+            .scrape_routed(&pages, router)
+            .await
+    }
+
+    fn scrape_stream<T>(&mut self, options: &ScrapeOptions) -> Result<BoxStream<'static, Result<T>>>
+    where
+        T: ScrapeBindable + Send + 'static,
+    {
+        let pages = self.parse()?;
+        Ok(Scraper::new(options.clone())?.scrape_stream(pages).boxed())
+    }
+
+    #[cfg(feature = "json")]
+    async fn scrape_json(&mut self) -> Result<Vec<serde_json::Value>> {
+        let pages = self.parse()?;
+        let scraper = Scraper::new(ScrapeOptions::default())?;
+        scraper.plan(pages.len());
+        let mut scraped: Vec<(&Page, ScrapedPage)> = vec![];
+        for page in &pages {
+            let mut following: Vec<ScrapedPage> = vec![];
+            scraper.scrape_following(page, &mut following).await?;
+            scraped.extend(following.into_iter().map(|scraped| (page, scraped)));
+        }
+        Ok(merge_groups(scraped)?
+            .iter()
+            .map(|(page, scraped)| match &scraped.merge {
+                Some(group) => {
+                    let elements: Vec<Element> = pages
+                        .iter()
+                        .filter(|page| page.merge() == Some(group))
+                        .flat_map(|page| page.elements().clone())
+                        .collect();
+                    json_object(&elements, &scraped.elements, &scraped.records).into()
+                }
+                None => page_json(page, scraped),
+            })
+            .collect())
+    }
+}
+
+/// Renders `scraped`, a scrape of `page`, as a JSON object keyed by identifier. Elements taking
+/// one match, without a quantifier or with `*last`, are a string, or `null` without a match, and
+/// the others an array of strings. Elements with a block are objects of their block instead,
+/// tables arrays of rows, attributes objects by name and definition lists an object of the
+/// descriptions by term. The records of `list` blocks are an array of objects under `"$list"`,
+/// which can't be an identifier.
+#[cfg(feature = "json")]
+pub fn page_json(page: &Page, scraped: &ScrapedPage) -> serde_json::Value {
+    json_object(page.elements(), &scraped.elements, &scraped.records).into()
+}
+
+#[cfg(feature = "json")]
+fn json_object(
+    elements: &[Element],
+    scraped: &[ScrapedElement],
+    records: &[ScrapedRecord],
+) -> serde_json::Map<String, serde_json::Value> {
+    use serde_json::Value;
+    let mut object = serde_json::Map::new();
+    let mut lists: Vec<Element> = vec![];
+    let mut pending: Vec<&Element> = elements.iter().rev().collect();
+    while let Some(element) = pending.pop() {
+        let Some(name) = element.identifier() else {
+            if element.list() {
+                lists.extend(element.children().iter().flatten().cloned());
+            } else {
+                pending.extend(element.children().iter().flatten().rev());
+            }
+            continue;
+        };
+        let Some(found) = scraped.iter().find(|e| e.name() == name) else {
+            continue;
+        };
+        let single = matches!(element.quantifier(), Quantifier::Single | Quantifier::Last);
+        let values: Vec<Value> = match (element.children(), element.extraction()) {
+            (Some(children), _) => found
+                .records()
+                .iter()
+                .map(|record| json_object(children, &record.elements, &record.records).into())
+                .collect(),
+            (None, Extraction::Table { headers: true }) => {
+                let rows = found.headed_rows().into_iter();
+                rows.map(|row| row.into_iter().collect()).collect()
+            }
+            (None, Extraction::Table { headers: false }) => {
+                found.rows().iter().map(|row| row.clone().into()).collect()
+            }
+            (None, Extraction::Attributes) => found
+                .attributes()
+                .into_iter()
+                .map(|attributes| attributes.into_iter().collect())
+                .collect(),
+            (None, Extraction::Definitions) => {
+                let definitions = found.definitions().into_iter();
+                vec![Value::Object(definitions.map(|(k, v)| (k, v.into())).collect())]
+            }
+            (None, _) => found.values().iter().map(|v| v.clone().into()).collect(),
+        };
+        let value = match (single, element.extraction()) {
+            (_, Extraction::Definitions) if element.children().is_none() => {
+                values.into_iter().next().unwrap_or(Value::Null)
+            }
+            (true, Extraction::Table { .. }) | (false, _) => Value::Array(values),
+            (true, _) => values.into_iter().next().unwrap_or(Value::Null),
+        };
+        object.insert(name.clone(), value);
+    }
+    if !lists.is_empty() {
+        let records = records
+            .iter()
+            .map(|record| json_object(&lists, &record.elements, &record.records).into());
+        object.insert("$list".to_string(), Value::Array(records.collect()));
+    }
+    object
+}
+
+/// Where [`Scraper::scrape_into`] writes the pages it scrapes, as soon as each is scraped.
+#[cfg(feature = "json")]
+pub trait PageSink {
+    /// Writes `scraped`, a scrape of `page`.
+    fn write_page(&mut self, page: &Page, scraped: &ScrapedPage) -> Result<()>;
+
+    /// Writes out what is buffered.
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// Writes every page as a line of [`page_json`], newline delimited JSON. A line is only written
+/// once it is rendered completely, and what is buffered is flushed when the sink is finished or
+/// dropped, so that the output of a scrape that failed or panicked halfway still holds every
+/// page written before.
+#[cfg(feature = "json")]
+pub struct NdjsonSink<W: std::io::Write> {
+    writer: Option<std::io::BufWriter<W>>,
+}
+
+#[cfg(feature = "json")]
+impl<W: std::io::Write> NdjsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(std::io::BufWriter::new(writer)),
+        }
+    }
+
+    /// Flushes the sink and returns its writer.
+    pub fn finish(mut self) -> Result<W> {
+        let writer = self.writer.take().expect("only finishing takes the writer");
+        writer.into_inner().map_err(|err| write_error(err.error()))
+    }
+}
+
+#[cfg(feature = "json")]
+impl<W: std::io::Write> PageSink for NdjsonSink<W> {
+    fn write_page(&mut self, page: &Page, scraped: &ScrapedPage) -> Result<()> {
+        use std::io::Write;
+        let mut line = page_json(page, scraped).to_string();
+        line.push('\n');
+        let writer = self.writer.as_mut().expect("only finishing takes the writer");
+        writer.write_all(line.as_bytes()).map_err(|err| write_error(&err))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        use std::io::Write;
+        match &mut self.writer {
+            Some(writer) => writer.flush().map_err(|err| write_error(&err)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl<W: std::io::Write> Drop for NdjsonSink<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Writes every page into a file of its own inside a directory, `0.json`, `1.json` and so on in
+/// the order they are scraped. Each file is written through an [`NdjsonSink`] and flushed before
+/// the next page, so a failed scrape leaves complete files only.
+#[cfg(feature = "json")]
+pub struct FileSink {
+    dir: std::path::PathBuf,
+    written: usize,
+}
+
+#[cfg(feature = "json")]
+impl FileSink {
+    /// A sink writing into `dir`, which is created if it doesn't exist.
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|err| write_error(&err))?;
+        Ok(Self { dir, written: 0 })
+    }
+
+    /// How many pages were written.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+}
+
+#[cfg(feature = "json")]
+impl PageSink for FileSink {
+    fn write_page(&mut self, page: &Page, scraped: &ScrapedPage) -> Result<()> {
+        let path = self.dir.join(format!("{}.json", self.written));
+        let file = std::fs::File::create(&path).map_err(|err| write_error(&err))?;
+        let mut sink = NdjsonSink::new(file);
+        sink.write_page(page, scraped)?;
+        sink.finish()?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "json")]
+fn write_error(err: &std::io::Error) -> Error {
+    Error::WriteError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Fixtures;
+    use wiremock::matchers::{header, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn extract(source: &str, html: &str) -> ScrapedPage {
+        let pages = Parser::new().parse_source(source).unwrap();
+        extract_page(&pages[0], html).unwrap()
+    }
+
+    fn values(page: &ScrapedPage, name: &str) -> Vec<String> {
+        page.element(name).unwrap().values().clone()
+    }
+
+    const LIST: &str = "<html><body><ul><li>a</li><li>b</li><li>c</li></ul></body></html>";
+
+    #[test]
+    fn applies_quantifiers() {
+        let page = extract(
+            "page <https://a.com> { $one = li; $all = li*all; $last = li*last; $two = li*2; $mid = li*1..2; $rest = li skip 1; }",
+            LIST,
+        );
+        assert_eq!(values(&page, "one"), ["a"]);
+        assert_eq!(values(&page, "all"), ["a", "b", "c"]);
+        assert_eq!(values(&page, "last"), ["c"]);
+        assert_eq!(values(&page, "two"), ["a", "b"]);
+        assert_eq!(values(&page, "mid"), ["b"]);
+        assert_eq!(values(&page, "rest"), ["b", "c"]);
+    }
+
+    #[test]
+    fn matches_universal_and_attribute_only_selectors() {
+        let page = extract(
+            "page <https://a.com> { $first = ul > *; $all = ul > * *all; $data = [data-x=1]*all; }",
+            "<body><ul><li>a</li><li data-x=\"1\">b</li><li data-x=\"1\">c</li></ul></body>",
+        );
+        assert_eq!(values(&page, "first"), ["a"]);
+        assert_eq!(values(&page, "all"), ["a", "b", "c"]);
+        assert_eq!(values(&page, "data"), ["b", "c"]);
+    }
+
+    #[test]
+    fn scopes_blocks_to_their_matches() {
+        let html = "<body><div class=\"item\"><h2>A</h2><p>1</p></div><div class=\"item\"><h2>B</h2></div></body>";
+        let page = extract(
+            "page <https://a.com> { $items = .item*all { $title = h2; $price = p; } }",
+            html,
+        );
+        let items = page.element("items").unwrap().records();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].element("title").unwrap().values(), &["A"]);
+        assert_eq!(items[0].element("price").unwrap().values(), &["1"]);
+        assert_eq!(items[1].element("title").unwrap().values(), &["B"]);
+        assert!(items[1].element("price").unwrap().values().is_empty());
+    }
+
+    struct Product {
+        name: String,
+        prices: Vec<String>,
+    }
+
+    impl ScrapeRecordBindable for Product {
+        fn bind(record: &ScrapedRecord) -> Self {
+            let price = record.element("price");
+            Self {
+                name: record.element("name").unwrap().values()[0].clone(),
+                prices: price.map(|e| e.values().clone()).unwrap_or_default(),
+            }
+        }
+    }
+
+    #[test]
+    fn scrapes_a_record_per_list_match() {
+        let html = "<body>\
+            <section><h2>Lamps</h2><div class=\"product\"><h3>Desk</h3><p>9</p></div><div class=\"product\"><h3>Floor</h3></div></section>\
+            <section><h2>Chairs</h2><div class=\"product\"><h3>Stool</h3><p>5</p><p>4</p></div></section>\
+            </body>";
+        let page = extract(
+            "page <https://a.com> { $title = h2; list section { $category = >h2; list .product { $name = h3; $price.optional = p*all; } } }",
+            html,
+        );
+        assert_eq!(values(&page, "title"), ["Lamps"]);
+        let categories = page.records();
+        assert_eq!(categories.len(), 2);
+        assert_eq!(
+            categories[1].element("category").unwrap().values(),
+            &["Chairs"]
+        );
+        let lamps: Vec<&str> = categories[0]
+            .records()
+            .iter()
+            .map(|r| r.element("name").unwrap().values()[0].as_str())
+            .collect();
+        assert_eq!(lamps, ["Desk", "Floor"]);
+
+        let page = extract(
+            "page <https://a.com> { list .product*2 { $name = h3; $price.optional = p*all; } }",
+            html,
+        );
+        let products: Vec<Product> = ScrapeBindable::bind(&page);
+        assert_eq!(products.len(), 2);
+        assert_eq!(products[0].name, "Desk");
+        assert_eq!(products[0].prices, ["9"]);
+        assert!(products[1].prices.is_empty());
+    }
+
+    #[derive(ScrapeBindable)]
+    struct Address {
+        street: String,
+        city: Option<String>,
+    }
+
+    #[derive(ScrapeBindable)]
+    struct Customer {
+        name: String,
+        tags: Vec<String>,
+        address: Address,
+        billing: Option<Address>,
+        shops: Vec<Address>,
+    }
+
+    #[test]
+    fn binds_nested_blocks_to_nested_structs() {
+        let html = "<body><h1>Ada</h1><i>new</i><i>vip</i>\
+            <div class=\"address\"><p class=\"street\">Main St 1</p><p class=\"city\">Berlin</p></div>\
+            <div class=\"shop\"><p class=\"street\">Side St 2</p></div><div class=\"shop\"><p class=\"street\">Back St 3</p></div>\
+            </body>";
+        let page = extract(
+            "page <https://a.com> { $name = h1; $tags = i*all; $address = .address { $street = .street; $city = .city; } $billing = .billing { $street = .street; } $shops = .shop*all { $street = .street; $city = .city; } }",
+            html,
+        );
+        let customer: Customer = ScrapeBindable::bind(&page);
+        assert_eq!(customer.name, "Ada");
+        assert_eq!(customer.tags, ["new", "vip"]);
+        assert_eq!(customer.address.street, "Main St 1");
+        assert_eq!(customer.address.city.as_deref(), Some("Berlin"));
+        assert!(customer.billing.is_none());
+        let shops: Vec<_> = customer.shops.iter().map(|shop| &shop.street).collect();
+        assert_eq!(shops, ["Side St 2", "Back St 3"]);
+        assert!(customer.shops[0].city.is_none());
+
+        let page = extract(
+            "page <https://a.com> { list .shop { $street = .street; } }",
+            html,
+        );
+        let addresses: Vec<Address> = ScrapeBindable::bind(&page);
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[1].street, "Back St 3");
+    }
+
+    #[test]
+    fn scrapes_tables_into_rows() {
+        let html = "<body><table>\
+            <thead><tr><th>Name</th><th>Price</th></tr></thead>\
+            <tbody><tr><td>Lamp</td><td> 9 </td></tr><tr><td colspan=\"2\">Sold out</td></tr>\
+            <tr><td>Desk</td><td><table><tr><td>nested</td></tr></table></td></tr></tbody>\
+            </table></body>";
+        let page = extract(
+            r#"page <https://a.com> { $rows = table(table); $products = table(table, "headers"); }"#,
+            html,
+        );
+        let rows = page.element("rows").unwrap().rows();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], ["Name", "Price"]);
+        assert_eq!(rows[1], ["Lamp", "9"]);
+        assert_eq!(rows[2], ["Sold out"]);
+        assert_eq!(rows[3], ["Desk", "nested"]);
+        let products = page.element("products").unwrap().headed_rows();
+        assert_eq!(products.len(), 3);
+        assert_eq!(products[0]["Name"], "Lamp");
+        assert_eq!(products[0]["Price"], "9");
+        assert_eq!(products[1].get("Price"), None);
+    }
+
+    #[test]
+    fn extracts_every_attribute_of_matches() {
+        let source = "page <https://a.com> { $data = .product@*; $all = .product@* * all | uppercase; }";
+        let html = "<body><div class=\"product\" data-id=\"7\" data-sku=\"L&amp;1\" data-price=\"9\">Lamp</div><div class=\"product\" id=\"desk\">Desk</div></body>";
+        let page = extract(source, html);
+        let data = page.element("data").unwrap();
+        assert!(data.values().is_empty());
+        let names: Vec<_> = data.records()[0].elements().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["class", "data-id", "data-price", "data-sku"]);
+        let attributes = data.attributes();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0]["data-id"], "7");
+        assert_eq!(attributes[0]["data-sku"], "L&1");
+        let all = page.element("all").unwrap().attributes();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0]["data-sku"], "L&1");
+        assert_eq!(all[1]["id"], "DESK");
+        #[cfg(feature = "json")]
+        {
+            let pages = Parser::new().parse_source(source).unwrap();
+            let json = page_json(&pages[0], &page);
+            assert_eq!(json["data"]["data-price"], "9");
+            assert_eq!(json["all"][1]["class"], "PRODUCT");
+        }
+    }
+
+    #[test]
+    fn pairs_the_terms_and_descriptions_of_definition_lists() {
+        let source = "page <https://a.com> { $specs = dl(dl); }";
+        let html = "<body><dl>\
+            <dd>orphan</dd>\
+            <dt>Color</dt><dd>Red</dd>\
+            <dt>Size</dt><dd>S</dd><dd>M</dd>\
+            <div><dt>Weight</dt><dt>Mass</dt><dd>2 kg</dd></div>\
+            <dt>Notes</dt>\
+            </dl></body>";
+        let page = extract(source, html);
+        let pair = |term: &str, description: &str| (term.to_string(), description.to_string());
+        assert_eq!(
+            page.element("specs").unwrap().pairs(),
+            &[
+                pair("Color", "Red"),
+                pair("Size", "S"),
+                pair("Size", "M"),
+                pair("Weight", "2 kg"),
+                pair("Mass", "2 kg"),
+                pair("Notes", ""),
+            ]
+        );
+        let definitions = page.element("specs").unwrap().definitions();
+        assert_eq!(definitions.len(), 5);
+        assert_eq!(definitions["Size"], "S\nM");
+        assert_eq!(definitions["Notes"], "");
+        #[cfg(feature = "json")]
+        {
+            let pages = Parser::new().parse_source(source).unwrap();
+            let json = page_json(&pages[0], &page);
+            assert_eq!(json["specs"]["Mass"], "2 kg");
+        }
+    }
+
+    #[test]
+    fn extracts_the_positions_of_matches() {
+        let page = extract(
+            "page <https://a.com> { $all = index(li)*all; $mid = index(li)*1..3; $names = li*1..3; $first = index(li); }",
+            "<body><ul><li>a</li><li>b</li><li>c</li><li>d</li></ul></body>",
+        );
+        assert_eq!(values(&page, "all"), ["0", "1", "2", "3"]);
+        assert_eq!(values(&page, "mid"), ["1", "2"]);
+        assert_eq!(values(&page, "names"), ["b", "c"]);
+        assert_eq!(values(&page, "first"), ["0"]);
+    }
+
+    #[test]
+    fn extracts_the_content_of_meta_tags() {
+        let page = extract(
+            "page <https://a.com> { $desc = meta[name=description]; $title = og:title; $image = og:image@content; $h1 = h1; }",
+            "<html><head><meta name=\"description\" content=\"A shop\"><meta property=\"og:title\" content=\"Lamps\"></head><body><h1>Shop</h1></body></html>",
+        );
+        assert_eq!(values(&page, "desc"), ["A shop"]);
+        assert_eq!(values(&page, "title"), ["Lamps"]);
+        assert!(values(&page, "image").is_empty());
+        assert_eq!(values(&page, "h1"), ["Shop"]);
+    }
+
+    #[test]
+    fn matches_meta_tags_inside_the_records_of_a_list() {
+        let page = extract(
+            "page <https://a.com> { $price = meta[itemprop=price]; list div.p { $price = meta[itemprop=price]; } }",
+            "<html><head><meta itemprop=\"price\" content=\"0\"></head><body><div class=\"p\"><meta itemprop=\"price\" content=\"1\"></div><div class=\"p\"><meta itemprop=\"price\" content=\"2\"></div></body></html>",
+        );
+        assert_eq!(values(&page, "price"), ["0"]);
+        let prices: Vec<&str> = page
+            .records()
+            .iter()
+            .map(|r| r.element("price").unwrap().values()[0].as_str())
+            .collect();
+        assert_eq!(prices, ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn extracts_the_document_title() {
+        let fixtures = Fixtures::start().await;
+        let html = "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\">\
+            <title> Lamps &amp; Lights </title></head>\
+            <body><h1>Shop</h1><svg><title>icon</title></svg></body></html>";
+        fixtures
+            .serve("/shop", html)
+            .await
+            .serve("/untitled", "<p>x</p>")
+            .await;
+        let source = "page </shop> { $title = title | trim; $titles = TITLE*all; $icon = svg > title; \
+                      svg { $nested = title; } } page </untitled> { $title = title; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(values(&scraped[0], "title"), ["Lamps & Lights"]);
+        assert_eq!(values(&scraped[0], "titles"), [" Lamps & Lights ", "icon"]);
+        assert_eq!(values(&scraped[0], "icon"), ["icon"]);
+        assert_eq!(values(&scraped[0], "nested"), ["icon"]);
+        assert!(values(&scraped[1], "title").is_empty());
+    }
+
+    #[test]
+    fn decodes_entities_of_attributes_unless_raw() {
+        let page = extract(
+            "page <https://a.com> { $href = a@href; $raw = a@href | raw; $title = a@title; $text = p; $decoded = p | decode; $numeric = b | decode; }",
+            "<body><a href=\"?a=1&amp;amp;b=2\" title=\"AT&amp;T &amp;#x41; &amp;bogus; &amp;#xZZ;\">x</a><p>&amp;lt;b&amp;gt;</p><b>&amp;#39;5&amp;#8364;</b></body>",
+        );
+        assert_eq!(values(&page, "href"), ["https://a.com/?a=1&b=2"]);
+        assert_eq!(values(&page, "raw"), ["?a=1&amp;b=2"]);
+        assert_eq!(values(&page, "title"), ["AT&T A &bogus; &#xZZ;"]);
+        assert_eq!(values(&page, "text"), ["&lt;b&gt;"]);
+        assert_eq!(values(&page, "decoded"), ["<b>"]);
+        assert_eq!(values(&page, "numeric"), ["'5€"]);
+    }
+
+    #[test]
+    fn matches_by_position_among_siblings() {
+        let page = extract(
+            "page <https://a.com> { $second = li:nth-child(2); $odd = li:nth-child(2n + 1)*all; $firsts = li:first-child*all; $last = li:last-child; $from_end = li:nth-last-child(2); $second_li = ul > :nth-of-type(2)*all; $first_p = p:first-of-type; $only = b:only-child; $lasts = li:last-child*1..; }",
+            "<body><ul><li>a</li><li>b</li><p>note</p><li>c</li><li>d</li></ul><ol><li>x</li><li>y</li></ol><p><b>bold</b></p></body>",
+        );
+        assert_eq!(values(&page, "second"), ["b"]);
+        assert_eq!(values(&page, "odd"), ["a", "d", "x"]);
+        assert_eq!(values(&page, "firsts"), ["a", "x"]);
+        assert_eq!(values(&page, "last"), ["d"]);
+        assert_eq!(values(&page, "from_end"), ["c"]);
+        assert_eq!(values(&page, "second_li"), ["b"]);
+        assert_eq!(values(&page, "first_p"), ["note"]);
+        assert_eq!(values(&page, "only"), ["bold"]);
+        assert_eq!(values(&page, "lasts"), ["y"]);
+    }
+
+    #[test]
+    fn matches_inside_the_root_of_the_page() {
+        let html = "<html><head><title>Lamps</title></head><body><main><h1>Shop</h1></main><h1>Ad</h1></body></html>";
+        let page = extract(
+            "page <https://a.com> { $head = head; $h1 = h1*all; }",
+            html,
+        );
+        assert!(values(&page, "head").is_empty());
+        let page = extract(
+            "page[root=html] <https://a.com> { $head = head; $h1 = h1*all; }",
+            html,
+        );
+        assert_eq!(values(&page, "head"), ["Lamps"]);
+        assert_eq!(values(&page, "h1"), ["Shop", "Ad"]);
+        let page = extract("page[root=main] <https://a.com> { $h1 = h1*all; }", html);
+        assert_eq!(values(&page, "h1"), ["Shop"]);
+        let pages = Parser::new()
+            .parse_source("page[root=article] <https://a.com> { $h1 = h1; }")
+            .unwrap();
+        assert_eq!(
+            extract_page(&pages[0], html).unwrap_err().to_string(),
+            "Error while scraping the site: root article matched nothing on https://a.com/"
+        );
+    }
+
+    #[test]
+    fn extracts_the_visible_text_of_pages() {
+        let html = "<html><head><title>Lamps</title><style>h1 { color: red }</style></head><body>\n  <h1>The   <b>L</b>amp\tshop</h1><p>Lamps</p><p>for\n\nevery room</p><br>Open<script>track('x')</script><noscript>Enable JavaScript</noscript>\n</body></html>";
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { } page[root=p] <https://a.com> { }")
+            .unwrap();
+        assert_eq!(
+            page_text(&pages[0], html).unwrap(),
+            "The Lamp shop Lamps for every room Open"
+        );
+        assert_eq!(page_text(&pages[1], html).unwrap(), "Lamps");
+    }
+
+    #[test]
+    fn picks_the_quantifier_branch_by_its_guard() {
+        let source = "page <https://a.com> { $items = .item*all when .show-all else 2; list .shop { $first = li when .more else last; } }";
+        let items = "<li class=\"item\">a</li><li class=\"item\">b</li><li class=\"item\">c</li>";
+        let page = extract(
+            source,
+            &format!("<body><ul>{}</ul><a class=\"show-all\">All</a></body>", items),
+        );
+        assert_eq!(values(&page, "items"), ["a", "b", "c"]);
+        let page = extract(source, &format!("<body><ul>{}</ul></body>", items));
+        assert_eq!(values(&page, "items"), ["a", "b"]);
+
+        let page = extract(
+            source,
+            "<body><ul class=\"shop\"><li>x</li><li>y</li><a class=\"more\"></a></ul><ul class=\"shop\"><li>x</li><li>y</li></ul></body>",
+        );
+        let firsts: Vec<_> = page
+            .records()
+            .iter()
+            .map(|record| record.element("first").unwrap().values().clone())
+            .collect();
+        assert_eq!(firsts, [["x"], ["y"]]);
+    }
+
+    #[test]
+    fn transforms_the_extracted_values() {
+        let page = extract(
+            "pipeline PRICE = trim | strip(\"$\") | number\npage <https://a.com> { $price = .price | trim | strip(\"$\") | number; $prices = .price*all | PRICE; $tags = li*all | trim | lowercase | unique; $at = index(li)*2 | number; }",
+            "<body><p class=\"price\"> $1.50 </p><p class=\"price\">$20</p><ul><li> New</li><li>new </li><li>SALE</li></ul></body>",
+        );
+        assert_eq!(values(&page, "price"), ["1.5"]);
+        assert_eq!(values(&page, "prices"), ["1.5", "20"]);
+        assert_eq!(values(&page, "tags"), ["new", "sale"]);
+        assert_eq!(values(&page, "at"), ["0", "1"]);
+
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $price = .price | number; }")
+            .unwrap();
+        assert_eq!(
+            extract_page(&pages[0], "<body><p class=\"price\">free</p></body>")
+                .unwrap_err()
+                .to_string(),
+            "Error while scraping the site: the transforms of $price failed on \"free\""
+        );
+    }
+
+    #[test]
+    fn fails_on_the_first_element_that_scrapes_nothing() {
+        let options = ScrapeOptions {
+            fail_on_empty: true,
+            ..Default::default()
+        };
+        let html = "<body><h1>Shop</h1><div class=\"product\"><h2>Lamp</h2></div></body>";
+        let pages = Parser::new()
+            .parse_source(
+                "page <https://a.com> { $title = h1; $promo.optional = .promo; .product { $name = h2; $price = .prise; } $stock = .stock; }",
+            )
+            .unwrap();
+        assert_eq!(
+            extract_page_with(&pages[0], html, &options)
+                .unwrap_err()
+                .to_string(),
+            "$price = .prise matched nothing on https://a.com/"
+        );
+        let page = extract_page(&pages[0], html).unwrap();
+        assert!(values(&page, "price").is_empty());
+
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $title = h1; $promo.optional = .promo; $link = a@href; }")
+            .unwrap();
+        let html = "<body><h1>Shop</h1><a>No link</a></body>";
+        assert_eq!(
+            extract_page_with(&pages[0], html, &options)
+                .unwrap_err()
+                .to_string(),
+            "$link = a matched nothing on https://a.com/"
+        );
+    }
+
+    #[test]
+    fn fails_on_all_elements_that_match_nothing() {
+        let html = "<body><h1>Shop</h1></body>";
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $title = h1; $promo = .promo; $tags = .tag*all; }")
+            .unwrap();
+        assert_eq!(
+            extract_page(&pages[0], html).unwrap_err().to_string(),
+            "$tags = .tag matched nothing on https://a.com/"
+        );
+        let lenient = ScrapeOptions {
+            allow_empty_all: true,
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &lenient).unwrap();
+        assert!(values(&page, "tags").is_empty());
+        assert!(values(&page, "promo").is_empty());
+
+        let page = extract(
+            "page <https://a.com> { $tags.optional = .tag*all; $some = .tag*3; $last = .tag*last; }",
+            html,
+        );
+        assert!(values(&page, "tags").is_empty());
+        assert!(values(&page, "some").is_empty());
+    }
+
+    #[test]
+    fn extracts_template_content_only_if_asked_to() {
+        let html = "<body><p class=\"price\">7</p>\
+            <template id=\"data\"><div class=\"item\"><p class=\"price\">5</p></div></template>\
+            <noscript><img src=\"a.png\"></noscript></body>";
+        let source = "page <https://a.com> { $images = noscript > img@src*all;\
+            $prices = template .price*all; template { $item = >.item .price; } }";
+        let pages = Parser::new().parse_source(source).unwrap();
+        let options = ScrapeOptions {
+            template_content: true,
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &options).unwrap();
+        assert_eq!(values(&page, "images"), ["https://a.com/a.png"]);
+        assert_eq!(values(&page, "prices"), ["5"]);
+        assert_eq!(values(&page, "item"), ["5"]);
+
+        let lenient = ScrapeOptions {
+            allow_empty_all: true,
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &lenient).unwrap();
+        assert!(values(&page, "images").is_empty());
+        assert!(values(&page, "prices").is_empty());
+    }
+
+    #[test]
+    fn matches_attribute_values_case_insensitively_with_i() {
+        let html = "<body><p data-kind=\"SALE\">a</p><p data-kind=\"sale i\">b</p>\
+            <input type=\"TEXT\" value=\"c\"><input type=\"text\" value=\"d\"></body>";
+        let page = extract(
+            r#"page <https://a.com> {
+                $exact.optional = p[data-kind="sale"]*all;
+                $any_case = p[data-kind="sale" i]*all;
+                $flag_in_value = p[data-kind="sale i"]*all;
+                $unquoted = p[data-kind=sale i]*all;
+                $typed = input[type="text"]@value*all;
+                $typed_exactly = input[type="text" s]@value*all;
+            }"#,
+            html,
+        );
+        assert!(values(&page, "exact").is_empty());
+        assert_eq!(values(&page, "any_case"), ["a"]);
+        assert_eq!(values(&page, "flag_in_value"), ["b"]);
+        assert_eq!(values(&page, "unquoted"), ["a"]);
+        assert_eq!(values(&page, "typed"), ["c", "d"]);
+        assert_eq!(values(&page, "typed_exactly"), ["d"]);
+    }
+
+    #[test]
+    fn matches_tag_and_attribute_names_in_any_case() {
+        let html = "<html><head><META NAME=\"description\" content=\"d\"></head><body>\
+            <DIV class=\"Card\" Data-Id=\"X\">a</DIV><div class=\"card\" data-id=\"x\">b</div>\
+            <p id=\"Main\">c</p><svg viewBox=\"0 0 1 1\"><foreignObject>d</foreignObject></svg>\
+            <IMG SRC=\"/e.png\"></body></html>";
+        let page = extract(
+            r#"page <https://a.com> {
+                $tags = DiV*all;
+                $class = div.Card;
+                $names = [DATA-ID]*all;
+                $value.optional = [data-id="X"]*all;
+                $any_value = [Data-Id=x i]*all;
+                $id = P#Main;
+                $no_id.optional = p#main;
+                $foreign = svg > foreignObject;
+                $lower = foreignobject;
+                $view_box = svg[VIEWBOX="0 0 1 1"] foreignObject;
+                $not.optional = body > :not(DIV, P, SVG, IMG)*all;
+                $image = img@SRC;
+                $description = META[name=description];
+            }"#,
+            html,
+        );
+        assert_eq!(values(&page, "tags"), ["a", "b"]);
+        assert_eq!(values(&page, "class"), ["a"]);
+        assert_eq!(values(&page, "names"), ["a", "b"]);
+        assert_eq!(values(&page, "value"), ["a"]);
+        assert_eq!(values(&page, "any_value"), ["a", "b"]);
+        assert_eq!(values(&page, "id"), ["c"]);
+        assert!(values(&page, "no_id").is_empty());
+        assert_eq!(values(&page, "foreign"), ["d"]);
+        assert_eq!(values(&page, "lower"), ["d"]);
+        assert_eq!(values(&page, "view_box"), ["d"]);
+        assert!(values(&page, "not").is_empty());
+        assert_eq!(values(&page, "image"), ["https://a.com/e.png"]);
+        assert_eq!(values(&page, "description"), ["d"]);
+        assert_eq!(
+            lowercase_selector_names(r#"UL > Li.Item#Top:nth-child(2N) + [Data-X|="Ab" I] ~ *"#),
+            r#"ul > li.Item#Top:nth-child(2n) + [data-x|="Ab" I] ~ *"#
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_data_uris_with_data_uri() {
+        let fixtures = Fixtures::start().await;
+        let html = "<img src=\"data:image/png;base64,iVBORw0K GgoA\">\
+            <img src=\"data:,a%20b\"><img src=\"/logo.png\">";
+        fixtures.serve("/a", html).await;
+        let definition = "page </a> { $images = img@src*all | data_uri; }";
+        let pages = fixtures.parser(definition).parse().unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let page = scraper.scrape_page(&pages[0]).await.unwrap();
+        let images = page.element("images").unwrap();
+        assert_eq!(images.values()[2], fixtures.url("/logo.png"));
+        let data = images.data();
+        assert_eq!(data.len(), 3);
+        let png = data[0].as_ref().unwrap();
+        assert_eq!(png.media_type(), "image/png");
+        assert_eq!(png.bytes(), b"\x89PNG\r\n\x1a\n\0");
+        let text = data[1].as_ref().unwrap();
+        assert_eq!(text.media_type(), "text/plain;charset=US-ASCII");
+        assert_eq!(text.bytes(), b"a b");
+        assert!(data[2].is_none());
+
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $image = img@src | data_uri; }")
+            .unwrap();
+        let html = "<img src=\"data:image/png;base64,!!\">";
+        assert!(matches!(extract_page(&pages[0], html), Err(Error::ScraperError(message))
+            if message.starts_with("Invalid data uri data:image/png;base64,!!")));
+        let html = "<img src=\"data:image/png\">";
+        assert!(extract_page(&pages[0], html).is_err());
+    }
+
+    #[test]
+    fn resolves_links_against_the_page_url() {
+        let html = "<body><a href=\"/page/2\">a</a><a href=\"next\">b</a>\
+            <a href=\"https://b.com/x\">c</a><a href=\"//cdn.com/lib.js\">d</a>\
+            <a href=\"http://[oops\">e</a><a href=\"mailto:a@b.com\">f</a><a>g</a>\
+            <img src=\"../a.png\" alt=\"/not-a-link\"></body>";
+        let source = r#"page <https://a.com/shop/list> {
+            $links = a@href ?? "none" *all;
+            $image = img@*;
+        }"#;
+        let pages = Parser::new().parse_source(source).unwrap();
+        let page = extract_page(&pages[0], html).unwrap();
+        assert_eq!(
+            values(&page, "links"),
+            [
+                "https://a.com/page/2",
+                "https://a.com/shop/next",
+                "https://b.com/x",
+                "https://cdn.com/lib.js",
+                "http://[oops",
+                "mailto:a@b.com",
+                "none",
+            ]
+        );
+        let image = &page.element("image").unwrap().records()[0];
+        assert_eq!(image.element("src").unwrap().values(), &["https://a.com/a.png"]);
+        assert_eq!(image.element("alt").unwrap().values(), &["/not-a-link"]);
+
+        let relative = ScrapeOptions {
+            relative_links: true,
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &relative).unwrap();
+        assert_eq!(values(&page, "links")[..2], ["/page/2", "next"]);
+    }
+
+    #[test]
+    fn dedupes_before_or_after_taking() {
+        let html =
+            "<body><p>a</p><p>a</p><p>b</p><p>a</p><p>c</p><p>b</p><p>d</p><p>e</p><p>a</p></body>";
+        let page = extract(
+            "page <https://a.com> { $naive = p*5 | unique_taken; $unique = p*5 | unique; $last = p*last | unique; }",
+            html,
+        );
+        assert_eq!(values(&page, "naive"), ["a", "b", "c"]);
+        assert_eq!(values(&page, "unique"), ["a", "b", "c", "d", "e"]);
+        assert_eq!(values(&page, "last"), ["e"]);
+    }
+
+    #[test]
+    fn fails_pages_whose_assertions_dont_hold() {
+        let source = r"page <https://a.com> {
+            $price = .price*all;
+            assert $price matches /^\$\d+$/;
+            assert count(.item) >= 2;
+            assert $price nonempty;
+        }";
+        let pages = Parser::new().parse_source(source).unwrap();
+        let html = "<body><p class=\"item price\">$10</p><p class=\"item price\">$5</p></body>";
+        assert!(extract_page(&pages[0], html).is_ok());
+        let Err(Error::ScraperError(message)) =
+            extract_page(&pages[0], "<body><p class=\"item price\">$10</p></body>")
+        else {
+            panic!("there is only one item");
+        };
+        assert_eq!(
+            message,
+            "assert count(.item) >= 2 failed on https://a.com/, found [\"1\"]"
+        );
+        let Err(Error::ScraperError(message)) = extract_page(
+            &pages[0],
+            "<body><p class=\"item price\">$10</p><p class=\"item price\">free</p></body>",
+        ) else {
+            panic!("free is no price");
+        };
+        assert!(message.starts_with(r"assert $price matches /^\$\d+$/ failed"));
+    }
+
+    #[test]
+    fn joins_texts_with_the_separator() {
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $address = .address; $own = own(.address); }")
+            .unwrap();
+        let html = "<body><p class=\"address\">Main St<b>1</b><br>Springfield <i>42</i></p></body>";
+        let page = extract_page(&pages[0], html).unwrap();
+        assert_eq!(values(&page, "address"), ["Main St 1\nSpringfield 42"]);
+        assert_eq!(values(&page, "own"), ["Main St\nSpringfield "]);
+        let options = ScrapeOptions {
+            text_separator: Some(String::new()),
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &options).unwrap();
+        assert_eq!(values(&page, "address"), ["Main St1\nSpringfield 42"]);
+        let options = ScrapeOptions {
+            text_separator: Some(" | ".to_string()),
+            ..Default::default()
+        };
+        let page = extract_page_with(&pages[0], html, &options).unwrap();
+        assert_eq!(values(&page, "address"), ["Main St | 1\nSpringfield 42"]);
+    }
+
+    #[test]
+    fn merges_unnamed_blocks_into_the_parent() {
+        let html = "<body><ul><li>a</li></ul><ul><li>b</li><li>c</li></ul></body>";
+        let page = extract(
+            "page <https://a.com> { ul*all { $items = >li*all; } }",
+            html,
+        );
+        assert_eq!(values(&page, "items"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn scrapes_guarded_elements_only_if_the_guard_matches() {
+        let source = "page <https://a.com> { $name = h1; .product*all { $sku = >.sku; when .in-stock { $price = .price; } } }";
+        let in_stock = extract(
+            source,
+            "<body><h1>Lamp</h1><div class=\"product\"><p class=\"sku\">1</p><p class=\"in-stock\"></p><p class=\"price\">9</p></div></body>",
+        );
+        assert_eq!(values(&in_stock, "sku"), ["1"]);
+        assert_eq!(values(&in_stock, "price"), ["9"]);
+        let sold_out = extract(
+            source,
+            "<body><h1>Lamp</h1><div class=\"product\"><p class=\"sku\">1</p><p class=\"price\">9</p></div></body>",
+        );
+        assert_eq!(values(&sold_out, "sku"), ["1"]);
+        assert!(sold_out.element("price").is_none());
+    }
+
+    #[test]
+    fn matches_siblings_of_labelled_elements() {
+        let html = "<body><dl><dt>Name</dt><dd>Lamp</dd><dt>Price</dt><dd>12</dd><dd>EUR</dd></dl><p>Price <b>5</b></p></body>";
+        let page = extract(
+            r#"page <https://a.com> {
+                $next = dt:contains("Price") + dd*all;
+                $following = dt:contains("Name") ~ dd*all;
+                $label = dt:contains('Name');
+                $inside = p:contains("Price") b;
+                $adjacent = dt + dd*all;
+            }"#,
+            html,
+        );
+        assert_eq!(values(&page, "next"), ["12"]);
+        assert_eq!(values(&page, "following"), ["Lamp", "12", "EUR"]);
+        assert_eq!(values(&page, "label"), ["Name"]);
+        assert_eq!(values(&page, "inside"), ["5"]);
+        assert_eq!(values(&page, "adjacent"), ["Lamp", "12"]);
+    }
+
+    #[test]
+    fn extracts_attributes_with_defaults() {
+        let html = "<body><a href=\"/a\">A</a><a>B</a><a href=\"/c\">C</a></body>";
+        let page = extract(
+            r#"page <https://a.com> {
+                $hrefs = a@href*all;
+                $aligned = a@href ?? "N/A" *all;
+                $second = a@href ?? "N/A" *1..2;
+                $missing = img@src ?? "N/A";
+            }"#,
+            html,
+        );
+        assert_eq!(values(&page, "hrefs"), ["https://a.com/a", "https://a.com/c"]);
+        assert_eq!(
+            values(&page, "aligned"),
+            ["https://a.com/a", "N/A", "https://a.com/c"]
+        );
+        assert_eq!(values(&page, "second"), ["N/A"]);
+        assert!(values(&page, "missing").is_empty());
+    }
+
+    #[test]
+    fn locates_matches_in_the_source() {
+        let html = "<!doctype html><!-- <li>no</li> --><script>let x = '<li>';</script>\n<ul><li class=\"a\">1</li><LI data-x='>'>2</LI></ul><table><tr><td>3</td></tr></table>";
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $items = li*all; $cell = td; $row = tbody; }")
+            .unwrap();
+        let page = extract_page_with_locations(&pages[0], html).unwrap();
+        let sources = |name: &str| -> Vec<&str> {
+            page.element(name)
+                .unwrap()
+                .locations()
+                .iter()
+                .map(|location| &html[location.start..location.end])
+                .collect()
+        };
+        assert_eq!(sources("items"), ["<li class=\"a\">", "<LI data-x='>'>"]);
+        assert_eq!(sources("cell"), ["<td>"]);
+        assert!(sources("row").is_empty());
+        assert_eq!(values(&page, "row"), ["3"]);
+        let page = extract_page(&pages[0], html).unwrap();
+        assert!(page.element("items").unwrap().locations().is_empty());
+    }
+
+    #[test]
+    fn counts_matches() {
+        let html = "<body><div class=\"product\"><p class=\"review\">a</p><p class=\"review\">b</p></div><div class=\"product\"></div><p class=\"review\">c</p></body>";
+        let page = extract(
+            "page <https://a.com> { $all = count(.review); $none = count(.missing); $per_product = .product*all { $reviews = count(.review); } }",
+            html,
+        );
+        assert_eq!(values(&page, "all"), ["3"]);
+        assert_eq!(values(&page, "none"), ["0"]);
+        let products = page.element("per_product").unwrap().records();
+        let counts: Vec<&Vec<String>> = products
+            .iter()
+            .map(|p| p.element("reviews").unwrap().values())
+            .collect();
+        assert_eq!(counts, [&vec!["2".to_string()], &vec!["0".to_string()]]);
+    }
+
+    #[test]
+    fn scrapes_documents_without_a_body() {
+        let page = extract(
+            "page <https://a.com> { $x = li*all; }",
+            "<li>a</li><li>b</li>",
+        );
+        assert_eq!(values(&page, "x"), ["a", "b"]);
+        let page = extract(
+            "page <https://a.com> { $frames = frame@src*all; $text = p; }",
+            "<html><frameset><frame src=\"/a\"><frame src=\"/b\"></frameset></html>",
+        );
+        assert_eq!(values(&page, "frames"), ["https://a.com/a", "https://a.com/b"]);
+        assert!(values(&page, "text").is_empty());
+        let page = extract("page <https://a.com> { $x = p; }", "{\"not\": \"html\"}");
+        assert!(values(&page, "x").is_empty());
+        assert!(
+            extract("page <https://a.com> { $x = p; }", "").elements()[0]
+                .values()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn extracts_own_text_without_children() {
+        let html = "<body><div class=\"price\">12 <span class=\"hidden\">was 15</span>EUR<br>incl. <b>tax</b></div></body>";
+        let page = extract(
+            "page <https://a.com> { $full = .price; $own = own(.price); $none = own(.hidden > b); }",
+            html,
+        );
+        assert_eq!(values(&page, "full"), ["12 was 15 EUR\nincl. tax"]);
+        assert_eq!(values(&page, "own"), ["12 EUR\nincl. "]);
+        assert!(values(&page, "none").is_empty());
+    }
+
+    #[test]
+    fn diffs_two_scrapes() {
+        let element = |name: &str, values: &[&str]| {
+            ScrapedElement::new(name.into(), values.iter().map(|v| v.to_string()).collect())
+        };
+        let old = ScrapedPage::new(
+            "https://a.com/".into(),
+            None,
+            vec![
+                element("title", &["Lamp"]),
+                element("price", &["12"]),
+                element("stock", &["3"]),
+            ],
+        );
+        let new = ScrapedPage::new(
+            "https://a.com/".into(),
+            None,
+            vec![
+                element("price", &["10"]),
+                element("title", &["Lamp"]),
+                element("tags", &["sale"]),
+            ],
+        );
+        assert_eq!(
+            old.diff(&new),
+            [
+                FieldChange::Changed("price".into(), vec!["12".into()], vec!["10".into()]),
+                FieldChange::Removed("stock".into(), vec!["3".into()]),
+                FieldChange::Added("tags".into(), vec!["sale".into()]),
+            ]
+        );
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn diffs_pages_scraped_twice() {
+        let source = "page <https://a.com> { $items = li*all; }";
+        let old = extract(source, LIST);
+        let new = extract(source, "<ul><li>a</li><li>c</li></ul>");
+        assert_eq!(
+            old.diff(&new),
+            [FieldChange::Changed(
+                "items".into(),
+                vec!["a".into(), "b".into(), "c".into()],
+                vec!["a".into(), "c".into()],
+            )]
+        );
+    }
+
+    #[test]
+    fn matches_inside_the_body() {
+        let html = "<html><head><title>Head</title></head><body><p>Body</p></body></html>";
+        let page = extract("page <https://a.com> { $head = head; $text = p; }", html);
+        assert!(values(&page, "head").is_empty());
+        assert_eq!(values(&page, "text"), ["Body"]);
+    }
+
+    #[cfg(feature = "jsonld")]
+    #[test]
+    fn extracts_json_ld_fields() {
+        let html = r#"<html><head>
+            <script type="application/ld+json">{"@type": "Organization", "name": "Shop"}</script>
+            <script type="application/ld+json">{"@graph": [
+                {"@type": "Product", "name": "Lamp", "offers": {"price": 12.5, "priceCurrency": "EUR"}},
+                {"@type": ["Product", "Thing"], "name": "Desk", "offers": {"price": "99"}}
+            ]}</script>
+            <script type="application/ld+json">not json</script>
+            </head><body></body></html>"#;
+        let page = extract(
+            r#"page <https://a.com> {
+                $names = jsonld("Product", "name")*all;
+                $price = jsonld("Product", "offers.price");
+                $shop = jsonld("Organization");
+            }"#,
+            html,
+        );
+        assert_eq!(values(&page, "names"), ["Lamp", "Desk"]);
+        assert_eq!(values(&page, "price"), ["12.5"]);
+        assert_eq!(
+            values(&page, "shop"),
+            [r#"{"@type":"Organization","name":"Shop"}"#]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $x = li[; }")
+            .unwrap();
+        assert!(matches!(
+            extract_page(&pages[0], LIST),
+            Err(Error::InvalidSelectorError(invalid)) if invalid.offset == Some(28)
+        ));
+    }
+
+    #[test]
+    fn names_the_element_page_and_line_of_invalid_selectors() {
+        let file = std::env::temp_dir().join(format!("pdml-selector-{}.pdml", std::process::id()));
+        std::fs::write(
+            &file,
+            "page <https://a.com/shop> {\n  $title = h1;\n  .item {\n    $price = p:nope;\n  }\n}\n",
+        )
+        .unwrap();
+        let pages = Parser::for_file(file.to_string_lossy().into_owned())
+            .parse()
+            .unwrap();
+        let html = "<body><h1>Shop</h1><div class=\"item\"><p>1</p></div></body>";
+        let err = extract_page(&pages[0], html).unwrap_err();
+        std::fs::remove_file(&file).unwrap();
+        let Error::InvalidSelectorError(invalid) = &err else {
+            panic!("expected a selector error, got {}", err);
+        };
+        assert_eq!(invalid.selector, "p:nope");
+        assert_eq!(invalid.element.as_deref(), Some("price"));
+        assert_eq!(invalid.url.as_deref(), Some("https://a.com/shop"));
+        assert_eq!(invalid.line, Some(4));
+        assert!(err
+            .to_string()
+            .starts_with("Invalid selector p:nope of $price on <https://a.com/shop> at line 4: "));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Titles(Vec<String>);
+
+    impl ScrapeBindable for Titles {
+        fn bind(page: &ScrapedPage) -> Self {
+            Titles(page.element("title").unwrap().values().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn compiles_each_selector_once_per_run() {
+        let server = MockServer::start().await;
+        Mock::given(path("/list"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        let source = format!(
+            "page <{0}/list> {{ list li {{ $text = :scope; }} }} page <{0}/list?again> {{ list li {{ $text = :scope; }} }}",
+            server.uri()
+        );
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(scraped[1].records().len(), 3);
+        assert_eq!(scraper.selectors().misses(), 2);
+        assert_eq!(scraper.selectors().hits(), 2 * 4 - 2);
+    }
+
+    #[test]
+    fn drops_the_selector_used_least_recently() {
+        let selectors = SelectorCache::new(2);
+        for selector in ["a", "b", "a", "c", "a", "b"] {
+            selectors.get(selector).unwrap();
+        }
+        assert_eq!(selectors.len(), 2);
+        assert_eq!((selectors.hits(), selectors.misses()), (2, 4));
+    }
+
+    #[tokio::test]
+    async fn records_the_freshness_of_responses() {
+        let server = MockServer::start().await;
+        Mock::given(path("/fresh"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .insert_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+                    .set_body_string(LIST),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/stale"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        let source = format!(
+            "page <{0}/fresh> {{ $li = li; }} page <{0}/stale> {{ $li = li; }}",
+            server.uri()
+        );
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let before = SystemTime::now();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(scraped[0].etag().map(String::as_str), Some("\"v1\""));
+        assert_eq!(
+            scraped[0].last_modified(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480))
+        );
+        assert!(scraped[0].fetched_at() >= before);
+        assert_eq!(
+            (scraped[1].etag(), scraped[1].last_modified()),
+            (None, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_cached_pages_that_were_not_modified() {
+        let server = MockServer::start().await;
+        Mock::given(path("/list"))
+            .and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/list"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .set_body_string(LIST),
+            )
+            .mount(&server)
+            .await;
+        let source = format!("page <{}/list> {{ $li = li*all; }}", server.uri());
+        let pages = Parser::new().parse_source(&source).unwrap();
+
+        let uncached = Scraper::new(ScrapeOptions::default()).unwrap();
+        uncached.scrape_pages(&pages).await.unwrap();
+        uncached.scrape_pages(&pages).await.unwrap();
+        assert!(uncached.cached_pages().is_empty());
+
+        let scraper = Scraper::new(ScrapeOptions::default())
+            .unwrap()
+            .with_page_cache(vec![]);
+        let first = scraper.scrape_pages(&pages).await.unwrap();
+        let compiled = (scraper.selectors().hits(), scraper.selectors().misses());
+        let second = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(values(&second[0], "li"), ["a", "b", "c"]);
+        assert_eq!(second[0].etag(), first[0].etag());
+        assert_eq!(
+            (scraper.selectors().hits(), scraper.selectors().misses()),
+            compiled
+        );
+        let conditional = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| {
+                let names = request.headers.keys();
+                names
+                    .into_iter()
+                    .any(|name| name.as_str() == "if-none-match")
+            })
+            .count();
+        assert_eq!(conditional, 1);
+    }
+
+    #[tokio::test]
+    async fn scrapes_a_parsed_file() {
+        let fixtures = Fixtures::start().await;
+        fixtures.serve("/list", LIST).await;
+        let mut parser = fixtures.parser("page </list> { $title = li*all; }");
+        let scraped: Vec<Titles> = parser.scrape().await.unwrap();
+        assert_eq!(scraped.len(), 1);
+        assert_eq!(scraped[0].0, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn merges_the_pages_of_a_merge_group() {
+        let fixtures = Fixtures::start().await;
+        fixtures
+            .serve("/item", "<h1>Lamp</h1><ul><li>new</li></ul>")
+            .await
+            .serve("/price", "<h1>Lamp (prices)</h1><p class=\"price\">9</p><ul><li>sale</li></ul>")
+            .await
+            .serve("/other", "<h1>Desk</h1>")
+            .await;
+        let source = "merge \"product\" {
+                page </item> { $name = h1; list ul { $tag = li; } }
+                page </price> { $price = .price; list ul { $tag = li; } }
+            }
+            page </other> { $name = h1; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(scraped.len(), 2);
+        assert_eq!(scraped[0].url(), &fixtures.url("/item"));
+        assert_eq!(scraped[0].name().unwrap(), "product");
+        assert_eq!(values(&scraped[0], "name"), ["Lamp"]);
+        assert_eq!(values(&scraped[0], "price"), ["9"]);
+        assert_eq!(scraped[0].records().len(), 2);
+        assert_eq!(values(&scraped[1], "name"), ["Desk"]);
+
+        let conflicting = "merge \"product\" { page </item> { $name = h1; } page </price> { $name = h1; $price = .price; } }";
+        let pages = fixtures.parser(conflicting).parse().unwrap();
+        assert_eq!(
+            scraper.scrape_pages(&pages).await.unwrap_err().to_string(),
+            "$name is scraped by more than one page of merge group product"
+        );
+        let overriding = conflicting.replace("merge \"product\"", "merge override");
+        let pages = fixtures.parser(&overriding).parse().unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(scraped[0].name(), None);
+        assert_eq!(values(&scraped[0], "name"), ["Lamp (prices)"]);
+        assert_eq!(values(&scraped[0], "price"), ["9"]);
+
+        #[cfg(feature = "json")]
+        assert_eq!(
+            fixtures.parser(source).scrape_json().await.unwrap(),
+            [
+                serde_json::json!({
+                    "name": "Lamp",
+                    "price": "9",
+                    "$list": [{ "tag": "new" }, { "tag": "sale" }],
+                }),
+                serde_json::json!({ "name": "Desk" }),
+            ]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn keeps_the_pages_written_before_a_failed_page() {
+        let fixtures = Fixtures::start().await;
+        fixtures
+            .serve("/a", "<h1>First</h1>")
+            .await
+            .serve("/c", "<h1>Third</h1>")
+            .await;
+        let source = "page </a> { $title = h1; } page </b> { $title = h1; } page </c> { $title = h1; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let file = std::env::temp_dir().join(format!("pdml-sink-{}.ndjson", std::process::id()));
+        let mut sink = NdjsonSink::new(std::fs::File::create(&file).unwrap());
+        assert!(scraper.scrape_into(&pages, &mut sink).await.is_err());
+        drop(sink);
+        let written = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, [serde_json::json!({ "title": "First" })]);
+
+        let mut sink = NdjsonSink::new(vec![]);
+        scraper.scrape_into(&[pages[0].clone(), pages[2].clone()], &mut sink).await.unwrap();
+        assert_eq!(
+            String::from_utf8(sink.finish().unwrap()).unwrap(),
+            "{\"title\":\"First\"}\n{\"title\":\"Third\"}\n"
+        );
+
+        let dir = std::env::temp_dir().join(format!("pdml-files-{}", std::process::id()));
+        let mut sink = FileSink::new(&dir).unwrap();
+        assert!(scraper.scrape_into(&pages, &mut sink).await.is_err());
+        assert_eq!(sink.written(), 1);
+        let first = std::fs::read_to_string(dir.join("0.json")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, "{\"title\":\"First\"}\n");
+    }
+
+    #[tokio::test]
+    async fn skips_disabled_pages_and_elements() {
+        let fixtures = Fixtures::start().await;
+        fixtures.serve("/a", "<h1>a</h1><h2>b</h2><ul><li>c</li></ul>").await;
+        let source = "page </a> { $title = h1; $sub.disabled = h2; $items.disabled = ul { $item = li; } } \
+                      page.disabled </b> { $title = h1; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        assert_eq!(pages.len(), 2);
+        assert!(pages[0].elements()[1].disabled());
+
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        assert_eq!(scraped.len(), 1);
+        assert_eq!(values(&scraped[0], "title"), ["a"]);
+        assert!(scraped[0].element("sub").is_none());
+        assert!(scraped[0].element("items").is_none());
+        assert!(scraped[0].element("item").is_none());
+        assert_eq!(scraper.summary().fetched, 1);
+        assert_eq!(scraper.summary().skipped, 1);
+
+        let streamed: Vec<Result<Titles>> = Scraper::new(ScrapeOptions::default())
+            .unwrap()
+            .scrape_stream(pages)
+            .collect()
+            .await;
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(streamed[0].as_ref().unwrap(), &Titles(vec!["a".into()]));
+    }
+
+    /// A headless browser noting the urls it loads, whose pages are `Loading` until their content
+    /// fills in 30 milliseconds after loading.
+    #[cfg(feature = "headless")]
+    #[derive(Debug, Default)]
+    struct Browser(Mutex<Vec<String>>);
+
+    #[cfg(feature = "headless")]
+    #[async_trait]
+    impl HeadlessBackend for Browser {
+        async fn screenshot(&self, url: &str) -> Result<Vec<u8>> {
+            self.0.lock().unwrap().push(url.to_string());
+            Ok(b"\x89PNG".to_vec())
+        }
+
+        async fn render(
+            &self,
+            url: &str,
+            selector: &str,
+            timeout: Duration,
+            interval: Duration,
+        ) -> Result<Option<String>> {
+            self.0.lock().unwrap().push(url.to_string());
+            let loaded = tokio::time::Instant::now();
+            let selector = Selector::parse(selector).unwrap();
+            loop {
+                let html = match loaded.elapsed() >= Duration::from_millis(30) {
+                    true => "<div class=\"content\">Done</div>",
+                    false => "<p>Loading</p>",
+                };
+                if Html::parse_document(html).select(&selector).next().is_some() {
+                    return Ok(Some(html.to_string()));
+                }
+                if loaded.elapsed() >= timeout {
+                    return Ok(None);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+
+    #[cfg(feature = "headless")]
+    #[tokio::test]
+    async fn waits_for_the_selector_in_the_rendered_page() {
+        let fixtures = Fixtures::start().await;
+        fixtures.serve("/report", "<p>Loading</p>").await;
+        let source = r#"page[wait_for=".content"] </report> { $state = .content; } page[wait_for=".never"] </report> { }"#;
+        let pages = fixtures.parser(source).parse().unwrap();
+        let browser = Arc::new(Browser::default());
+        let options = ScrapeOptions {
+            headless: Some(browser.clone()),
+            wait_timeout: Some(Duration::from_millis(100)),
+            wait_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let scraper = Scraper::new(options.clone()).unwrap();
+        let page = scraper.scrape_page(&pages[0]).await.unwrap();
+        assert_eq!(values(&page, "state"), ["Done"]);
+        assert_eq!(*browser.0.lock().unwrap(), [fixtures.url("/report")]);
+        assert_eq!(fixtures.requested_routes().await, ["/report"]);
+        let Err(Error::ScraperError(message)) = scraper.scrape_page(&pages[1]).await else {
+            panic!("the selector never matches");
+        };
+        assert!(message.starts_with(".never did not match on"));
+        assert!(message.ends_with("within 100ms"));
+        assert_eq!(estimate_requests(&pages, &options), 2);
+    }
+
+    #[cfg(feature = "headless")]
+    #[tokio::test]
+    async fn saves_screenshots_of_the_pages_marked_for_it() {
+        let fixtures = Fixtures::start().await;
+        fixtures
+            .serve("/shop", "<h1>a</h1>")
+            .await
+            .serve("/b", "<h1>b</h1>")
+            .await;
+        let source = "page[screenshot=true] </shop?p=2> { $title = h1; } page </b> { $title = h1; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let dir = std::env::temp_dir().join(format!("pdml-screenshots-{}", std::process::id()));
+        let browser = Arc::new(Browser::default());
+        let options = ScrapeOptions {
+            headless: Some(browser.clone()),
+            screenshot_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let scraped = Scraper::new(options).unwrap().scrape_pages(&pages).await.unwrap();
+        let url = fixtures.url("/shop?p=2");
+        assert_eq!(*browser.0.lock().unwrap(), [url.as_str()]);
+        let file = dir.join(format!("{}.png", screenshot_name(&url)));
+        assert!(file.file_name().unwrap().to_str().unwrap().ends_with("_shop_p_2.png"));
+        assert_eq!(std::fs::read(&file).unwrap(), b"\x89PNG");
+        assert_eq!(scraped[0].screenshot(), Some(&file));
+        assert_eq!(scraped[1].screenshot(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let scraped = Scraper::new(ScrapeOptions::default()).unwrap().scrape_pages(&pages).await;
+        assert_eq!(scraped.unwrap()[0].screenshot(), None);
+        assert_eq!(screenshot_name("https://a.com/"), "a.com");
+    }
+
+    #[tokio::test]
+    async fn sends_the_extra_query_with_every_request() {
+        let fixtures = Fixtures::start().await;
+        fixtures.serve("/a", "<h1>a</h1>").await;
+        let source = "page </a?lang={de,fr}> { $title = h1; } page </a> { $title = h1; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let options = ScrapeOptions {
+            extra_query: vec![("lang".into(), "en".into()), ("key".into(), "k 1".into())],
+            ..Default::default()
+        };
+        let scraped = Scraper::new(options).unwrap().scrape_pages(&pages).await.unwrap();
+        assert_eq!(
+            fixtures.requested_routes().await,
+            ["/a?lang=de&key=k+1", "/a?lang=fr&key=k+1", "/a?lang=en&key=k+1"]
+        );
+        assert_eq!(scraped[0].url(), &fixtures.url("/a?lang=de"));
+        assert!(scraped.iter().all(|page| page.final_url().is_none()));
+    }
+
+    #[tokio::test]
+    async fn summarizes_failed_and_skipped_pages() {
+        let fixtures = Fixtures::start().await;
+        fixtures
+            .serve("/a", "<li>a</li>")
+            .await
+            .serve("/c", "<li>c</li>")
+            .await;
+        let source = "page </a> { $title = li; } page </b> { $title = li; } page </c> { $title = li; }";
+        let pages = fixtures.parser(source).parse().unwrap();
+        let counter = Arc::new(ScrapeCounter::default());
+        let options = ScrapeOptions {
+            max_total_pages: Some(2),
+            counter: Some(counter.clone()),
+            ..Default::default()
+        };
+        let scraped: Vec<Result<Titles>> = Scraper::new(options)
+            .unwrap()
+            .scrape_stream(pages.clone())
+            .collect()
+            .await;
+        assert_eq!(scraped.len(), 2);
+        assert!(scraped[1].is_err());
+        assert_eq!(
+            counter.summary(),
+            ScrapeSummary {
+                planned: 3,
+                fetched: 2,
+                succeeded: 1,
+                failed: 1,
+                skipped: 1,
+            }
+        );
+
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        assert!(scraper.scrape_pages(&pages).await.is_err());
+        assert_eq!(
+            scraper.summary(),
+            ScrapeSummary {
+                planned: 3,
+                fetched: 2,
+                succeeded: 1,
+                failed: 1,
+                skipped: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_the_same_pages_as_a_batch_scrape() {
+        let fixtures = Fixtures::start().await;
+        for (page, item) in [("/a", "a"), ("/b", "b")] {
+            fixtures.serve(page, &format!("<li>{}</li>", item)).await;
+        }
+        let mut parser = fixtures.parser("page </a> { $title = li; } page </b> { $title = li; }");
+        let batch: Vec<Titles> = parser.scrape().await.unwrap();
+        let stream = parser.scrape_stream(&ScrapeOptions::default()).unwrap();
+        let streamed: Vec<Titles> = stream.map(|page| page.unwrap()).collect().await;
+        assert_eq!(streamed, batch);
+        assert_eq!(
+            streamed,
+            [Titles(vec!["a".into()]), Titles(vec!["b".into()])]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_stream_pages_only_when_polled() {
+        let server = MockServer::start().await;
+        Mock::given(path("/a"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source(
+                "page </a> { $title = li; } page </a> { $title = li; } page </a> { $title = li; }",
+            )
+            .unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let mut stream = Box::pin(scraper.scrape_stream::<Titles>(pages));
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+        assert_eq!(stream.count().await, 2);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    struct Links(Vec<String>);
+
+    impl ScrapeBindable for Links {
+        fn bind(page: &ScrapedPage) -> Self {
+            Links(page.element("link").unwrap().values().clone())
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Routed {
+        List(Vec<String>),
+        Detail(Vec<String>),
+    }
+
+    #[tokio::test]
+    async fn routes_pages_to_binders_by_url() {
+        let server = MockServer::start().await;
+        Mock::given(path("/list"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<a>1</a><a>2</a>"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/item/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<li>Lamp</li>"))
+            .mount(&server)
+            .await;
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source("page </list> { $link = a*all; } page </item/1> { $title = li; }")
+            .unwrap();
+        let router = ScrapeRouter::new()
+            .route("*/item/*", |Titles(titles)| Routed::Detail(titles))
+            .route("*/list", |Links(links)| Routed::List(links))
+            .route("*", |Titles(titles)| Routed::Detail(titles));
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let routed = scraper.scrape_routed(&pages, &router).await.unwrap();
+        assert_eq!(
+            routed,
+            [
+                Routed::List(vec!["1".into(), "2".into()]),
+                Routed::Detail(vec!["Lamp".into()]),
+            ]
+        );
+        let unrouted = ScrapedPage::new("https://a.com/".into(), None, vec![]);
+        assert!(ScrapeRouter::new()
+            .route("*/list", |Links(links)| Routed::List(links))
+            .dispatch(&unrouted)
+            .is_none());
+    }
+
+    async fn retry_server(retry_after: &str) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(path("/list"))
+            .respond_with(ResponseTemplate::new(503).insert_header("retry-after", retry_after))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/list"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn scrape_list(server: &MockServer, options: ScrapeOptions) -> Result<ScrapedPage> {
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source("page </list> { $x = li; }")
+            .unwrap();
+        Scraper::new(options).unwrap().scrape_page(&pages[0]).await
+    }
+
+    #[tokio::test]
+    async fn retries_after_the_delay_the_server_asks_for() {
+        let server = retry_server("2").await;
+        let started = std::time::Instant::now();
+        let options = ScrapeOptions {
+            retries: 1,
+            retry_backoff: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let page = scrape_list(&server, options).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        assert_eq!(values(&page, "x"), ["a"]);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn caps_retry_delays_and_ignores_malformed_headers() {
+        for header in ["3600", "Wed, 21 Oct 2099 07:28:00 GMT", "soon"] {
+            let server = retry_server(header).await;
+            let started = std::time::Instant::now();
+            let options = ScrapeOptions {
+                retries: 1,
+                retry_backoff: Some(Duration::from_millis(10)),
+                max_retry_delay: Some(Duration::from_millis(200)),
+                ..Default::default()
+            };
+            assert!(scrape_list(&server, options).await.is_ok(), "{}", header);
+            assert!(started.elapsed() < Duration::from_secs(2), "{}", header);
+        }
+        let server = retry_server("0").await;
+        assert!(scrape_list(&server, ScrapeOptions::default())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn doubles_the_backoff() {
+        let options = ScrapeOptions {
+            retry_backoff: Some(Duration::from_secs(1)),
+            max_retry_delay: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (0..4)
+            .map(|attempt| options.retry_delay(attempt, None).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 5]);
+        assert_eq!(
+            options
+                .retry_delay(0, Some(Duration::from_secs(3)))
+                .as_secs(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let server = MockServer::start().await;
+        Mock::given(path("/fast"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        Mock::given(path("/slow"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(LIST)
+                    .set_delay(Duration::from_secs(30)),
+            )
+            .mount(&server)
+            .await;
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source(
+                "page </fast> { $x = li; } page </slow> { $x = li; } page </fast> { $x = li; }",
+            )
+            .unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let started = std::time::Instant::now();
+        let scraped = scraper
+            .scrape_pages_until(&pages, tokio::time::sleep(Duration::from_millis(300)))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(scraped.len(), 1);
+        assert!(scraped[0].url().ends_with("/fast"));
+    }
+
+    #[tokio::test]
+    async fn decompresses_gzip_responses() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(LIST.as_bytes()).unwrap();
+        let server = MockServer::start().await;
+        Mock::given(path("/list"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .insert_header("content-type", "text/html")
+                    .set_body_bytes(encoder.finish().unwrap()),
+            )
+            .mount(&server)
+            .await;
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source("page </list> { $x = li*all; }")
+            .unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let page = scraper.scrape_page(&pages[0]).await.unwrap();
+        assert_eq!(values(&page, "x"), ["a", "b", "c"]);
+        let requests = server.received_requests().await.unwrap();
+        let accepted = requests[0].headers.get(&"accept-encoding".into()).unwrap();
+        assert!(accepted.iter().any(|encoding| encoding == "gzip"));
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_statuses() {
+        let server = MockServer::start().await;
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source("page </missing> { $x = li; }")
+            .unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        assert!(matches!(
+            scraper.scrape_page(&pages[0]).await,
+            Err(Error::ScraperError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_fast_on_unroutable_hosts() {
+        let options = ScrapeOptions {
+            connect_timeout: Some(Duration::from_millis(200)),
+            timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let pages = Parser::new()
+            .parse_source("page <http://10.255.255.1/> { $x = li; }")
+            .unwrap();
+        let scraper = Scraper::new(options).unwrap();
+        let started = std::time::Instant::now();
+        assert!(scraper.scrape_page(&pages[0]).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let options = ScrapeOptions {
+            connect_timeout: Some(Duration::from_secs(10)),
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        assert!(matches!(Scraper::new(options), Err(Error::ScraperError(_))));
+    }
+
+    #[test]
+    fn estimates_requests_without_fetching() {
+        let pages = Parser::new()
+            .parse_source(
+                "page <https://a.com/{1..3}> <https://b.com/{1..3}> { $x = li; } page <https://c.com> { $x = li; }",
+            )
+            .unwrap();
+        assert_eq!(estimate_requests(&pages, &ScrapeOptions::default()), 7);
+        let retrying = ScrapeOptions {
+            retries: 2,
+            ..Default::default()
+        };
+        assert_eq!(estimate_requests(&pages, &retrying), 21);
+        assert_eq!(estimate_requests(&[], &retrying), 0);
+        let capped = ScrapeOptions {
+            max_total_pages: Some(2),
+            ..retrying.clone()
+        };
+        // The two pages with a mirror, each tried three times on both urls.
+        assert_eq!(estimate_requests(&pages, &capped), 12);
+        let pages = Parser::new()
+            .parse_source("page[follow_next=10] <https://a.com> { $x = li; } page <https://b.com> { }")
+            .unwrap();
+        let capped = ScrapeOptions {
+            max_total_pages: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(estimate_requests(&pages, &ScrapeOptions::default()), 12);
+        assert_eq!(estimate_requests(&pages, &capped), 4);
+        let pages = Parser::new()
+            .parse_source("page <https://a.com> { $x = li; } page.disabled <https://b.com> { $x = li; }")
+            .unwrap();
+        assert_eq!(estimate_requests(&pages, &ScrapeOptions::default()), 1);
+    }
+
+    #[tokio::test]
+    async fn ignores_wait_for_without_a_headless_backend() {
+        let fixtures = Fixtures::start().await;
+        fixtures.serve("/report", "<p>Loading</p>").await;
+        let source = r#"page[wait_for=".content"] </report> { $state = p; }"#;
+        let pages = fixtures.parser(source).parse().unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let page = scraper.scrape_page(&pages[0]).await.unwrap();
+        assert_eq!(values(&page, "state"), ["Loading"]);
+        assert_eq!(fixtures.requested_routes().await, ["/report"]);
+        assert_eq!(estimate_requests(&pages, &ScrapeOptions::default()), 1);
+    }
+
+    struct Number(String);
+
+    impl ScrapeBindable for Number {
+        fn bind(page: &ScrapedPage) -> Self {
+            Number(page.element("n").unwrap().values()[0].clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_next_links_up_to_the_cap() {
+        let server = MockServer::start().await;
+        let pages = [
+            (
+                "1",
+                "<head><link rel=\"next\" href=\"list?page=2\"></head><p>1</p>",
+            ),
+            (
+                "2",
+                "<p>2</p><a rel=\"prev next\" href=\"/list?page=3\">more</a>",
+            ),
+            (
+                "3",
+                "<p>3</p><a rel=\"next\" href=\"/list?page=1\">again</a>",
+            ),
+        ];
+        for (page, body) in pages {
+            Mock::given(path("/list"))
+                .and(wiremock::matchers::query_param("page", page))
+                .respond_with(ResponseTemplate::new(200).set_body_string(body))
+                .mount(&server)
+                .await;
+        }
+        let source =
+            "page[follow_next=5] </list?page=1> { $n = p; } page[follow_next=1] </list?page=1> { $n = p; }";
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source(source)
+            .unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages).await.unwrap();
+        let numbers: Vec<&str> = scraped
+            .iter()
+            .map(|page| page.element("n").unwrap().values()[0].as_str())
+            .collect();
+        assert_eq!(numbers, ["1", "2", "3", "1", "2"]);
+        assert_eq!(scraped[1].url(), &format!("{}/list?page=2", server.uri()));
+        let streamed: Vec<String> = scraper
+            .scrape_stream(pages[..1].to_vec())
+            .map(|page: Result<Number>| page.unwrap().0)
+            .collect()
+            .await;
+        assert_eq!(streamed, ["1", "2", "3"]);
+        assert_eq!(estimate_requests(&pages, &ScrapeOptions::default()), 8);
+    }
+
+    #[tokio::test]
+    async fn shares_request_permits_between_scrapes() {
+        let server = MockServer::start().await;
+        Mock::given(path("/slow"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_delay(Duration::from_millis(200))
+                    .set_body_string(LIST),
+            )
+            .mount(&server)
+            .await;
+        let source = format!("page <{}/slow> {{ $li = li; }}", server.uri());
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let scrape_both = |permits: usize| {
+            let options = ScrapeOptions {
+                request_permits: Some(Arc::new(Semaphore::new(permits))),
+                ..Default::default()
+            };
+            let first = Scraper::new(options.clone()).unwrap();
+            let second = Scraper::new(options).unwrap();
+            let pages = pages.clone();
+            async move {
+                let start = std::time::Instant::now();
+                let (a, b) =
+                    future::join(first.scrape_pages(&pages), second.scrape_pages(&pages)).await;
+                assert_eq!(values(&a.unwrap()[0], "li"), values(&b.unwrap()[0], "li"));
+                start.elapsed()
+            }
+        };
+        assert!(scrape_both(1).await >= Duration::from_millis(400));
+        assert!(scrape_both(2).await < Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn records_or_refuses_redirects() {
+        let server = MockServer::start().await;
+        Mock::given(path("/old"))
+            .respond_with(ResponseTemplate::new(301).insert_header("Location", "/login"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/login"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        let source = format!(
+            "page <{0}/old> {{ $li = li; }} page <{0}/login> {{ $li = li; }}",
+            server.uri()
+        );
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let scraped = Scraper::new(ScrapeOptions::default())
+            .unwrap()
+            .scrape_pages(&pages)
+            .await
+            .unwrap();
+        let login = format!("{}/login", server.uri());
+        assert_eq!(scraped[0].final_url(), Some(&login));
+        assert_eq!(values(&scraped[0], "li"), ["a"]);
+        assert_eq!(scraped[1].final_url(), None);
+
+        let options = ScrapeOptions {
+            max_redirects: Some(0),
+            ..Default::default()
+        };
+        let err = Scraper::new(options)
+            .unwrap()
+            .scrape_page(&pages[0])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Error while scraping the site: {}/old redirects to /login, but redirects are not followed",
+                server.uri()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_mirrors_in_order() {
+        let server = MockServer::start().await;
+        Mock::given(path("/down"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        Mock::given(path("/up"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(LIST))
+            .mount(&server)
+            .await;
+        let source = format!(
+            "page <{0}/down> <{0}/up> <{0}/down> {{ $li = li; }} page <{0}/up> {{ $li = li; }} \
+             page <{0}/down> <{0}/down> {{ $li = li; }}",
+            server.uri()
+        );
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let scraper = Scraper::new(ScrapeOptions::default()).unwrap();
+        let scraped = scraper.scrape_pages(&pages[..2]).await.unwrap();
+        let up = format!("{}/up", server.uri());
+        assert_eq!(scraped[0].url(), &format!("{}/down", server.uri()));
+        assert_eq!(scraped[0].mirror(), Some(&up));
+        assert_eq!(scraped[0].final_url(), None);
+        assert_eq!(values(&scraped[0], "li"), ["a"]);
+        assert_eq!(scraped[1].mirror(), None);
+
+        let err = scraper.scrape_page(&pages[2]).await.unwrap_err();
+        let down = format!("{}/down", server.uri());
+        let err = err.to_string();
+        assert!(
+            err.starts_with(&format!(
+                "Error while scraping the site: {} and its mirrors failed: {}: ",
+                down, down
+            )),
+            "{}",
+            err
+        );
+        assert_eq!(err.matches("500 Internal Server Error").count(), 2, "{}", err);
+    }
+
+    #[tokio::test]
+    async fn previews_the_start_of_fetched_documents() {
+        let server = MockServer::start().await;
+        Mock::given(path("/captcha"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<p>Prove you're human ☺</p>"))
+            .mount(&server)
+            .await;
+        let source = format!("page <{}/captcha> {{ $li = li; }}", server.uri());
+        let pages = Parser::new().parse_source(&source).unwrap();
+        let preview = |chars: Option<usize>| {
+            let options = ScrapeOptions {
+                preview_chars: chars,
+                ..Default::default()
+            };
+            let pages = pages.clone();
+            async move {
+                let scraper = Scraper::new(options).unwrap();
+                let scraped = scraper.scrape_page(&pages[0]).await.unwrap();
+                scraped.preview().cloned()
+            }
+        };
+        assert_eq!(preview(Some(8)).await.as_deref(), Some("<p>Prove"));
+        assert_eq!(
+            preview(Some(24)).await.as_deref(),
+            Some("<p>Prove you're human ☺<")
+        );
+        assert_eq!(
+            preview(Some(100)).await.as_deref(),
+            Some("<p>Prove you're human ☺</p>")
+        );
+        assert_eq!(preview(None).await, None);
+    }
+
+    #[tokio::test]
+    async fn stops_crawling_at_the_page_budget() {
+        let server = MockServer::start().await;
+        for page in 1..=5 {
+            let body = format!(
+                "<p>{}</p><a rel=\"next\" href=\"?page={}\">next</a>",
+                page,
+                page + 1
+            );
+            Mock::given(path("/list"))
+                .and(wiremock::matchers::query_param("page", page.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_string(body))
+                .mount(&server)
+                .await;
+        }
+        let source =
+            "page[follow_next=4] </list?page=1> { $n = p; } page </list?page=5> { $n = p; }";
+        let pages = Parser::new()
+            .with_base_url(server.uri())
+            .parse_source(source)
+            .unwrap();
+        let options = ScrapeOptions {
+            max_total_pages: Some(3),
+            ..Default::default()
+        };
+        let scraped = Scraper::new(options.clone())
+            .unwrap()
+            .scrape_pages(&pages)
+            .await
+            .unwrap();
+        let numbers: Vec<&str> = scraped
+            .iter()
+            .map(|page| page.element("n").unwrap().values()[0].as_str())
+            .collect();
+        assert_eq!(numbers, ["1", "2", "3"]);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+        let streamed: Vec<String> = Scraper::new(options)
+            .unwrap()
+            .scrape_stream(pages)
+            .map(|page: Result<Number>| page.unwrap().0)
+            .collect()
+            .await;
+        assert_eq!(streamed, ["1", "2", "3"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn scrapes_pages_into_json() {
+        let fixtures = Fixtures::start().await;
+        let html = "<body><h1>Shop</h1>\
+            <div class=\"product\"><h2>Lamp</h2><p>9</p><p>12</p></div>\
+            <div class=\"product\"><h2>Desk</h2></div>\
+            <section><h3>Reviews</h3><blockquote>Great</blockquote></section></body>";
+        fixtures.serve("/shop", html).await;
+        let json = fixtures
+            .parser("page </shop> { $title = h1; $missing = .none; $products = .product*all { $name = h2; $prices.optional = p*all; } when h1 { $sections = h3*all; } list section { $quote = blockquote; } }")
+            .scrape_json()
+            .await
+            .unwrap();
+        assert_eq!(
+            json,
+            [serde_json::json!({
+                "title": "Shop",
+                "missing": null,
+                "products": [
+                    { "name": "Lamp", "prices": ["9", "12"] },
+                    { "name": "Desk", "prices": [] },
+                ],
+                "sections": ["Reviews"],
+                "$list": [{ "quote": "Great" }],
+            })]
+        );
+    }
+}
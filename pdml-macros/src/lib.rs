@@ -92,3 +92,47 @@ pub fn partial(_attr: TokenStream, mut item: TokenStream) -> TokenStream {
     item.extend(tokens);
     item
 }
+
+/// Derives `ScrapeBindable` and `ScrapeRecordBindable` for a struct with named fields. Each field
+/// binds to the scraped element of the same name through `pdml_lib::scrape::BindField`, so that a
+/// field of another such struct binds to the block of its element.
+#[proc_macro_derive(ScrapeBindable)]
+pub fn derive_scrape_bindable(item: TokenStream) -> TokenStream {
+    let DeriveInput { ident, data, .. } = syn::parse(item).unwrap();
+
+    let fields = match data {
+        syn::Data::Struct(s) => match s.fields {
+            syn::Fields::Named(FieldsNamed { named, .. }) => named,
+            _ => panic!("ScrapeBindable only works for structs with named fields"),
+        },
+        _ => panic!("ScrapeBindable only works for structs"),
+    };
+    let bindings = fields
+        .iter()
+        .map(|f| {
+            let field = f.ident.as_ref().unwrap();
+            let name = field.to_string();
+            let name = name.strip_prefix("r#").unwrap_or(&name);
+            quote! {
+                #field: ::pdml_lib::scrape::BindField::bind_field(scraped.element(#name)),
+            }
+        })
+        .collect::<Vec<proc_macro2::TokenStream>>();
+    TokenStream::from(quote! {
+        impl ::pdml_lib::scrape::ScrapeRecordBindable for #ident {
+            fn bind(scraped: &::pdml_lib::scrape::ScrapedRecord) -> Self {
+                Self {
+                    #(#bindings)*
+                }
+            }
+        }
+
+        impl ::pdml_lib::scrape::ScrapeBindable for #ident {
+            fn bind(scraped: &::pdml_lib::scrape::ScrapedPage) -> Self {
+                Self {
+                    #(#bindings)*
+                }
+            }
+        }
+    })
+}
@@ -1,8 +1,26 @@
 use pdml_lib::parser::Parser;
+use pdml_lib::scrape::ParserExt;
+use std::process::ExitCode;
 
+/// Scrapes the definition given as the first argument and prints every scraped page as a JSON
+/// object on a line of its own.
 #[tokio::main]
-async fn main() {
-    let mut parser = Parser::for_file("example.pdml".to_string());
-    let pages = parser.parse().unwrap();
-    dbg!(pages);
+async fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: pdml <definition.pdml>");
+        return ExitCode::FAILURE;
+    };
+    let mut parser = Parser::for_file(path.clone());
+    match parser.scrape_json().await {
+        Ok(pages) => {
+            for page in pages {
+                println!("{}", page);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use wiremock::matchers::path;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn pdml(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pdml"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+/// Writes `definition` to a file of its own in the temp dir.
+fn definition(name: &str, definition: &str) -> PathBuf {
+    let file = std::env::temp_dir().join(format!("pdml-cli-{}-{}.pdml", name, std::process::id()));
+    std::fs::write(&file, definition).unwrap();
+    file
+}
+
+// The server runs on the runtime of the test while the binary is waited for.
+#[tokio::test(flavor = "multi_thread")]
+async fn prints_the_scraped_pages_as_json() {
+    let server = MockServer::start().await;
+    for (route, html) in [
+        (
+            "/shop",
+            "<h1>Lamps</h1><ul><li>Desk</li><li>Floor</li></ul>",
+        ),
+        ("/about", "<h1>About</h1>"),
+    ] {
+        Mock::given(path(route))
+            .respond_with(ResponseTemplate::new(200).set_body_string(html))
+            .mount(&server)
+            .await;
+    }
+    let file = definition(
+        "scrape",
+        &format!(
+            "page <{0}/shop> {{ $title = h1; $items = li*all; }}\npage <{0}/about> {{ $title = h1; }}",
+            server.uri()
+        ),
+    );
+    let output = pdml(&[file.to_str().unwrap()]);
+    std::fs::remove_file(&file).unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "{\"items\":[\"Desk\",\"Floor\"],\"title\":\"Lamps\"}\n{\"title\":\"About\"}\n"
+    );
+
+    let file = definition(
+        "missing",
+        &format!("page <{}/missing> {{ $title = h1; }}", server.uri()),
+    );
+    let output = pdml(&[file.to_str().unwrap()]);
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(file.to_str().unwrap()), "{}", stderr);
+    assert!(stderr.contains("404"), "{}", stderr);
+}
+
+#[test]
+fn fails_without_a_definition() {
+    let output = pdml(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "usage: pdml <definition.pdml>\n"
+    );
+}
+
+#[test]
+fn fails_on_definitions_it_cannot_parse() {
+    let output = pdml(&["missing.pdml"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("missing.pdml: Error while reading the source"));
+
+    let file = definition("invalid", "page { $title = h1; }");
+    let output = pdml(&[file.to_str().unwrap()]);
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with(file.to_str().unwrap()));
+}